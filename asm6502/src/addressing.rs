@@ -1,5 +1,7 @@
 //! Addressing mode detection and handling
 
+use crate::symbol::AccessKind;
+
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub enum AddrOverride {
     Auto,
@@ -18,6 +20,23 @@ pub fn parse_addr_override(operand: &str) -> (&str, AddrOverride) {
     }
 }
 
+/// True for the accumulator operand `A` (any case).
+pub fn is_accumulator(operand: &str) -> bool {
+    operand.trim().eq_ignore_ascii_case("A")
+}
+
+/// Check if an (uppercase) word is an instruction mnemonic
+pub fn is_mnemonic(word: &str) -> bool {
+    matches!(
+        word,
+        "ADC" | "AND" | "ASL" | "BCC" | "BCS" | "BEQ" | "BIT" | "BMI" | "BNE" | "BPL" | "BRK" | "BVC" | "BVS"
+            | "CLC" | "CLD" | "CLI" | "CLV" | "CMP" | "CPX" | "CPY" | "DEC" | "DEX" | "DEY" | "EOR" | "INC"
+            | "INX" | "INY" | "JMP" | "JSR" | "LDA" | "LDX" | "LDY" | "LSR" | "NOP" | "ORA" | "PHA" | "PHP"
+            | "PLA" | "PLP" | "ROL" | "ROR" | "RTI" | "RTS" | "SBC" | "SEC" | "SED" | "SEI" | "STA" | "STX"
+            | "STY" | "TAX" | "TAY" | "TSX" | "TXA" | "TXS" | "TYA"
+    )
+}
+
 /// Check if a mnemonic is a branch instruction
 pub fn is_branch(mnemonic: &str) -> bool {
     matches!(
@@ -26,6 +45,20 @@ pub fn is_branch(mnemonic: &str) -> bool {
     )
 }
 
+/// How an instruction uses the symbols in its operand, for the
+/// cross-reference table.
+pub fn access_kind(mnemonic: &str, operand: &str) -> AccessKind {
+    if is_branch(mnemonic) {
+        return AccessKind::Branch;
+    }
+    match mnemonic {
+        "JMP" | "JSR" => AccessKind::Jump,
+        _ if operand.starts_with('#') => AccessKind::Data,
+        "STA" | "STX" | "STY" | "INC" | "DEC" | "ASL" | "LSR" | "ROL" | "ROR" => AccessKind::Write,
+        _ => AccessKind::Read,
+    }
+}
+
 /// The branch with the opposite condition. Used by the long-branch
 /// expander: a `BXX far_label` becomes `BYY skip; JMP far_label; skip:`
 /// where YY inverts XX so the jump fires under the original condition.
@@ -1,19 +1,33 @@
 //! Main assembler implementation
 
-use std::fs;
+use std::collections::{HashMap, HashSet};
+use std::iter;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
 
 #[cfg(feature = "listing")]
 use std::fs::File;
 #[cfg(feature = "listing")]
 use std::io::{self, Write};
 
-use crate::error::AsmError;
+use crate::error::{AsmError, Warning};
+use crate::expand::Expander;
+use crate::files::{FileCache, FileProvider};
+use crate::image::AssembledImage;
 use crate::opcodes::OpcodeTables;
-use crate::symbol::SymbolTable;
-use crate::parser::{parse_source, parse_line, Either, ExpressionParser};
-use crate::addressing::{invert_branch, parse_addr_override, is_branch, AddrOverride};
-use crate::eval::ExpressionEvaluator;
+use crate::parser::expression::Expr;
+use crate::source::SourceLoc;
+use crate::symbol::{AccessKind, Reference, Symbol, SymbolKind, SymbolOrder, SymbolTable};
+use crate::dialect::Dialect;
+use crate::export::{export_name, AddressSize, ExportTarget, OperandForm, SourceWriter};
+use crate::parser::{parse_file, parse_line, Either, ExpressionParser};
+use crate::addressing::{access_kind, invert_branch, is_accumulator, parse_addr_override, is_branch, AddrOverride};
+use crate::eval::{ExpressionEvaluator, ReferenceLog};
 use crate::reserved::ReservedRange;
+use crate::segment::{MemoryArea, MemoryConfig, Segment, SegmentKind};
+use crate::bank::link_banks;
 
 // Re-export Item for public API
 pub use crate::parser::lexer::Item;
@@ -23,7 +37,23 @@ pub struct Assembler6502 {
     symbols: SymbolTable,
     start_address: u16,
     skip_label_counter: u32,
+    internal_labels: HashSet<String>,
     reserved_ranges: Vec<ReservedRange>,
+    include_dirs: Vec<PathBuf>,
+    files: FileCache,
+    ref_log: ReferenceLog,
+    referenced: HashSet<String>,
+    references: Vec<Reference>,
+    warnings: Vec<Warning>,
+    memory_config: Option<MemoryConfig>,
+    dialect: Dialect,
+    /// The memory configuration the last program was placed with: the
+    /// host's, or the one its `.bank` directives describe.
+    layout: Option<MemoryConfig>,
+    byte_addrs: Vec<u16>,
+    image: AssembledImage,
+    #[cfg(feature = "listing")]
+    listing_xref: bool,
 }
 
 impl Default for Assembler6502 {
@@ -39,7 +69,21 @@ impl Assembler6502 {
             symbols: SymbolTable::new(),
             start_address: 0x0080,
             skip_label_counter: 0,
+            internal_labels: HashSet::new(),
             reserved_ranges: Vec::new(),
+            include_dirs: Vec::new(),
+            files: FileCache::default(),
+            ref_log: ReferenceLog::default(),
+            referenced: HashSet::new(),
+            references: Vec::new(),
+            warnings: Vec::new(),
+            memory_config: None,
+            dialect: Dialect::default(),
+            layout: None,
+            byte_addrs: Vec::new(),
+            image: AssembledImage::default(),
+            #[cfg(feature = "listing")]
+            listing_xref: false,
         }
     }
 
@@ -61,6 +105,51 @@ impl Assembler6502 {
         self.assemble(src).map_err(AsmError::Asm)
     }
 
+    /// Assemble and return the output placed at its addresses, one chunk
+    /// per region, instead of concatenated.
+    pub fn assemble_image(&mut self, src: &str) -> Result<AssembledImage, AsmError> {
+        self.assemble(src).map_err(AsmError::Asm)?;
+        Ok(self.image.clone())
+    }
+
+    /// The placed output of the last successful assembly.
+    pub fn image(&self) -> &AssembledImage {
+        &self.image
+    }
+
+    /// Assemble a source file. `.include` paths inside it resolve relative
+    /// to its directory, and every location names the file.
+    pub fn assemble_file(&mut self, path: impl AsRef<Path>) -> Result<Vec<u8>, AsmError> {
+        let path = path.as_ref();
+        self.files.clear();
+        let src = self.files.read(path)?;
+        let src = std::str::from_utf8(&src)
+            .map_err(|_| AsmError::Asm(format!("{}: not UTF-8 text", path.display())))?;
+        let name: Arc<str> = Arc::from(path.display().to_string());
+        let (bytes, _items) = self.assemble_named(src, Some(name)).map_err(AsmError::Asm)?;
+        Ok(bytes)
+    }
+
+    /// Directories searched, in order, for `.include` files not found next
+    /// to the including file.
+    pub fn add_include_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.include_dirs.push(dir.into());
+    }
+
+    /// Where `.include` and `.incbin` read files from; the filesystem by
+    /// default. Each file is read at most once per assembly.
+    pub fn set_file_provider(&mut self, provider: impl FileProvider + 'static) {
+        self.files.set_provider(Box::new(provider));
+    }
+
+    pub fn clear_include_dirs(&mut self) {
+        self.include_dirs.clear();
+    }
+
+    pub fn include_dirs(&self) -> &[PathBuf] {
+        &self.include_dirs
+    }
+
     pub fn set_origin(&mut self, addr: u16) {
         self.start_address = addr;
     }
@@ -77,6 +166,82 @@ impl Assembler6502 {
         self.symbols.get(name)
     }
 
+    /// Define a symbol before assembly, e.g. a build variant (`PAL = 1`).
+    /// It is visible to conditions and expressions like a constant and
+    /// stays defined across assemblies until `clear_defined_symbols`.
+    pub fn define_symbol(&mut self, name: &str, value: u16) {
+        self.symbols.predefine(name.to_string(), value);
+    }
+
+    pub fn clear_defined_symbols(&mut self) {
+        self.symbols.clear_predefined();
+    }
+
+    /// Symbols are case-sensitive by default. With `false`, symbol names
+    /// are folded to uppercase, so `Loop`, `loop` and `LOOP` are the same
+    /// symbol and every symbol view reports the folded name. Mnemonics,
+    /// index registers and directives are case-insensitive either way.
+    pub fn set_case_sensitive_symbols(&mut self, enabled: bool) {
+        self.symbols.set_case_sensitive(enabled);
+    }
+
+    /// Read sources written for another assembler's syntax, e.g. with
+    /// colon-less labels in column 0.
+    pub fn set_dialect(&mut self, dialect: Dialect) {
+        self.dialect = dialect;
+    }
+
+    pub fn dialect(&self) -> &Dialect {
+        &self.dialect
+    }
+
+    /// Kind, value and definition site of a symbol from the last assembly.
+    pub fn symbol_info(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.info(name)
+    }
+
+    /// Symbols from the last assembly, sorted, without the labels the
+    /// assembler generates for itself (`__skip_N`).
+    pub fn sorted_symbols(&self, order: SymbolOrder) -> impl Iterator<Item = &Symbol> {
+        self.symbols.sorted(order, false).into_iter()
+    }
+
+    /// Like [`Self::sorted_symbols`] but including internal symbols.
+    pub fn sorted_symbols_with_internal(&self, order: SymbolOrder) -> impl Iterator<Item = &Symbol> {
+        self.symbols.sorted(order, true).into_iter()
+    }
+
+    /// Labels, constants and variables from the last assembly that no
+    /// instruction, data directive, origin or other constant refers to,
+    /// in source order. Predefined symbols are never reported.
+    pub fn unused_symbols(&self) -> Vec<&Symbol> {
+        let mut unused: Vec<&Symbol> = self
+            .symbols
+            .sorted(SymbolOrder::Name, false)
+            .into_iter()
+            .filter(|s| s.kind != SymbolKind::Predefined && !self.referenced.contains(&s.name))
+            .collect();
+        unused.sort_by(|a, b| a.loc.cmp(&b.loc).then_with(|| a.name.cmp(&b.name)));
+        unused
+    }
+
+    /// Every symbol reference made by the emitted program, in output
+    /// order: instruction operands, data directives and origins.
+    pub fn references(&self) -> &[Reference] {
+        &self.references
+    }
+
+    /// The `.warning`s the last assembly reached, in output order.
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// The cross-reference entries for one symbol.
+    pub fn references_to<'a>(&'a self, name: &str) -> impl Iterator<Item = &'a Reference> + 'a {
+        let key = self.symbols.key(name).into_owned();
+        self.references.iter().filter(move |r| r.symbol == key)
+    }
+
     pub fn assemble_with_symbols(
         &mut self,
         src: &str,
@@ -85,77 +250,13 @@ impl Assembler6502 {
         Ok((b, self.symbols.clone_labels()))
     }
 
+    #[allow(clippy::type_complexity)]
     pub fn assemble_with_addr_map(
         &mut self,
         src: &str,
     ) -> Result<(Vec<u8>, Vec<(usize, u16)>), AsmError> {
-        let (bytes, items) = self.assemble(src).map_err(AsmError::Asm)?;
-        let mut map = Vec::new();
-        let mut pc = self.start_address;
-        let mut idx = 0usize;
-        for it in items.iter() {
-            match it {
-                Item::Instruction { mnemonic, operand } => {
-                    let b = self
-                        .assemble_instruction(mnemonic, operand.as_deref(), pc)
-                        .map_err(AsmError::Asm)?;
-                    for _ in 0..b.len() {
-                        map.push((idx, pc));
-                        idx += 1;
-                        pc = pc.wrapping_add(1);
-                    }
-                }
-                Item::Data(exprs) => {
-                    let eval = ExpressionEvaluator::new(&self.symbols, pc);
-                    for expr in exprs {
-                        eval.evaluate_u16(expr).map_err(AsmError::Asm)?;
-                        map.push((idx, pc));
-                        idx += 1;
-                        pc = pc.wrapping_add(1);
-                    }
-                }
-                Item::Words(exprs) => {
-                    let eval = ExpressionEvaluator::new(&self.symbols, pc);
-                    for expr in exprs {
-                        eval.evaluate_u16(expr).map_err(AsmError::Asm)?;
-                        map.push((idx, pc));
-                        idx += 1;
-                        pc = pc.wrapping_add(1);
-                        map.push((idx, pc));
-                        idx += 1;
-                        pc = pc.wrapping_add(1);
-                    }
-                }
-                Item::String(s) => {
-                    for _ in s.bytes() {
-                        map.push((idx, pc));
-                        idx += 1;
-                        pc = pc.wrapping_add(1);
-                    }
-                }
-                Item::IncBin(filename) => {
-                    if let Ok(bytes) = fs::read(filename) {
-                        for _ in bytes {
-                            map.push((idx, pc));
-                            idx += 1;
-                            pc = pc.wrapping_add(1);
-                        }
-                    }
-                }
-                Item::Org(expr) => {
-                    let eval = ExpressionEvaluator::new(&self.symbols, pc);
-                    pc = eval.evaluate_u16(expr).map_err(AsmError::Asm)?;
-                }
-                Item::Pad(n) => {
-                    for _ in 0..*n {
-                        map.push((idx, pc));
-                        idx += 1;
-                        pc = pc.wrapping_add(1);
-                    }
-                }
-                Item::Label(_) | Item::Constant(_, _) => {}
-            }
-        }
+        let (bytes, _items) = self.assemble(src).map_err(AsmError::Asm)?;
+        let map = self.byte_addrs.iter().copied().enumerate().collect();
         Ok((bytes, map))
     }
 
@@ -167,6 +268,13 @@ impl Assembler6502 {
         self.symbols.clear();
         self.start_address = 0x0080;
         self.skip_label_counter = 0;
+        self.internal_labels.clear();
+        self.referenced.clear();
+        self.references.clear();
+        self.warnings.clear();
+        self.layout = None;
+        self.byte_addrs.clear();
+        self.image = AssembledImage::default();
     }
 
     // ===== Reserved memory ranges =====
@@ -176,6 +284,17 @@ impl Assembler6502 {
     /// Returns an error if the range is malformed, overlaps an existing
     /// reserved range, or sits closer than 3 bytes (the JMP size) to one.
     pub fn add_reserved_range(&mut self, start: u16, end: u16) -> Result<(), AsmError> {
+        self.push_reserved_range(ReservedRange { start, end, bank: None })
+    }
+
+    /// Like `add_reserved_range`, but only for code placed in `bank`
+    /// (`.bank`, or a banked memory area).
+    pub fn add_bank_reserved_range(&mut self, bank: u32, start: u16, end: u16) -> Result<(), AsmError> {
+        self.push_reserved_range(ReservedRange { start, end, bank: Some(bank) })
+    }
+
+    fn push_reserved_range(&mut self, range: ReservedRange) -> Result<(), AsmError> {
+        let (start, end) = (range.start, range.end);
         if start > end {
             return Err(AsmError::Asm(format!(
                 "Invalid reserved range: ${:04X}-${:04X} (start > end)",
@@ -188,7 +307,8 @@ impl Assembler6502 {
                 start, end
             )));
         }
-        for r in &self.reserved_ranges {
+        // Ranges for two different banks never meet
+        for r in self.reserved_ranges.iter().filter(|r| r.applies_to(range.bank) || range.applies_to(r.bank)) {
             if start <= r.end && end >= r.start {
                 return Err(AsmError::Asm(format!(
                     "Reserved range ${:04X}-${:04X} overlaps existing ${:04X}-${:04X}",
@@ -204,7 +324,7 @@ impl Assembler6502 {
                 )));
             }
         }
-        self.reserved_ranges.push(ReservedRange { start, end });
+        self.reserved_ranges.push(range);
         self.reserved_ranges.sort_by_key(|r| r.start);
         Ok(())
     }
@@ -217,22 +337,89 @@ impl Assembler6502 {
         &self.reserved_ranges
     }
 
+    // ===== Segments =====
+
+    /// Place `.segment` contents with `config` instead of the flat `*=`
+    /// model. Programs may then not use `*=`.
+    pub fn set_memory_config(&mut self, config: MemoryConfig) {
+        self.memory_config = Some(config);
+    }
+
+    pub fn clear_memory_config(&mut self) {
+        self.memory_config = None;
+    }
+
+    pub fn memory_config(&self) -> Option<&MemoryConfig> {
+        self.memory_config.as_ref()
+    }
+
+    /// The bank label `name` was placed in by the last assembly: its
+    /// `.bank`, or the bank of its memory area.
+    pub fn bank_of(&self, name: &str) -> Option<u32> {
+        self.symbols.bank(name)
+    }
+
+    /// The bank of each label in banked memory, keyed like the symbol
+    /// table. Linking has fixed which segment every label is in.
+    fn label_banks(&self, instructions: &[Item]) -> HashMap<String, u32> {
+        let Some(config) = &self.layout else {
+            return HashMap::new();
+        };
+        let mut banks = HashMap::new();
+        let mut bank = None;
+        for inst in instructions {
+            match inst {
+                Item::Segment(name) => bank = config.segment_bank(name),
+                Item::Label(name) => {
+                    if let Some(bank) = bank {
+                        banks.insert(self.symbols.key(name).into_owned(), bank);
+                    }
+                }
+                _ => {}
+            }
+        }
+        banks
+    }
+
     // ===== Parsing =====
 
     pub fn parse_source(&self, source: &str) -> Result<Vec<Item>, String> {
-        parse_source(source)
+        parse_file(source, None, &self.dialect, &|_| false)
     }
 
     #[allow(dead_code)]
     fn parse_line(&self, line: &str) -> Result<Option<Either<Item>>, String> {
-        parse_line(line)
+        parse_line(line, &self.dialect)
     }
 
     // ===== Assembly core =====
 
     fn assemble(&mut self, code: &str) -> Result<(Vec<u8>, Vec<Item>), String> {
-        let mut instructions = self.parse_source(code)?;
+        self.files.clear();
+        self.assemble_named(code, None)
+    }
+
+    fn assemble_named(&mut self, code: &str, file: Option<Arc<str>>) -> Result<(Vec<u8>, Vec<Item>), String> {
+        self.warnings.clear();
+        let parsed = parse_file(code, file.clone(), &self.dialect, &|_| false)?;
+        let mut instructions =
+            Expander::new(&self.opcodes, self.symbols.predefined_only(), &self.include_dirs, &self.files, &self.dialect)
+                .expand(parsed, file.as_deref())?;
+        self.layout = match &self.memory_config {
+            Some(config) => Some(config.clone()),
+            None if instructions.iter().any(|i| matches!(i, Item::Bank { .. })) => {
+                let (config, banked) = link_banks(instructions)?;
+                instructions = banked;
+                Some(config)
+            }
+            None => None,
+        };
+        if let Some(config) = &self.layout {
+            instructions = config.link(instructions)?;
+        }
+        self.symbols.set_banks(self.label_banks(&instructions));
         self.skip_label_counter = 0;
+        self.internal_labels.clear();
 
         // Adaptive pass limit. Both reserved-range insertion and long-branch
         // expansion only ever ADD items, so convergence is guaranteed; the
@@ -258,26 +445,25 @@ impl Assembler6502 {
 
                 for inst in instructions.iter() {
                     if let Item::Instruction { mnemonic, operand } = inst {
-                        if is_branch(mnemonic.as_str()) {
-                            if let Some(target) = operand {
-                                if let Some(target_addr) = self.symbols.get(target) {
-                                    let offset = target_addr as i32 - (current_address as i32 + 2);
-                                    if offset < -128 || offset > 127 {
-                                        problematic_branches.push(format!(
-                                            "${:04X}: {} {} (offset: {}, target: ${:04X})",
-                                            current_address, mnemonic, target, offset, target_addr
-                                        ));
-                                    }
-                                }
+                        if is_branch(mnemonic.as_str())
+                            && let Some(target) = operand
+                            && let Some(target_addr) = self.symbols.get(target)
+                        {
+                            let offset = target_addr as i32 - (current_address as i32 + 2);
+                            if !(-128..=127).contains(&offset) {
+                                problematic_branches.push(format!(
+                                    "${:04X}: {} {} (offset: {}, target: ${:04X})",
+                                    current_address, mnemonic, target, offset, target_addr
+                                ));
                             }
                         }
                         if let Ok(size) = self.instruction_size(inst, current_address) {
                             current_address = current_address.wrapping_add(size as u16);
                         }
-                    } else if !matches!(inst, Item::Label(_)) {
-                        if let Ok(size) = self.instruction_size(inst, current_address) {
-                            current_address = current_address.wrapping_add(size as u16);
-                        }
+                    } else if !matches!(inst, Item::Label(_))
+                        && let Ok(size) = self.instruction_size(inst, current_address)
+                    {
+                        current_address = current_address.wrapping_add(size as u16);
                     }
                 }
 
@@ -300,95 +486,315 @@ impl Assembler6502 {
         let mut machine: Vec<u8> = Vec::new();
         let mut current_address = self.start_address;
 
-        // First pass: compute label addresses and evaluate constants
+        // First pass: compute label addresses and evaluate constants.
+        // References are logged from here on so the unused-symbol report
+        // sees constants that only feed other constants.
         self.symbols.clear();
+        self.ref_log.start();
+        let mut loc = SourceLoc::default();
+        let mut blocks = LogicalBlocks::default();
+        let mut open_blocks = Vec::new();
         for inst in instructions.iter() {
             match inst {
+                Item::Line(l) => {
+                    loc = l.clone();
+                }
                 Item::Label(name) => {
-                    self.symbols.insert(name.clone(), current_address);
+                    let kind = if self.internal_labels.contains(name) {
+                        SymbolKind::Internal
+                    } else {
+                        SymbolKind::Label
+                    };
+                    self.symbols.define(name.clone(), current_address, kind, loc.clone());
                 }
                 Item::Constant(name, expr) => {
                     // Evaluate constant and add to symbol table
-                    let eval = ExpressionEvaluator::new(&self.symbols, current_address);
+                    let eval = self.evaluator(current_address);
                     let value = eval.evaluate_u16(expr)
-                        .map_err(|e| format!("Constant '{}': {}", name, e))?;
-                    self.symbols.insert(name.clone(), value);
+                        .map_err(|e| format!("{}: Constant '{}': {}", loc, name, e))?;
+                    self.symbols.define(name.clone(), value, SymbolKind::Constant, loc.clone());
                 }
                 Item::Org(expr) => {
-                    let eval = ExpressionEvaluator::new(&self.symbols, current_address);
+                    if let Some(open) = open_blocks.last() {
+                        // With a memory configuration the only `*=` are
+                        // the ones that start each area
+                        return Err(match self.layout {
+                            Some(_) => format!("{}: .pseudopc without .realpc", open),
+                            None => format!("{}: *= inside the .pseudopc block opened at {}", loc, open),
+                        });
+                    }
+                    let eval = self.evaluator(current_address);
                     current_address = eval.evaluate_u16(expr)
-                        .map_err(|e| format!("ORG directive: {}", e))?;
+                        .map_err(|e| format!("{}: ORG directive: {}", loc, e))?;
+                }
+                Item::Segment(_) if !open_blocks.is_empty() => {
+                    return Err(format!("{}: .pseudopc without .realpc", open_blocks.last().unwrap()));
+                }
+                Item::Logical(expr) => {
+                    let logical = self.evaluator(current_address).evaluate_u16(expr)
+                        .map_err(|e| format!("{}: .pseudopc: {}", loc, e))?;
+                    current_address = blocks.enter(current_address, logical);
+                    open_blocks.push(loc.clone());
+                }
+                Item::RealPc => {
+                    current_address = blocks.leave(current_address)
+                        .ok_or_else(|| format!("{}: .realpc without .pseudopc", loc))?;
+                    open_blocks.pop();
                 }
                 _ => {
-                    current_address =
-                        current_address.wrapping_add(self.instruction_size(inst, current_address)? as u16);
+                    let size = self.instruction_size(inst, current_address).map_err(|e| {
+                        match self.later_symbol(inst, &instructions) {
+                            Some((directive, name)) => format!(
+                                "{}: {} size depends on '{}', which is defined later; \
+                                 a storage size can only use symbols defined above it",
+                                loc, directive, name
+                            ),
+                            None => format!("{}: {}", loc, e),
+                        }
+                    })?;
+                    current_address = current_address.wrapping_add(size as u16);
                 }
             }
         }
+        if let Some(open) = open_blocks.last() {
+            return Err(format!("{}: .pseudopc without .realpc", open));
+        }
 
-        // Second pass: emit bytes
+        // Second pass: emit bytes, recording each item's references and
+        // the address of every byte
+        self.referenced = self.ref_log.take().into_iter().collect();
+        let mut references = Vec::new();
+        let config = self.layout.clone();
+        let mut area: Option<AreaOutput> = None;
+        // Where each area's bytes start in the output, and its bank
+        let mut area_starts: Vec<(usize, Option<u32>)> = Vec::new();
+        let mut segment = None;
+        let mut byte_addrs = Vec::new();
+        let mut writes = Vec::new();
+        loc = SourceLoc::default();
         current_address = self.start_address;
         for inst in instructions.iter() {
+            let item_address = current_address;
+            let physical = blocks.physical(item_address);
+            let mut emitted = machine.len();
+            let bss = segment.is_some_and(|s: &Segment| s.kind == SegmentKind::Bss);
+            if bss
+                && matches!(
+                    inst,
+                    Item::Instruction { .. } | Item::Data(_) | Item::Words(_) | Item::Values { .. }
+                    | Item::Text { .. } | Item::IncBin { .. }
+                )
+            {
+                return Err(format!(
+                    "{}: segment \"{}\" is BSS: it can only reserve space, not hold code or data",
+                    loc, segment.unwrap().name
+                ));
+            }
             match inst {
+                Item::Line(l) => {
+                    loc = l.clone();
+                }
                 Item::Label(_) => {}
+                Item::Logical(_) | Item::RealPc => {
+                    current_address = self.logical_step(inst, current_address, &mut blocks);
+                }
+                Item::Segment(name) => {
+                    let Some(config) = &config else {
+                        return Err(format!("{}: .segment \"{}\" needs a memory configuration (set_memory_config)", loc, name));
+                    };
+                    // The linker only emits configured segments
+                    let next = config.segment(name).unwrap();
+                    let next_area = config.area(&next.area).unwrap();
+                    if area.as_ref().is_none_or(|out| out.area.name != next_area.name) {
+                        if let Some(done) = area.take() {
+                            done.close(&mut machine, &mut byte_addrs);
+                            // The padding belongs to the area just closed
+                            emitted = machine.len();
+                        }
+                        area = Some(AreaOutput { area: next_area, base: machine.len() });
+                        area_starts.push((machine.len(), next_area.bank));
+                    }
+                    segment = Some(next);
+                }
+                Item::Macro { .. } | Item::Rept { .. } | Item::For { .. } | Item::Include { .. }
+                | Item::Struct { .. } | Item::Enum { .. } | Item::StructData { .. } | Item::Bank { .. }
+                | Item::If(_) | Item::ElseIf(_) | Item::Else | Item::EndIf
+                | Item::String { .. } | Item::Encoding(_) | Item::CharMap(..) => {}
                 Item::Constant(_, _) => {}  // Constants don't emit bytes
                 Item::Org(expr) => {
-                    let eval = ExpressionEvaluator::new(&self.symbols, current_address);
+                    let eval = self.evaluator(current_address);
                     current_address = eval.evaluate_u16(expr)
-                        .map_err(|e| format!("ORG directive: {}", e))?;
+                        .map_err(|e| format!("{}: ORG directive: {}", loc, e))?;
                 }
                 Item::Data(exprs) => {
-                    let eval = ExpressionEvaluator::new(&self.symbols, current_address);
+                    let eval = self.evaluator(current_address);
                     for expr in exprs {
                         let val = eval.evaluate_u16(expr)
-                            .map_err(|e| format!(".byte directive at ${:04X}: {}", current_address, e))?;
+                            .map_err(|e| format!("{}: .byte directive at ${:04X}: {}", loc, current_address, e))?;
                         machine.push((val & 0xFF) as u8);
                         current_address = current_address.wrapping_add(1);
                     }
                 }
                 Item::Words(exprs) => {
-                    let eval = ExpressionEvaluator::new(&self.symbols, current_address);
+                    let eval = self.evaluator(current_address);
                     for expr in exprs {
                         let val = eval.evaluate_u16(expr)
-                            .map_err(|e| format!(".word directive at ${:04X}: {}", current_address, e))?;
+                            .map_err(|e| format!("{}: .word directive at ${:04X}: {}", loc, current_address, e))?;
                         // Little-endian: low byte first, then high byte
                         machine.push((val & 0xFF) as u8);
                         machine.push((val >> 8) as u8);
                         current_address = current_address.wrapping_add(2);
                     }
                 }
-                Item::String(s) => {
-                    for byte in s.bytes() {
-                        machine.push(byte);
-                        current_address = current_address.wrapping_add(1);
+                Item::Values { layout, exprs } => {
+                    let eval = self.evaluator(current_address);
+                    for expr in exprs {
+                        let val = eval.evaluate(expr)
+                            .map_err(|e| format!("{}: {} directive at ${:04X}: {}", loc, layout.directive(), current_address, e))?;
+                        machine.extend_from_slice(&layout.bytes(val));
+                        current_address = current_address.wrapping_add(layout.size() as u16);
                     }
                 }
-                Item::IncBin(filename) => {
-                    let bytes = fs::read(filename)
-                        .map_err(|e| format!(".incbin \"{}\" at ${:04X}: {}", filename, current_address, e))?;
-                    for byte in bytes {
-                        machine.push(byte);
+                Item::Text { directive, bytes, .. } => {
+                    let eval = self.evaluator(current_address);
+                    for expr in bytes {
+                        let val = eval.evaluate_u16(expr)
+                            .map_err(|e| format!("{}: {} directive at ${:04X}: {}", loc, directive, current_address, e))?;
+                        machine.push((val & 0xFF) as u8);
                         current_address = current_address.wrapping_add(1);
                     }
                 }
+                Item::IncBin { path, offset, length } => {
+                    let (bytes, range) = self
+                        .incbin_slice(path, offset.as_ref(), length.as_ref(), current_address)
+                        .map_err(|e| format!("{}: {} at ${:04X}", loc, e, current_address))?;
+                    current_address = current_address.wrapping_add(range.len() as u16);
+                    machine.extend_from_slice(&bytes[range]);
+                }
                 Item::Instruction { mnemonic, operand } => {
                     let bytes = self.assemble_instruction(mnemonic, operand.as_deref(), current_address)
                         .map_err(|e| {
                             let op_str = operand.as_ref().map(|s| format!(" {}", s)).unwrap_or_default();
-                            format!("${:04X}: {}{} - {}", current_address, mnemonic, op_str, e)
+                            format!("{}: ${:04X}: {}{} - {}", loc, current_address, mnemonic, op_str, e)
                         })?;
                     current_address = current_address.wrapping_add(bytes.len() as u16);
                     machine.extend_from_slice(&bytes);
                 }
                 Item::Pad(n) => {
-                    for _ in 0..*n {
-                        machine.push(0);
-                        current_address = current_address.wrapping_add(1);
+                    if !bss {
+                        machine.resize(machine.len() + *n, 0);
+                    }
+                    current_address = current_address.wrapping_add(*n as u16);
+                }
+                Item::Align { fill, .. } | Item::Fill { value: fill, .. } => {
+                    let n = self.instruction_size(inst, current_address)
+                        .map_err(|e| format!("{}: {} at ${:04X}", loc, e, current_address))?;
+                    let byte = self.fill_byte(fill.as_ref(), current_address)
+                        .map_err(|e| format!("{}: {} at ${:04X}", loc, e, current_address))?;
+                    if !bss {
+                        machine.resize(machine.len() + n, byte);
+                    }
+                    current_address = current_address.wrapping_add(n as u16);
+                }
+                Item::Assert { cond, text, message } => {
+                    // Checked here, once every address is final
+                    let eval = self.evaluator(current_address);
+                    let value = eval.evaluate(cond)
+                        .map_err(|e| format!("{}: .assert {}: {}", loc, text, e))?;
+                    if value == 0 {
+                        return Err(match message {
+                            Some(message) => format!("{}: assertion failed: {}", loc, message),
+                            None => format!("{}: assertion failed: {}", loc, text),
+                        });
                     }
                 }
+                Item::Error(message) => return Err(format!("{}: {}", loc, message)),
+                Item::Warning(message) => {
+                    self.warnings.push(Warning { loc: loc.clone(), message: message.clone() });
+                }
+                Item::Skip(_) => {
+                    // Moves the PC like `*=` does; nothing lands in the output
+                    let n = self.instruction_size(inst, current_address)
+                        .map_err(|e| format!("{}: {} at ${:04X}", loc, e, current_address))?;
+                    current_address = current_address.wrapping_add(n as u16);
+                }
+            }
+
+            let mut first_byte = emitted;
+            if let Some(out) = &area {
+                // Bytes sit at their address's offset in the area; a gap
+                // left by BSS or .skip before them is padded
+                let offset = out.base + physical.wrapping_sub(out.area.start) as usize;
+                if machine.len() > emitted && offset < emitted {
+                    return Err(format!(
+                        "{}: output at ${:04X} is behind bytes already placed in memory area \"{}\"; *= can only move forward in an area",
+                        loc, physical, out.area.name
+                    ));
+                }
+                if machine.len() > emitted && offset > emitted {
+                    let gap = offset - emitted;
+                    machine.splice(emitted..emitted, iter::repeat_n(out.area.fill.unwrap_or(0), gap));
+                    byte_addrs.extend((0..gap).map(|i| physical.wrapping_sub((gap - i) as u16)));
+                    first_byte = offset;
+                }
+                let used = blocks.physical(current_address).wrapping_sub(out.area.start) as u32;
+                if !matches!(inst, Item::Org(_)) && used > out.area.size {
+                    return Err(format!(
+                        "{}: segment \"{}\" overflows memory area \"{}\" (${:04X}-${:04X}) by {}",
+                        loc, segment.map_or("", |s| s.name.as_str()), out.area.name,
+                        out.area.start, out.area.end(), byte_count((used - out.area.size) as usize)
+                    ));
+                }
+            }
+            byte_addrs.extend((0..machine.len() - first_byte).map(|i| physical.wrapping_add(i as u16)));
+            if machine.len() > first_byte {
+                let start = physical as u32;
+                let bank = area.as_ref().and_then(|out| out.area.bank);
+                writes.push((bank, start, start + (machine.len() - first_byte) as u32, loc.clone()));
+            }
+
+            for symbol in self.ref_log.take() {
+                let (statement, access) = match inst {
+                    Item::Instruction { mnemonic, operand: Some(op) } => {
+                        (format!("{} {}", mnemonic, op), access_kind(mnemonic, op))
+                    }
+                    Item::Words(_) => (".word".to_string(), AccessKind::Data),
+                    Item::Org(_) => ("*=".to_string(), AccessKind::Data),
+                    Item::Logical(_) => (".pseudopc".to_string(), AccessKind::Data),
+                    Item::IncBin { .. } => (".incbin".to_string(), AccessKind::Data),
+                    Item::Text { directive, .. } => (directive.to_string(), AccessKind::Data),
+                    Item::Values { layout, .. } => (layout.directive().to_string(), AccessKind::Data),
+                    Item::Assert { .. } => (".assert".to_string(), AccessKind::Data),
+                    Item::Align { .. } => (".align".to_string(), AccessKind::Data),
+                    Item::Fill { .. } => (".fill".to_string(), AccessKind::Data),
+                    Item::Skip(_) => (".skip".to_string(), AccessKind::Data),
+                    _ => (".byte".to_string(), AccessKind::Data),
+                };
+                self.referenced.insert(symbol.clone());
+                references.push(Reference {
+                    symbol,
+                    loc: loc.clone(),
+                    address: item_address,
+                    statement,
+                    access,
+                });
             }
         }
 
+        if let Some(done) = area {
+            done.close(&mut machine, &mut byte_addrs);
+        }
+        self.ref_log.stop();
+        self.references = references;
+        check_overlaps(&writes)?;
+        let mut byte_banks = vec![None; machine.len()];
+        for (i, &(start, bank)) in area_starts.iter().enumerate() {
+            let end = area_starts.get(i + 1).map_or(machine.len(), |next| next.0);
+            byte_banks[start..end].fill(bank);
+        }
+        self.image = AssembledImage::from_bytes(&machine, &byte_addrs, &byte_banks);
+        self.byte_addrs = byte_addrs;
+
         Ok((machine, instructions))
     }
 
@@ -400,15 +806,36 @@ impl Assembler6502 {
         operand: Option<&str>,
         current_address: u16,
     ) -> Result<Vec<u8>, String> {
+        // Mnemonics are case-insensitive; the parser already uppercases
+        // them, so only direct callers pay for the conversion.
+        let upper;
+        let mnemonic = if mnemonic.bytes().any(|b| b.is_ascii_lowercase()) {
+            upper = mnemonic.to_ascii_uppercase();
+            upper.as_str()
+        } else {
+            mnemonic
+        };
+
         // Implied/accumulator form
-        if operand.is_none() {
+        let Some(operand_raw) = operand else {
             if let Some(&op) = self.opcodes.opcodes.get(mnemonic) {
                 return Ok(vec![op]);
             }
             return Err(format!("Unknown mnemonic: {}", mnemonic));
+        };
+
+        // Explicit accumulator operand: `ASL A`. `A` can't be a symbol
+        // name (the parser rejects it), so this is never a label.
+        if is_accumulator(operand_raw) {
+            return self
+                .opcodes
+                .extended_opcodes
+                .get(mnemonic)
+                .and_then(|m| m.get("accumulator"))
+                .map(|&op| vec![op])
+                .ok_or_else(|| format!("Accumulator addressing not supported for {}", mnemonic));
         }
 
-        let operand_raw = operand.unwrap();
         let (operand, mode_override) = parse_addr_override(operand_raw);
 
         // Special handlers
@@ -425,7 +852,7 @@ impl Assembler6502 {
         // Immediate mode: #value (can have expressions like #$02+1)
         if let Some(rest) = operand.strip_prefix('#') {
             let expr = ExpressionParser::parse(rest)?;
-            let eval = ExpressionEvaluator::new(&self.symbols, current_address);
+            let eval = self.evaluator(current_address);
             let value = eval.evaluate_u16(&expr)?;
             if value > 0xFF {
                 return Err(format!("Immediate value too large: ${:04X}", value));
@@ -458,19 +885,19 @@ impl Assembler6502 {
         if operand.starts_with('(') && operand.ends_with(')') {
             let inner = &operand[1..operand.len() - 1];
             let expr = ExpressionParser::parse(inner)?;
-            let eval = ExpressionEvaluator::new(&self.symbols, current_address);
+            let eval = self.evaluator(current_address);
             let value = eval.evaluate_u16(&expr)?;
             return Ok(vec![0x6C, (value & 0xFF) as u8, (value >> 8) as u8]);
         }
         let expr = ExpressionParser::parse(operand)?;
-        let eval = ExpressionEvaluator::new(&self.symbols, current_address);
+        let eval = self.evaluator(current_address);
         let value = eval.evaluate_u16(&expr)?;
         Ok(vec![0x4C, (value & 0xFF) as u8, (value >> 8) as u8])
     }
 
     fn handle_subroutine(&self, operand: &str, current_address: u16) -> Result<Vec<u8>, String> {
         let expr = ExpressionParser::parse(operand)?;
-        let eval = ExpressionEvaluator::new(&self.symbols, current_address);
+        let eval = self.evaluator(current_address);
         let value = eval.evaluate_u16(&expr)?;
         Ok(vec![0x20, (value & 0xFF) as u8, (value >> 8) as u8])
     }
@@ -485,8 +912,9 @@ impl Assembler6502 {
             .symbols
             .get(operand)
             .ok_or_else(|| format!("Undefined label: {}", operand))?;
+        self.ref_log.record(&self.symbols.key(operand));
         let offset = target as i32 - (current_address as i32 + 2);
-        if offset < -128 || offset > 127 {
+        if !(-128..=127).contains(&offset) {
             return Err(format!(
                 "Branch offset out of range: {}. Target: ${:04X}, Current: ${:04X}",
                 offset, target, current_address
@@ -498,14 +926,12 @@ impl Assembler6502 {
 
     fn handle_indirect(&self, mnemonic: &str, operand: &str, current_address: u16) -> Result<Vec<u8>, String> {
         // (addr),Y
-        if operand.contains("),Y") {
-            let inner = operand
-                .strip_prefix('(')
-                .and_then(|s| s.split("),Y").next())
-                .unwrap_or("")
-                .trim();
-            let expr = ExpressionParser::parse(inner)?;
-            let eval = ExpressionEvaluator::new(&self.symbols, current_address);
+        if let Some((inner, idx)) = operand[1..].rsplit_once(')')
+            && let Some(idx) = idx.trim().strip_prefix(',')
+            && idx.trim().eq_ignore_ascii_case("Y")
+        {
+            let expr = ExpressionParser::parse(inner.trim())?;
+            let eval = self.evaluator(current_address);
             let val = eval.evaluate_u16(&expr)?;
             let code = self
                 .opcodes
@@ -523,7 +949,7 @@ impl Assembler6502 {
             let idx = parts.next().unwrap_or("");
             if idx.eq_ignore_ascii_case("X") {
                 let expr = ExpressionParser::parse(a)?;
-                let eval = ExpressionEvaluator::new(&self.symbols, current_address);
+                let eval = self.evaluator(current_address);
                 let val = eval.evaluate_u16(&expr)?;
                 let code = self
                     .opcodes
@@ -546,23 +972,23 @@ impl Assembler6502 {
         current_address: u16,
     ) -> Result<Vec<u8>, String> {
         let expr = ExpressionParser::parse(addr_part)?;
-        let eval = ExpressionEvaluator::new(&self.symbols, current_address);
+        let eval = self.evaluator(current_address);
         let val = eval.evaluate_u16(&expr)?;
         let force_zp = mode_override == AddrOverride::ForceZp;
         let force_abs = mode_override == AddrOverride::ForceAbs;
         let is_zp = val < 0x100;
+        let idx = idx.to_ascii_uppercase();
         let mode_zp = format!("zeropage,{}", idx);
         let mode_abs = format!("absolute,{}", idx);
 
-        if (is_zp && !force_abs) || force_zp {
-            if let Some(code) = self
+        if ((is_zp && !force_abs) || force_zp)
+            && let Some(code) = self
                 .opcodes
                 .extended_opcodes
                 .get(mnemonic)
                 .and_then(|m| m.get(mode_zp.as_str()))
-            {
-                return Ok(vec![*code, (val & 0xFF) as u8]);
-            }
+        {
+            return Ok(vec![*code, (val & 0xFF) as u8]);
         }
 
         let code = self
@@ -582,20 +1008,19 @@ impl Assembler6502 {
         current_address: u16,
     ) -> Result<Vec<u8>, String> {
         let expr = ExpressionParser::parse(operand)?;
-        let eval = ExpressionEvaluator::new(&self.symbols, current_address);
+        let eval = self.evaluator(current_address);
         let val = eval.evaluate_u16(&expr)?;
         let force_zp = mode_override == AddrOverride::ForceZp;
         let force_abs = mode_override == AddrOverride::ForceAbs;
 
-        if (val < 0x100 && !force_abs) || force_zp {
-            if let Some(code) = self
+        if ((val < 0x100 && !force_abs) || force_zp)
+            && let Some(code) = self
                 .opcodes
                 .extended_opcodes
                 .get(mnemonic)
                 .and_then(|m| m.get("zeropage"))
-            {
-                return Ok(vec![*code, (val & 0xFF) as u8]);
-            }
+        {
+            return Ok(vec![*code, (val & 0xFF) as u8]);
         }
 
         let code = self
@@ -609,6 +1034,10 @@ impl Assembler6502 {
 
     // ===== Helpers =====
 
+    fn evaluator(&self, current_address: u16) -> ExpressionEvaluator<'_> {
+        ExpressionEvaluator::new(&self.symbols, current_address).with_log(&self.ref_log)
+    }
+
     fn instruction_size(&self, inst: &Item, current_address: u16) -> Result<usize, String> {
         match inst {
             Item::Instruction { mnemonic, operand } => {
@@ -619,12 +1048,11 @@ impl Assembler6502 {
                 if self.opcodes.opcodes.contains_key(m) && operand.is_none() {
                     return Ok(1);
                 }
-                if self.opcodes.opcodes.contains_key(m) && operand.is_some() {
-                    if let Some(op) = operand {
-                        if op.starts_with('#') {
-                            return Ok(2);
-                        }
-                    }
+                if self.opcodes.opcodes.contains_key(m)
+                    && let Some(op) = operand
+                    && op.starts_with('#')
+                {
+                    return Ok(2);
                 }
                 if is_branch(m) {
                     return Ok(2);
@@ -633,17 +1061,130 @@ impl Assembler6502 {
             }
             Item::Data(exprs) => Ok(exprs.len()),
             Item::Words(exprs) => Ok(exprs.len() * 2),  // 2 bytes per word
-            Item::String(s) => Ok(s.len()),
-            Item::IncBin(filename) => {
-                // Try to get file size, or return error
-                match fs::metadata(filename) {
-                    Ok(metadata) => Ok(metadata.len() as usize),
-                    Err(_) => Err(format!("Cannot read file: {}", filename)),
-                }
+            Item::Values { layout, exprs } => Ok(exprs.len() * layout.size()),
+            Item::Text { bytes, .. } => Ok(bytes.len()),
+            Item::IncBin { path, offset, length } => {
+                // Sized from the cached contents, so emit sees the same file
+                let (_, range) = self.incbin_slice(path, offset.as_ref(), length.as_ref(), current_address)?;
+                Ok(range.len())
             }
             Item::Pad(n) => Ok(*n),
-            Item::Org(_) | Item::Label(_) | Item::Constant(_, _) => Ok(0),
+            Item::Align { boundary, .. } => {
+                let n = self.evaluator(current_address)
+                    .evaluate(boundary)
+                    .map_err(|e| format!(".align: {}", e))?;
+                if n == 0 || n > 0x10000 {
+                    return Err(format!(".align boundary must be between 1 and $10000, got ${:X}", n));
+                }
+                let pc = current_address as u32;
+                Ok(((n - pc % n) % n) as usize)
+            }
+            Item::Fill { count, .. } => self.storage_count(".fill", count, current_address),
+            Item::Skip(count) => self.storage_count(".skip", count, current_address),
+            Item::Org(_) | Item::Segment(_) | Item::Logical(_) | Item::RealPc
+            | Item::Label(_) | Item::Constant(_, _) | Item::Line(_) => Ok(0),
+            Item::Macro { .. } | Item::Rept { .. } | Item::For { .. } | Item::Include { .. }
+            | Item::Struct { .. } | Item::Enum { .. } | Item::StructData { .. } | Item::Bank { .. }
+            | Item::If(_) | Item::ElseIf(_) | Item::Else | Item::EndIf
+            | Item::String { .. } | Item::Encoding(_) | Item::CharMap(..)
+            | Item::Assert { .. } | Item::Error(_) | Item::Warning(_) => Ok(0),
+        }
+    }
+
+    /// The byte count of `.fill` or `.skip`, which may depend on `*`.
+    fn storage_count(&self, directive: &str, count: &Expr, current_address: u16) -> Result<usize, String> {
+        let n = self.evaluator(current_address)
+            .evaluate(count)
+            .map_err(|e| format!("{}: {}", directive, e))?;
+        if n > 0x10000 {
+            return Err(format!(
+                "{} count ${:X} is larger than the address space (is the PC already past the target?)",
+                directive, n
+            ));
+        }
+        Ok(n as usize)
+    }
+
+    /// For storage whose size failed to evaluate during the definition
+    /// pass: the directive and a symbol in its size that is only defined
+    /// further down, which no amount of layout passes can resolve.
+    fn later_symbol(&self, inst: &Item, instructions: &[Item]) -> Option<(&'static str, String)> {
+        let (directive, expr) = match inst {
+            Item::Align { boundary, .. } => (".align", boundary),
+            Item::Fill { count, .. } => (".fill", count),
+            Item::Skip(count) => (".skip", count),
+            _ => return None,
+        };
+        let mut found = None;
+        expr.visit(&mut |e| {
+            if let Expr::Label(name) = e
+                && found.is_none()
+                && self.symbols.get(name).is_none()
+            {
+                let key = self.symbols.key(name);
+                let defined = instructions.iter().any(|item| match item {
+                    Item::Label(n) | Item::Constant(n, _) => self.symbols.key(n) == key,
+                    _ => false,
+                });
+                if defined {
+                    found = Some(name.clone());
+                }
+            }
+        });
+        found.map(|name| (directive, name))
+    }
+
+    /// The byte `.align` and `.fill` pad with; `$00` unless given.
+    fn fill_byte(&self, fill: Option<&Expr>, current_address: u16) -> Result<u8, String> {
+        match fill {
+            Some(expr) => {
+                let value = self.evaluator(current_address)
+                    .evaluate_u16(expr)
+                    .map_err(|e| format!("fill value: {}", e))?;
+                Ok((value & 0xFF) as u8)
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// The cached contents of an `.incbin` file and the range of it to
+    /// include, checked against the file size.
+    fn incbin_slice(
+        &self,
+        path: &str,
+        offset: Option<&Expr>,
+        length: Option<&Expr>,
+        current_address: u16,
+    ) -> Result<(Rc<[u8]>, Range<usize>), String> {
+        let bytes = self
+            .files
+            .read(Path::new(path))
+            .map_err(|e| format!(".incbin \"{}\": {}", path, e))?;
+        let eval = self.evaluator(current_address);
+        let start = match offset {
+            Some(expr) => eval.evaluate(expr).map_err(|e| format!(".incbin \"{}\" offset: {}", path, e))? as usize,
+            None => 0,
+        };
+        if start > bytes.len() {
+            return Err(format!(
+                ".incbin \"{}\": offset {} is past the end of the file ({} bytes)",
+                path, start, bytes.len()
+            ));
         }
+        let end = match length {
+            Some(expr) => {
+                let len = eval.evaluate(expr).map_err(|e| format!(".incbin \"{}\" length: {}", path, e))? as usize;
+                if start + len > bytes.len() {
+                    return Err(format!(
+                        ".incbin \"{}\": offset {} + length {} runs past the end of the file ({} bytes)",
+                        path, start, len, bytes.len()
+                    ));
+                }
+                start + len
+            }
+            None => bytes.len(),
+        };
+        Ok((bytes, start..end))
     }
 
     fn count_branches(&self, items: &[Item]) -> usize {
@@ -656,27 +1197,64 @@ impl Assembler6502 {
             .count()
     }
 
+    /// Follow `.pseudopc` and `.realpc` in a sizing walk: the PC after
+    /// `inst`. Errors are left for the definition pass to report.
+    fn logical_step(&self, inst: &Item, current_address: u16, blocks: &mut LogicalBlocks) -> u16 {
+        match inst {
+            Item::Logical(expr) => {
+                let logical = self.evaluator(current_address).evaluate_u16(expr).unwrap_or(current_address);
+                blocks.enter(current_address, logical)
+            }
+            Item::RealPc => blocks.leave(current_address).unwrap_or(current_address),
+            _ => current_address,
+        }
+    }
+
+    /// The item at `i` and its size, or for a `.pseudopc` the index of
+    /// its `.realpc` and the bytes of the whole block.
+    fn sized_span(&self, instructions: &[Item], i: usize, pc: u16) -> (usize, u32) {
+        if !matches!(instructions[i], Item::Logical(_)) {
+            return (i, self.instruction_size(&instructions[i], pc).unwrap_or(0) as u32);
+        }
+        let mut blocks = LogicalBlocks::default();
+        let mut current_address = pc;
+        for (k, inst) in instructions.iter().enumerate().skip(i) {
+            current_address = match inst {
+                Item::Logical(_) | Item::RealPc => self.logical_step(inst, current_address, &mut blocks),
+                _ => current_address.wrapping_add(self.instruction_size(inst, current_address).unwrap_or(0) as u16),
+            };
+            if blocks.is_empty() {
+                return (k, current_address.wrapping_sub(pc) as u32);
+            }
+        }
+        (instructions.len() - 1, blocks.physical(current_address).wrapping_sub(pc) as u32)
+    }
+
     /// Sizing pre-pass: walk items to assign label and constant addresses.
     fn rebuild_symbols(&mut self, instructions: &[Item]) {
         self.symbols.clear();
         let mut current_address = self.start_address;
+        let mut blocks = LogicalBlocks::default();
         for inst in instructions.iter() {
             match inst {
                 Item::Label(name) => {
                     self.symbols.insert(name.clone(), current_address);
                 }
                 Item::Constant(name, expr) => {
-                    let eval = ExpressionEvaluator::new(&self.symbols, current_address);
+                    let eval = self.evaluator(current_address);
                     if let Ok(value) = eval.evaluate_u16(expr) {
                         self.symbols.insert(name.clone(), value);
                     }
                 }
                 Item::Org(expr) => {
-                    let eval = ExpressionEvaluator::new(&self.symbols, current_address);
+                    let eval = self.evaluator(current_address);
                     if let Ok(addr) = eval.evaluate_u16(expr) {
                         current_address = addr;
                     }
                 }
+                Item::Logical(_) | Item::RealPc => {
+                    current_address = self.logical_step(inst, current_address, &mut blocks);
+                }
                 _ => {
                     if let Ok(size) = self.instruction_size(inst, current_address) {
                         current_address = current_address.wrapping_add(size as u16);
@@ -686,6 +1264,11 @@ impl Assembler6502 {
         }
     }
 
+    /// The reserved ranges that apply to code placed in `bank`.
+    fn reserved_ranges_for(&self, bank: Option<u32>) -> Vec<ReservedRange> {
+        self.reserved_ranges.iter().filter(|r| r.applies_to(bank)).copied().collect()
+    }
+
     /// Insert `JMP <end+1>` plus `$00`-fill before any reserved range the
     /// code would otherwise enter. The JMP is placed *immediately* at
     /// the current PC (no executable pre-pad), so a CPU that walks
@@ -703,19 +1286,29 @@ impl Assembler6502 {
         let mut output: Vec<Item> = Vec::with_capacity(instructions.len());
         let mut pc: u32 = self.start_address as u32;
         let mut modified = false;
+        // The ranges for the bank being placed
+        let mut ranges = self.reserved_ranges_for(None);
 
         let mut i = 0usize;
         while i < instructions.len() {
             let inst = &instructions[i];
 
             match inst {
-                Item::Label(_) | Item::Constant(_, _) => {
+                Item::Label(_) | Item::Constant(_, _) | Item::Line(_)
+                | Item::Assert { .. } | Item::Error(_) | Item::Warning(_) => {
+                    output.push(inst.clone());
+                    i += 1;
+                    continue;
+                }
+                Item::Segment(name) => {
+                    let bank = self.layout.as_ref().and_then(|c| c.segment_bank(name));
+                    ranges = self.reserved_ranges_for(bank);
                     output.push(inst.clone());
                     i += 1;
                     continue;
                 }
                 Item::Org(expr) => {
-                    let eval = ExpressionEvaluator::new(&self.symbols, pc as u16);
+                    let eval = self.evaluator(pc as u16);
                     if let Ok(addr) = eval.evaluate_u16(expr) {
                         pc = addr as u32;
                     }
@@ -731,7 +1324,7 @@ impl Assembler6502 {
             // (PC + JMP_SIZE) up through r_end, including the gap
             // between the JMP and r_start (those bytes are dead — the
             // JMP unconditionally jumps over them — so $00 is fine).
-            let bridge = detect_bridge(instructions, i, &self.reserved_ranges);
+            let bridge = detect_bridge(instructions, i, &ranges);
             if let Some(b) = bridge {
                 let r_end = b.r_end;
 
@@ -771,7 +1364,7 @@ impl Assembler6502 {
                 continue;
             }
 
-            for r in &self.reserved_ranges {
+            for r in &ranges {
                 if pc >= r.start as u32 && pc <= r.end as u32 {
                     return Err(format!(
                         "PC ${:04X} lands inside reserved range ${:04X}-${:04X}",
@@ -780,7 +1373,9 @@ impl Assembler6502 {
                 }
             }
 
-            let mut size = self.instruction_size(inst, pc as u16).unwrap_or(0) as u32;
+            // A .pseudopc block is copied elsewhere to run, so like a
+            // data block it is placed in one piece
+            let (last, mut size) = self.sized_span(instructions, i, pc as u16);
 
             // Long-branch-expansion sequences (`BR __skip_N + JMP
             // + Label(__skip_N)`) are atomic: splitting them with
@@ -804,7 +1399,7 @@ impl Assembler6502 {
                     // triple: BR(2) + JMP(3) + Label(0) starting at
                     // pc - 2 (= BR's PC).
                     let br_pc = pc.wrapping_sub(2);
-                    self.reserved_ranges.iter().find(|r| {
+                    ranges.iter().find(|r| {
                         let r_start = r.start as u32;
                         br_pc < r_start && br_pc + 5 > r_start.saturating_sub(JMP_SIZE)
                     }).copied().map(|r| (r, prev_branch, next_in))
@@ -851,7 +1446,7 @@ impl Assembler6502 {
             // Insert a skip-block before any range this item would either
             // cross or leave too little room (< JMP_SIZE bytes) before.
             loop {
-                let conflict = self.reserved_ranges.iter().find(|r| {
+                let conflict = ranges.iter().find(|r| {
                     let r_start = r.start as u32;
                     pc < r_start && pc + size > r_start.saturating_sub(JMP_SIZE)
                 }).copied();
@@ -899,12 +1494,12 @@ impl Assembler6502 {
 
                 pc = r_end + 1;
                 modified = true;
-                size = self.instruction_size(inst, pc as u16).unwrap_or(0) as u32;
+                size = self.sized_span(instructions, i, pc as u16).1;
             }
 
-            output.push(inst.clone());
+            output.extend_from_slice(&instructions[i..=last]);
             pc = pc.wrapping_add(size);
-            i += 1;
+            i = last + 1;
         }
 
         Ok((output, modified))
@@ -914,6 +1509,7 @@ impl Assembler6502 {
         // CRITICAL: Build symbol table FIRST so we know where all labels are
         self.symbols.clear();
         let mut current_address = self.start_address;
+        let mut blocks = LogicalBlocks::default();
 
         for inst in instructions.iter() {
             match inst {
@@ -921,17 +1517,20 @@ impl Assembler6502 {
                     self.symbols.insert(name.clone(), current_address);
                 }
                 Item::Constant(name, expr) => {
-                    let eval = ExpressionEvaluator::new(&self.symbols, current_address);
+                    let eval = self.evaluator(current_address);
                     if let Ok(value) = eval.evaluate_u16(expr) {
                         self.symbols.insert(name.clone(), value);
                     }
                 }
                 Item::Org(expr) => {
-                    let eval = ExpressionEvaluator::new(&self.symbols, current_address);
+                    let eval = self.evaluator(current_address);
                     if let Ok(addr) = eval.evaluate_u16(expr) {
                         current_address = addr;
                     }
                 }
+                Item::Logical(_) | Item::RealPc => {
+                    current_address = self.logical_step(inst, current_address, &mut blocks);
+                }
                 _ => {
                     if let Ok(size) = self.instruction_size(inst, current_address) {
                         current_address = current_address.wrapping_add(size as u16);
@@ -943,19 +1542,26 @@ impl Assembler6502 {
         // Now expand branches using the computed symbol table
         let mut fixed: Vec<Item> = Vec::new();
         current_address = self.start_address;
+        blocks = LogicalBlocks::default();
         let mut modified = false;
 
         for inst in instructions.iter() {
             // Handle ORG first
             if let Item::Org(expr) = inst {
                 fixed.push(inst.clone());
-                let eval = ExpressionEvaluator::new(&self.symbols, current_address);
+                let eval = self.evaluator(current_address);
                 if let Ok(addr) = eval.evaluate_u16(expr) {
                     current_address = addr;
                 }
                 continue;
             }
 
+            if let Item::Logical(_) | Item::RealPc = inst {
+                fixed.push(inst.clone());
+                current_address = self.logical_step(inst, current_address, &mut blocks);
+                continue;
+            }
+
             // Handle labels - they don't advance address
             if let Item::Label(_) = inst {
                 fixed.push(inst.clone());
@@ -969,77 +1575,76 @@ impl Assembler6502 {
             }
 
             // Check for branch expansion
-            if let Item::Instruction { mnemonic, operand } = inst {
-                if is_branch(mnemonic.as_str()) {
-                    if let Some(op) = operand {
-                        if let Some(target_addr) = self.symbols.get(op) {
-                            let (_, in_range) =
-                                self.calculate_branch_distance(current_address, target_addr);
-                            if !in_range {
-                                // Expand `BXX far_label` to:
-                                //   BYY skip      ; YY = inverted condition
-                                //   JMP far_label
-                                //   skip:
-                                // The branch must be INVERTED so that
-                                // when the original BXX would have been
-                                // taken (jump to far_label), control
-                                // falls through into the JMP.
-                                let inverted = invert_branch(mnemonic.as_str())
-                                    .expect("is_branch implies invertible");
-                                let skip_label = format!("__skip_{}", self.skip_label_counter);
-                                self.skip_label_counter += 1;
-
-                                // Record the pre-expansion branch
-                                // address; we use it below to shift
-                                // every label that sits AFTER the
-                                // branch by the +3 bytes the expansion
-                                // adds. Without this in-pass fix-up,
-                                // later branches in the same pass
-                                // consult a stale symbol table — the
-                                // entries built before the loop — and
-                                // miscompute their reach. The most
-                                // visible symptom: borderline branches
-                                // (true reach > 127, calculated reach
-                                // ≤ 127 because the target's address
-                                // is stale-too-low) get left
-                                // unexpanded this pass, only to be
-                                // picked up by the next outer-loop
-                                // iteration. With many such borderline
-                                // branches the outer guard runs out
-                                // before convergence. Aussie Cricket
-                                // (~237 branches) hits this exactly.
-                                let expansion_at = current_address;
-
-                                // BYY __skip (2 bytes at current_address)
-                                fixed.push(Item::Instruction {
-                                    mnemonic: inverted.to_string(),
-                                    operand: Some(skip_label.clone()),
-                                });
-                                current_address = current_address.wrapping_add(2);
-
-                                // JMP label (3 bytes)
-                                fixed.push(Item::Instruction {
-                                    mnemonic: "JMP".to_string(),
-                                    operand: Some(op.clone()),
-                                });
-                                current_address = current_address.wrapping_add(3);
-
-                                // __skip: label (0 bytes - just marks position)
-                                fixed.push(Item::Label(skip_label));
-
-                                // Slide every symbol that lives strictly
-                                // after the branch's original position
-                                // forward by 3 bytes (the net growth of
-                                // the expansion: 5 emitted bytes minus
-                                // the 2-byte branch it replaced). Labels
-                                // at or before `expansion_at` stay put.
-                                self.symbols.shift_above(expansion_at, 3);
-
-                                modified = true;
-                                continue;
-                            }
-                        }
-                    }
+            if let Item::Instruction { mnemonic, operand } = inst
+                && is_branch(mnemonic.as_str())
+                && let Some(op) = operand
+                && let Some(target_addr) = self.symbols.get(op)
+            {
+                let (_, in_range) =
+                    self.calculate_branch_distance(current_address, target_addr);
+                if !in_range {
+                    // Expand `BXX far_label` to:
+                    //   BYY skip      ; YY = inverted condition
+                    //   JMP far_label
+                    //   skip:
+                    // The branch must be INVERTED so that
+                    // when the original BXX would have been
+                    // taken (jump to far_label), control
+                    // falls through into the JMP.
+                    let inverted = invert_branch(mnemonic.as_str())
+                        .expect("is_branch implies invertible");
+                    let skip_label = format!("__skip_{}", self.skip_label_counter);
+                    self.skip_label_counter += 1;
+                    self.internal_labels.insert(skip_label.clone());
+
+                    // Record the pre-expansion branch
+                    // address; we use it below to shift
+                    // every label that sits AFTER the
+                    // branch by the +3 bytes the expansion
+                    // adds. Without this in-pass fix-up,
+                    // later branches in the same pass
+                    // consult a stale symbol table — the
+                    // entries built before the loop — and
+                    // miscompute their reach. The most
+                    // visible symptom: borderline branches
+                    // (true reach > 127, calculated reach
+                    // ≤ 127 because the target's address
+                    // is stale-too-low) get left
+                    // unexpanded this pass, only to be
+                    // picked up by the next outer-loop
+                    // iteration. With many such borderline
+                    // branches the outer guard runs out
+                    // before convergence. Aussie Cricket
+                    // (~237 branches) hits this exactly.
+                    let expansion_at = current_address;
+
+                    // BYY __skip (2 bytes at current_address)
+                    fixed.push(Item::Instruction {
+                        mnemonic: inverted.to_string(),
+                        operand: Some(skip_label.clone()),
+                    });
+                    current_address = current_address.wrapping_add(2);
+
+                    // JMP label (3 bytes)
+                    fixed.push(Item::Instruction {
+                        mnemonic: "JMP".to_string(),
+                        operand: Some(op.clone()),
+                    });
+                    current_address = current_address.wrapping_add(3);
+
+                    // __skip: label (0 bytes - just marks position)
+                    fixed.push(Item::Label(skip_label));
+
+                    // Slide every symbol that lives strictly
+                    // after the branch's original position
+                    // forward by 3 bytes (the net growth of
+                    // the expansion: 5 emitted bytes minus
+                    // the 2-byte branch it replaced). Labels
+                    // at or before `expansion_at` stay put.
+                    self.symbols.shift_above(expansion_at, 3);
+
+                    modified = true;
+                    continue;
                 }
             }
 
@@ -1058,156 +1663,271 @@ impl Assembler6502 {
         (offset as i16, (-128..=127).contains(&(offset as i16)))
     }
 
-    // ===== Listing (feature-gated) =====
+    // ===== Source export =====
 
-    #[cfg(feature = "listing")]
-    pub fn print_assembly_listing(&self, instructions: &[Item]) {
+    /// The program from [`Assembler6502::assemble_full`] as source for
+    /// another assembler, as it was laid out here: long branches expanded,
+    /// reserved ranges bridged and padded. Labels and constants keep their
+    /// names, scoped names `a::b` written `a__b`.
+    pub fn export_source(&self, instructions: &[Item], target: ExportTarget) -> Result<String, AsmError> {
+        self.write_source(instructions, target).map_err(AsmError::Asm)
+    }
+
+    fn write_source(&self, instructions: &[Item], target: ExportTarget) -> Result<String, String> {
+        let mut out = SourceWriter::new(target);
+        // Symbols defined so far; a later one can't size an operand yet
+        let mut defined = HashSet::new();
+        for symbol in self.symbols.sorted(SymbolOrder::Name, false) {
+            if symbol.kind == SymbolKind::Predefined {
+                out.constant(&export_name(&symbol.name), &Expr::Number(symbol.value as u32), false);
+                defined.insert(symbol.name.clone());
+            }
+        }
         let mut current_address = self.start_address;
-        println!("\nAssembly Listing:");
-        println!("Address:  Machine Code  Assembly");
-        println!("{}", "-".repeat(50));
+        let mut blocks = LogicalBlocks::default();
+        let mut loc = SourceLoc::default();
+        let mut placed = false;
+        let mut bss = false;
         for inst in instructions.iter() {
+            let markers = matches!(
+                inst,
+                Item::Line(_) | Item::Constant(..) | Item::Org(_) | Item::Assert { .. } | Item::Warning(_)
+            );
+            if !placed && !markers {
+                out.org(current_address);
+                placed = true;
+            }
             match inst {
+                Item::Line(l) => {
+                    loc = l.clone();
+                }
                 Item::Label(name) => {
-                    println!("${:04X}:          {}:", current_address, name);
+                    let key = self.symbols.key(name).into_owned();
+                    out.label(&export_name(&key));
+                    defined.insert(key);
                 }
                 Item::Constant(name, expr) => {
-                    let eval = ExpressionEvaluator::new(&self.symbols, current_address);
-                    if let Ok(value) = eval.evaluate_u16(expr) {
-                        println!("              {} = ${:04X}", name, value);
-                    }
+                    let key = self.symbols.key(name).into_owned();
+                    let variable = self.symbols.info(&key).is_some_and(|s| s.kind == SymbolKind::Variable);
+                    let value = self.export_expr(expr, current_address)
+                        .map_err(|e| format!("{}: Constant '{}': {}", loc, name, e))?;
+                    out.constant(&export_name(&key), &value, variable);
+                    defined.insert(key);
                 }
-                Item::Instruction { mnemonic, operand } => {
-                    if let Ok(size) = self.instruction_size(inst, current_address) {
-                        let code_bytes = self
-                            .assemble_instruction(mnemonic, operand.as_deref(), current_address)
-                            .unwrap_or_else(|_| vec![]);
-                        let hex_bytes = code_bytes
-                            .iter()
-                            .map(|b| format!("${:02X}", b))
-                            .collect::<Vec<_>>()
-                            .join(" ");
-                        let hex_padded = format!("{:<12}", hex_bytes);
-                        let op_str = operand.clone().unwrap_or_default();
-                        println!(
-                            "${:04X}: {} {} {}",
-                            current_address, hex_padded, mnemonic, op_str
-                        );
-                        current_address = current_address.wrapping_add(size as u16);
+                Item::Org(expr) => {
+                    let address = self.evaluator(current_address).evaluate_u16(expr)
+                        .map_err(|e| format!("{}: ORG directive: {}", loc, e))?;
+                    if placed {
+                        out.reorg(current_address, address);
+                    } else {
+                        out.org(address);
                     }
+                    current_address = address;
+                    placed = true;
                 }
-                Item::Org(expr) => {
-                    let eval = ExpressionEvaluator::new(&self.symbols, current_address);
-                    if let Ok(addr) = eval.evaluate_u16(expr) {
-                        println!("${:04X}:          *=${:04X}", current_address, addr);
-                        current_address = addr;
-                    }
+                Item::Segment(name) => {
+                    bss = self.layout.as_ref()
+                        .and_then(|config| config.segment(name))
+                        .is_some_and(|s| s.kind == SegmentKind::Bss);
+                    out.comment(&format!(".segment \"{}\"", name));
                 }
-                Item::Data(exprs) => {
-                    let eval = ExpressionEvaluator::new(&self.symbols, current_address);
-                    let bytes: Vec<u8> = exprs.iter()
-                        .filter_map(|e| eval.evaluate_u16(e).ok())
-                        .map(|v| (v & 0xFF) as u8)
-                        .collect();
-                    let hex_data = bytes
-                        .iter()
-                        .map(|b| format!("${:02X}", b))
-                        .collect::<Vec<_>>()
-                        .join(" ");
-                    let hex_padded = format!("{:<12}", hex_data.clone());
-                    println!(
-                        "${:04X}: {} .byte {}",
-                        current_address, hex_padded, hex_data
-                    );
-                    current_address = current_address.wrapping_add(bytes.len() as u16);
+                Item::Logical(expr) => {
+                    let logical = self.evaluator(current_address).evaluate_u16(expr)
+                        .map_err(|e| format!("{}: .pseudopc: {}", loc, e))?;
+                    out.logical(logical);
+                    current_address = blocks.enter(current_address, logical);
                 }
-                Item::Words(exprs) => {
-                    let eval = ExpressionEvaluator::new(&self.symbols, current_address);
-                    let words: Vec<u16> = exprs.iter()
-                        .filter_map(|e| eval.evaluate_u16(e).ok())
-                        .collect();
-                    let bytes: Vec<u8> = words.iter()
-                        .flat_map(|&w| vec![(w & 0xFF) as u8, (w >> 8) as u8])
-                        .collect();
-                    let hex_data = bytes
-                        .iter()
-                        .map(|b| format!("${:02X}", b))
-                        .collect::<Vec<_>>()
-                        .join(" ");
-                    let hex_padded = format!("{:<12}", hex_data);
-                    let word_data = words
-                        .iter()
-                        .map(|w| format!("${:04X}", w))
-                        .collect::<Vec<_>>()
-                        .join(",");
-                    println!(
-                        "${:04X}: {} .word {}",
-                        current_address, hex_padded, word_data
-                    );
-                    current_address = current_address.wrapping_add(bytes.len() as u16);
+                Item::RealPc => {
+                    current_address = blocks.leave(current_address)
+                        .ok_or_else(|| format!("{}: .realpc without .pseudopc", loc))?;
+                    out.real_pc(current_address);
                 }
-                Item::String(s) => {
-                    let bytes: Vec<u8> = s.bytes().collect();
-                    let hex_data = bytes
-                        .iter()
-                        .take(6)
-                        .map(|b| format!("${:02X}", b))
-                        .collect::<Vec<_>>()
-                        .join(" ");
-                    let mut hex_padded = format!("{:<12}", hex_data);
-                    if bytes.len() > 6 {
-                        hex_padded = format!("{}...", hex_padded);
+                Item::Instruction { mnemonic, operand } => {
+                    let size = self.export_instruction(&mut out, mnemonic, operand.as_deref(), current_address, &defined)
+                        .map_err(|e| format!("{}: ${:04X}: {} - {}", loc, current_address, mnemonic, e))?;
+                    current_address = current_address.wrapping_add(size as u16);
+                }
+                Item::Data(exprs) | Item::Text { bytes: exprs, .. } => {
+                    if let Item::Text { directive, text, .. } = inst {
+                        out.comment(&format!("{} {}", directive, text));
                     }
-                    println!(
-                        "${:04X}: {} .string \"{}\"",
-                        current_address, hex_padded, s
-                    );
+                    let bytes = exprs.iter()
+                        .map(|e| self.export_byte(e, current_address))
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|e| format!("{}: .byte directive at ${:04X}: {}", loc, current_address, e))?;
+                    out.bytes(&bytes);
                     current_address = current_address.wrapping_add(bytes.len() as u16);
                 }
-                Item::IncBin(filename) => {
-                    if let Ok(bytes) = fs::read(filename) {
-                        let hex_preview = bytes
-                            .iter()
-                            .take(6)
-                            .map(|b| format!("${:02X}", b))
-                            .collect::<Vec<_>>()
-                            .join(" ");
-                        let mut hex_padded = format!("{:<12}", hex_preview);
-                        if bytes.len() > 6 {
-                            hex_padded = format!("{}...", hex_padded);
-                        }
-                        println!(
-                            "${:04X}: {} .incbin \"{}\" ({} bytes)",
-                            current_address, hex_padded, filename, bytes.len()
-                        );
-                        current_address = current_address.wrapping_add(bytes.len() as u16);
+                Item::Words(exprs) | Item::Values { exprs, .. } => {
+                    let values = exprs.iter()
+                        .map(|e| self.export_expr(e, current_address))
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|e| format!("{}: data at ${:04X}: {}", loc, current_address, e))?;
+                    match inst {
+                        Item::Values { layout, .. } => out.values(*layout, &values),
+                        _ => out.words(&values),
                     }
+                    let size = self.instruction_size(inst, current_address)
+                        .map_err(|e| format!("{}: {}", loc, e))?;
+                    current_address = current_address.wrapping_add(size as u16);
                 }
-                Item::Pad(n) => {
-                    println!(
-                        "${:04X}: {:<12} <reserved fill, {} bytes of $00>",
-                        current_address, "", n
-                    );
-                    current_address = current_address.wrapping_add(*n as u16);
+                Item::IncBin { path, offset, length } => {
+                    let (bytes, range) = self
+                        .incbin_slice(path, offset.as_ref(), length.as_ref(), current_address)
+                        .map_err(|e| format!("{}: {} at ${:04X}", loc, e, current_address))?;
+                    current_address = current_address.wrapping_add(range.len() as u16);
+                    out.incbin(path, range, bytes.len());
+                }
+                Item::Pad(_) | Item::Align { .. } | Item::Fill { .. } | Item::Skip(_) => {
+                    let n = self.instruction_size(inst, current_address)
+                        .map_err(|e| format!("{}: {} at ${:04X}", loc, e, current_address))?;
+                    let fill = match inst {
+                        Item::Align { fill, .. } | Item::Fill { value: fill, .. } => fill.as_ref(),
+                        _ => None,
+                    };
+                    let byte = self.fill_byte(fill, current_address)
+                        .map_err(|e| format!("{}: {} at ${:04X}", loc, e, current_address))?;
+                    if n > 0 && (bss || matches!(inst, Item::Skip(_))) {
+                        out.skip(n);
+                    } else if n > 0 {
+                        out.fill(n, byte);
+                    }
+                    current_address = current_address.wrapping_add(n as u16);
+                }
+                Item::Assert { .. } | Item::Warning(_) | Item::Error(_) => {}
+                Item::Macro { .. } | Item::Rept { .. } | Item::For { .. } | Item::Include { .. }
+                | Item::Struct { .. } | Item::Enum { .. } | Item::StructData { .. } | Item::Bank { .. }
+                | Item::If(_) | Item::ElseIf(_) | Item::Else | Item::EndIf
+                | Item::String { .. } | Item::Encoding(_) | Item::CharMap(..) => {}
+            }
+        }
+        Ok(out.finish())
+    }
+
+    /// Write one instruction and return its size. The operand's address
+    /// size is spelled out where the target would pick the other one: a
+    /// zero-page value assembled as absolute, and zero-page addresses
+    /// from labels (which ca65 sizes by their segment) or from symbols
+    /// defined further down.
+    fn export_instruction(
+        &self,
+        out: &mut SourceWriter,
+        mnemonic: &str,
+        operand: Option<&str>,
+        current_address: u16,
+        defined: &HashSet<String>,
+    ) -> Result<usize, String> {
+        let size = self.assemble_instruction(mnemonic, operand, current_address)?.len();
+        let Some(operand) = operand.filter(|op| !is_accumulator(op)) else {
+            out.instruction(mnemonic, OperandForm::Implied, None, AddressSize::Auto);
+            return Ok(size);
+        };
+        let (form, text) = OperandForm::split(parse_addr_override(operand).0);
+        let parsed = ExpressionParser::parse(text)?;
+        let value = self.evaluator(current_address).evaluate(&parsed)?;
+        let zp_mode = match form {
+            OperandForm::Direct => Some("zeropage".to_string()),
+            OperandForm::Indexed(register) => Some(format!("zeropage,{}", register)),
+            _ => None,
+        };
+        let has_zp = !is_branch(mnemonic)
+            && zp_mode.is_some_and(|mode| {
+                self.opcodes.extended_opcodes.get(mnemonic).is_some_and(|modes| modes.contains_key(mode.as_str()))
+            });
+        let mut late_or_label = false;
+        parsed.visit(&mut |e| {
+            if let Expr::Label(name) = e {
+                let key = self.symbols.key(name);
+                let label = self.symbols.info(&key)
+                    .is_none_or(|s| matches!(s.kind, SymbolKind::Label | SymbolKind::Internal));
+                late_or_label |= label || !defined.contains(key.as_ref());
+            }
+        });
+        let address_size = match size {
+            3 if has_zp && value < 0x100 => AddressSize::Absolute,
+            2 if has_zp && late_or_label => AddressSize::ZeroPage,
+            _ => AddressSize::Auto,
+        };
+        let value = self.export_expr(&parsed, current_address)?;
+        out.instruction(mnemonic, form, Some(&value), address_size);
+        Ok(size)
+    }
+
+    /// `e` ready to export: bank numbers, characters, the PC, comparisons
+    /// and logic evaluated, and symbols under their exported names.
+    fn export_expr(&self, e: &Expr, current_address: u16) -> Result<Expr, String> {
+        let eval = self.evaluator(current_address);
+        let mut e = e.clone();
+        let mut failed = None;
+        e.visit_mut(&mut |node| {
+            if matches!(
+                node,
+                Expr::CurrentAddress | Expr::Char(_) | Expr::Bank(_)
+                | Expr::Eq(..) | Expr::Ne(..) | Expr::Lt(..) | Expr::Le(..) | Expr::Gt(..) | Expr::Ge(..)
+                | Expr::And(..) | Expr::Or(..) | Expr::Not(_)
+            ) {
+                match eval.evaluate(node) {
+                    Ok(value) => *node = Expr::Number(value),
+                    Err(err) => failed = Some(err),
                 }
             }
+        });
+        if let Some(err) = failed {
+            return Err(err);
         }
+        e.visit_mut(&mut |node| {
+            if let Expr::Label(name) = node {
+                *name = export_name(&self.symbols.key(name));
+            }
+        });
+        Ok(e)
+    }
+
+    /// A `.byte` value, masked to its low byte with `<` when larger, as
+    /// the targets reject such values where this assembler truncates.
+    fn export_byte(&self, e: &Expr, current_address: u16) -> Result<Expr, String> {
+        let value = self.evaluator(current_address).evaluate_u16(e)?;
+        let e = self.export_expr(e, current_address)?;
+        Ok(if value > 0xFF { Expr::LowByte(Box::new(e)) } else { e })
+    }
+
+    // ===== Listing (feature-gated) =====
+
+    #[cfg(feature = "listing")]
+    pub fn print_assembly_listing(&self, instructions: &[Item]) {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        let _ = writeln!(out);
+        let _ = self.write_listing(&mut out, instructions);
     }
 
     #[cfg(feature = "listing")]
     pub fn save_listing(&self, instructions: &[Item], filename: &str) -> io::Result<()> {
         let mut f = File::create(filename)?;
+        self.write_listing(&mut f, instructions)
+    }
+
+    #[cfg(feature = "listing")]
+    fn write_listing<W: Write>(&self, f: &mut W, instructions: &[Item]) -> io::Result<()> {
         writeln!(f, "Assembly Listing:")?;
         writeln!(f, "Address:  Machine Code  Assembly")?;
         writeln!(f, "{}", "-".repeat(50))?;
         let mut current_address = self.start_address;
+        let mut blocks = LogicalBlocks::default();
         for inst in instructions.iter() {
+            let addr = blocks.listing_address(current_address);
             match inst {
+                Item::Line(_) => {}
+                Item::Macro { .. } | Item::Rept { .. } | Item::For { .. } | Item::Include { .. }
+                | Item::Struct { .. } | Item::Enum { .. } | Item::StructData { .. } | Item::Bank { .. }
+                | Item::If(_) | Item::ElseIf(_) | Item::Else | Item::EndIf
+                | Item::String { .. } | Item::Encoding(_) | Item::CharMap(..)
+                | Item::Assert { .. } | Item::Error(_) | Item::Warning(_) => {}
                 Item::Label(name) => {
-                    writeln!(f, "${:04X}:          {}:", current_address, name)?;
+                    writeln!(f, "{}:          {}:", addr, name)?;
                 }
                 Item::Constant(name, expr) => {
-                    let eval = ExpressionEvaluator::new(&self.symbols, current_address);
+                    let eval = self.evaluator(current_address);
                     if let Ok(value) = eval.evaluate_u16(expr) {
                         writeln!(f, "              {} = ${:04X}", name, value)?;
                     }
@@ -1226,21 +1946,41 @@ impl Assembler6502 {
                         let op_str = operand.clone().unwrap_or_default();
                         writeln!(
                             f,
-                            "${:04X}: {} {} {}",
-                            current_address, hex_padded, mnemonic, op_str
+                            "{}: {} {} {}",
+                            addr, hex_padded, mnemonic, op_str
                         )?;
                         current_address = current_address.wrapping_add(size as u16);
                     }
                 }
                 Item::Org(expr) => {
-                    let eval = ExpressionEvaluator::new(&self.symbols, current_address);
+                    let eval = self.evaluator(current_address);
                     if let Ok(addr) = eval.evaluate_u16(expr) {
-                        writeln!(f, "${:04X}:          *=${:04X}", current_address, addr)?;
+                        writeln!(f, "{}:          *=${:04X}", addr, addr)?;
                         current_address = addr;
                     }
                 }
+                Item::Logical(expr) => {
+                    let eval = self.evaluator(current_address);
+                    if let Ok(logical) = eval.evaluate_u16(expr) {
+                        writeln!(f, "{}:          .pseudopc ${:04X}", addr, logical)?;
+                    }
+                    current_address = self.logical_step(inst, current_address, &mut blocks);
+                }
+                Item::RealPc => {
+                    writeln!(f, "{}:          .realpc", addr)?;
+                    current_address = self.logical_step(inst, current_address, &mut blocks);
+                }
+                Item::Segment(name) => {
+                    // A `.bank` is listed as one; its segment is generated
+                    match self.layout.as_ref().and_then(|c| c.segment_bank(name)) {
+                        Some(bank) if self.memory_config.is_none() => {
+                            writeln!(f, "${:04X}:          .bank {}", current_address, bank)?
+                        }
+                        _ => writeln!(f, "${:04X}:          .segment \"{}\"", current_address, name)?,
+                    }
+                }
                 Item::Data(exprs) => {
-                    let eval = ExpressionEvaluator::new(&self.symbols, current_address);
+                    let eval = self.evaluator(current_address);
                     let bytes: Vec<u8> = exprs.iter()
                         .filter_map(|e| eval.evaluate_u16(e).ok())
                         .map(|v| (v & 0xFF) as u8)
@@ -1251,11 +1991,11 @@ impl Assembler6502 {
                         .collect::<Vec<_>>()
                         .join(" ");
                     let hex_padded = format!("{:<12}", hex_data.clone());
-                    writeln!(f, "${:04X}: {} .byte {}", current_address, hex_padded, hex_data)?;
+                    writeln!(f, "{}: {} .byte {}", addr, hex_padded, hex_data)?;
                     current_address = current_address.wrapping_add(bytes.len() as u16);
                 }
                 Item::Words(exprs) => {
-                    let eval = ExpressionEvaluator::new(&self.symbols, current_address);
+                    let eval = self.evaluator(current_address);
                     let words: Vec<u16> = exprs.iter()
                         .filter_map(|e| eval.evaluate_u16(e).ok())
                         .collect();
@@ -1273,11 +2013,42 @@ impl Assembler6502 {
                         .map(|w| format!("${:04X}", w))
                         .collect::<Vec<_>>()
                         .join(",");
-                    writeln!(f, "${:04X}: {} .word {}", current_address, hex_padded, word_data)?;
+                    writeln!(f, "{}: {} .word {}", addr, hex_padded, word_data)?;
+                    current_address = current_address.wrapping_add(bytes.len() as u16);
+                }
+                Item::Values { layout, exprs } => {
+                    let eval = self.evaluator(current_address);
+                    let values: Vec<u32> = exprs.iter()
+                        .filter_map(|e| eval.evaluate(e).ok())
+                        .collect();
+                    let bytes: Vec<u8> = values.iter()
+                        .flat_map(|&v| layout.bytes(v))
+                        .collect();
+                    let hex_data = bytes
+                        .iter()
+                        .take(6)
+                        .map(|b| format!("${:02X}", b))
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    let mut hex_padded = format!("{:<12}", hex_data);
+                    if bytes.len() > 6 {
+                        hex_padded = format!("{}...", hex_padded);
+                    }
+                    // Two hex digits per stored byte, but whole addresses for the byte tables
+                    let width = layout.size().max(2) * 2;
+                    let value_data = values
+                        .iter()
+                        .map(|v| format!("${:0width$X}", v, width = width))
+                        .collect::<Vec<_>>()
+                        .join(",");
+                    writeln!(f, "{}: {} {} {}", addr, hex_padded, layout.directive(), value_data)?;
                     current_address = current_address.wrapping_add(bytes.len() as u16);
                 }
-                Item::String(s) => {
-                    let bytes: Vec<u8> = s.bytes().collect();
+                Item::Text { directive, text, bytes } => {
+                    let eval = self.evaluator(current_address);
+                    let bytes: Vec<u8> = bytes.iter()
+                        .map(|e| eval.evaluate_u16(e).map_or(0, |v| (v & 0xFF) as u8))
+                        .collect();
                     let hex_data = bytes
                         .iter()
                         .take(6)
@@ -1288,11 +2059,14 @@ impl Assembler6502 {
                     if bytes.len() > 6 {
                         hex_padded = format!("{}...", hex_padded);
                     }
-                    writeln!(f, "${:04X}: {} .string \"{}\"", current_address, hex_padded, s)?;
+                    writeln!(f, "{}: {} {} {}", addr, hex_padded, directive, text)?;
                     current_address = current_address.wrapping_add(bytes.len() as u16);
                 }
-                Item::IncBin(filename) => {
-                    if let Ok(bytes) = fs::read(filename) {
+                Item::IncBin { path, offset, length } => {
+                    if let Ok((bytes, range)) =
+                        self.incbin_slice(path, offset.as_ref(), length.as_ref(), current_address)
+                    {
+                        let bytes = &bytes[range.clone()];
                         let hex_preview = bytes
                             .iter()
                             .take(6)
@@ -1303,10 +2077,15 @@ impl Assembler6502 {
                         if bytes.len() > 6 {
                             hex_padded = format!("{}...", hex_padded);
                         }
+                        let slice = if offset.is_some() || length.is_some() {
+                            format!(", ${:X}, {}", range.start, range.len())
+                        } else {
+                            String::new()
+                        };
                         writeln!(
                             f,
-                            "${:04X}: {} .incbin \"{}\" ({} bytes)",
-                            current_address, hex_padded, filename, bytes.len()
+                            "${:04X}: {} .incbin \"{}\"{} ({} bytes)",
+                            current_address, hex_padded, path, slice, bytes.len()
                         )?;
                         current_address = current_address.wrapping_add(bytes.len() as u16);
                     }
@@ -1314,15 +2093,205 @@ impl Assembler6502 {
                 Item::Pad(n) => {
                     writeln!(
                         f,
-                        "${:04X}: {:<12} <reserved fill, {} bytes of $00>",
-                        current_address, "", n
+                        "{}: {:<12} <reserved fill, {} bytes of $00>",
+                        addr, "", n
                     )?;
                     current_address = current_address.wrapping_add(*n as u16);
                 }
+                Item::Align { boundary, fill } => {
+                    let eval = self.evaluator(current_address);
+                    if let Ok(n) = self.instruction_size(inst, current_address)
+                        && let Ok(to) = eval.evaluate(boundary)
+                    {
+                        let byte = self.fill_byte(fill.as_ref(), current_address).unwrap_or(0);
+                        writeln!(
+                            f,
+                            "{}: {:<12} .align ${:X} ({} of ${:02X})",
+                            addr, "", to, byte_count(n), byte
+                        )?;
+                        current_address = current_address.wrapping_add(n as u16);
+                    }
+                }
+                Item::Fill { value, .. } => {
+                    if let Ok(n) = self.instruction_size(inst, current_address) {
+                        let byte = self.fill_byte(value.as_ref(), current_address).unwrap_or(0);
+                        writeln!(
+                            f,
+                            "{}: {:<12} .fill {} of ${:02X}",
+                            addr, "", byte_count(n), byte
+                        )?;
+                        current_address = current_address.wrapping_add(n as u16);
+                    }
+                }
+                Item::Skip(_) => {
+                    if let Ok(n) = self.instruction_size(inst, current_address) {
+                        writeln!(f, "{}: {:<12} .skip {}", addr, "", byte_count(n))?;
+                        current_address = current_address.wrapping_add(n as u16);
+                    }
+                }
+            }
+        }
+        self.write_warnings(f)?;
+        self.write_unused_symbols(f)?;
+        if self.listing_xref {
+            self.write_cross_reference(f)?;
+        }
+        Ok(())
+    }
+
+    /// Append a cross-reference table (who reads, writes, jumps to or
+    /// branches to each symbol) to printed and saved listings.
+    #[cfg(feature = "listing")]
+    pub fn set_listing_cross_reference(&mut self, enabled: bool) {
+        self.listing_xref = enabled;
+    }
+
+    #[cfg(feature = "listing")]
+    fn write_cross_reference<W: Write>(&self, f: &mut W) -> io::Result<()> {
+        writeln!(f)?;
+        writeln!(f, "Cross Reference:")?;
+        writeln!(f, "{}", "-".repeat(50))?;
+        for sym in self.sorted_symbols(SymbolOrder::Name) {
+            writeln!(f, "{} = ${:04X}  ({})", sym.name, sym.value, sym.loc)?;
+            for r in self.references_to(&sym.name) {
+                let access = match r.access {
+                    AccessKind::Read => "read",
+                    AccessKind::Write => "write",
+                    AccessKind::Jump => "jump",
+                    AccessKind::Branch => "branch",
+                    AccessKind::Data => "data",
+                };
+                writeln!(f, "    ${:04X}  {:<6}  {:<24}  {}", r.address, access, r.statement, r.loc)?;
             }
         }
         Ok(())
     }
+
+    #[cfg(feature = "listing")]
+    fn write_warnings<W: Write>(&self, f: &mut W) -> io::Result<()> {
+        if self.warnings.is_empty() {
+            return Ok(());
+        }
+        writeln!(f)?;
+        writeln!(f, "Warnings:")?;
+        writeln!(f, "{}", "-".repeat(50))?;
+        for warning in &self.warnings {
+            writeln!(f, "{}", warning)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "listing")]
+    fn write_unused_symbols<W: Write>(&self, f: &mut W) -> io::Result<()> {
+        let unused = self.unused_symbols();
+        if unused.is_empty() {
+            return Ok(());
+        }
+        writeln!(f)?;
+        writeln!(f, "Unused Symbols:")?;
+        writeln!(f, "{}", "-".repeat(50))?;
+        for sym in unused {
+            let kind = match sym.kind {
+                SymbolKind::Label => "label",
+                SymbolKind::Constant => "constant",
+                SymbolKind::Variable => "variable",
+                SymbolKind::Internal => "internal",
+                SymbolKind::Predefined => "predef",
+            };
+            writeln!(f, "${:04X}  {:<8}  {:<24}  {}", sym.value, kind, sym.name, sym.loc)?;
+        }
+        Ok(())
+    }
+}
+
+/// `n` with "byte" or "bytes", for messages.
+fn byte_count(n: usize) -> String {
+    if n == 1 { "1 byte".to_string() } else { format!("{} bytes", n) }
+}
+
+/// Report the first two writes, in emission order `(bank, start, end,
+/// loc)`, that put bytes at the same address of the same bank.
+fn check_overlaps(writes: &[(Option<u32>, u32, u32, SourceLoc)]) -> Result<(), String> {
+    let mut order: Vec<usize> = (0..writes.len()).collect();
+    order.sort_by_key(|&i| (writes[i].0, writes[i].1));
+    let mut furthest: Option<usize> = None;
+    for i in order {
+        if let Some(f) = furthest
+            && writes[i].0 == writes[f].0
+            && writes[i].1 < writes[f].2
+        {
+            let (earlier, later) = if f < i { (f, i) } else { (i, f) };
+            let from = writes[i].1;
+            let to = writes[i].2.min(writes[f].2) - 1;
+            let bank = writes[i].0.map(|b| format!(" in bank {}", b)).unwrap_or_default();
+            return Err(format!(
+                "{}: output at ${:04X}-${:04X}{} overlaps bytes already written by {}",
+                writes[later].3, from, to, bank, writes[earlier].3
+            ));
+        }
+        if furthest.is_none_or(|f| writes[i].0 != writes[f].0 || writes[i].2 > writes[f].2) {
+            furthest = Some(i);
+        }
+    }
+    Ok(())
+}
+
+/// The `.pseudopc` blocks open at a point of a PC walk, innermost last:
+/// the PC each started at and the logical address it assembles for.
+#[derive(Default)]
+struct LogicalBlocks(Vec<(u16, u16)>);
+
+impl LogicalBlocks {
+    /// Open a block assembled for `logical`; the PC continues there.
+    fn enter(&mut self, pc: u16, logical: u16) -> u16 {
+        self.0.push((pc, logical));
+        logical
+    }
+
+    /// Close the innermost block; the PC continues after its bytes.
+    fn leave(&mut self, pc: u16) -> Option<u16> {
+        let (outer, logical) = self.0.pop()?;
+        Some(outer.wrapping_add(pc.wrapping_sub(logical)))
+    }
+
+    /// Where logical address `pc` lands in the output.
+    fn physical(&self, pc: u16) -> u16 {
+        self.0.iter().rev().fold(pc, |pc, &(outer, logical)| outer.wrapping_add(pc.wrapping_sub(logical)))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The address column of a listing line: inside a block, the logical
+    /// address followed by the physical one.
+    #[cfg(feature = "listing")]
+    fn listing_address(&self, pc: u16) -> String {
+        if self.is_empty() {
+            format!("${:04X}", pc)
+        } else {
+            format!("${:04X} (${:04X})", pc, self.physical(pc))
+        }
+    }
+}
+
+/// The memory area the emit pass is writing and where its bytes begin
+/// in the output.
+struct AreaOutput<'a> {
+    area: &'a MemoryArea,
+    base: usize,
+}
+
+impl AreaOutput<'_> {
+    /// Pad a filled area to its full size.
+    fn close(self, machine: &mut Vec<u8>, byte_addrs: &mut Vec<u16>) {
+        if let Some(fill) = self.area.fill {
+            let end = self.base + self.area.size as usize;
+            let written = machine.len() - self.base;
+            byte_addrs.extend((written..self.area.size as usize).map(|i| self.area.start.wrapping_add(i as u16)));
+            machine.resize(end, fill);
+        }
+    }
 }
 
 struct BridgeAt {
@@ -1565,8 +2534,8 @@ mod reserved_tests {
 
         // Bytes from offset 0xFF (dead-fill) through 0x1FF (the
         // reservation $0900-$09FF) must all be $00.
-        for off in 0xFF..=0x1FF {
-            assert_eq!(bytes[off], 0x00, "offset {:04X} should be $00", off);
+        for (off, &b) in bytes.iter().enumerate().take(0x200).skip(0xFF) {
+            assert_eq!(b, 0x00, "offset {:04X} should be $00", off);
         }
         // Code resumes at offset 0x200 (== $0A00)
         assert_eq!(bytes[0x200], 0xA9); // LDA opcode
@@ -1582,9 +2551,9 @@ mod reserved_tests {
         for _ in 0..253 { src.push_str("NOP\n"); }
         src.push_str("LDA #$11\n");
         let bytes = a.assemble_bytes(&src).unwrap();
-        for off in 0..253 { assert_eq!(bytes[off], 0xEA); }
+        assert!(bytes[0..253].iter().all(|&b| b == 0xEA));
         assert_eq!(&bytes[253..256], &[0x4C, 0x00, 0x0A]);
-        for off in 256..512 { assert_eq!(bytes[off], 0x00); }
+        assert!(bytes[256..512].iter().all(|&b| b == 0x00));
         assert_eq!(&bytes[512..514], &[0xA9, 0x11]);
     }
 
@@ -1599,7 +2568,7 @@ mod reserved_tests {
 
         let pos = bytes.windows(10).position(|w| w == b"ABCDEFGHIJ").unwrap();
         assert!(pos >= 0x200, "string at offset {:04X}, must be >= $0200", pos);
-        for off in 0x100..=0x1FF { assert_eq!(bytes[off], 0x00); }
+        assert!(bytes[0x100..=0x1FF].iter().all(|&b| b == 0x00));
     }
 
     #[test]
@@ -1613,14 +2582,14 @@ mod reserved_tests {
         // and the reservation $0900-$09FF must all be $00.
         let jmp1 = bytes.windows(3).position(|w| w == [0x4C, 0x00, 0x0A]).unwrap();
         assert_eq!(jmp1, 0xFC);
-        for off in 0xFF..=0x1FF { assert_eq!(bytes[off], 0x00); }
+        assert!(bytes[0xFF..=0x1FF].iter().all(|&b| b == 0x00));
 
         // Second bridge: 126 more LDAs fill $0A00-$0AFB, JMP $0B50
         // at $0AFC (offset 0x2FC). Dead-fill $0AFF and reservation
         // $0B00-$0B4F must all be $00.
         let jmp2 = bytes.windows(3).position(|w| w == [0x4C, 0x50, 0x0B]).unwrap();
         assert_eq!(jmp2, 0x2FC);
-        for off in 0x2FF..=0x34F { assert_eq!(bytes[off], 0x00); }
+        assert!(bytes[0x2FF..=0x34F].iter().all(|&b| b == 0x00));
     }
 
     #[test]
@@ -1642,7 +2611,7 @@ mod reserved_tests {
         let bytes = a.assemble_bytes(&src).unwrap();
 
         assert!(bytes.windows(3).any(|w| w == [0x4C, 0x00, 0x0A]));
-        for off in 0x100..=0x1FF { assert_eq!(bytes[off], 0x00); }
+        assert!(bytes[0x100..=0x1FF].iter().all(|&b| b == 0x00));
     }
 
     #[test]
@@ -1701,3 +2670,2274 @@ mod reserved_tests {
         assert!(result.is_ok(), "two reservations + long branches: {result:?}");
     }
 }
+
+#[cfg(test)]
+mod symbol_tests {
+    use super::*;
+
+    #[test]
+    fn records_kind_value_and_definition_line() {
+        let mut a = Assembler6502::new();
+        a.assemble_bytes("*=$0800\nSCREEN = $0400\nstart:\n  LDA #$00\n  STA SCREEN\n").unwrap();
+
+        let screen = a.symbol_info("SCREEN").unwrap();
+        assert_eq!(screen.kind, SymbolKind::Constant);
+        assert_eq!(screen.value, 0x0400);
+        assert_eq!(screen.loc.line, 2);
+        assert_eq!(screen.loc.file, None);
+        assert_eq!(screen.scope, None);
+
+        let start = a.symbol_info("start").unwrap();
+        assert_eq!(start.kind, SymbolKind::Label);
+        assert_eq!(start.value, 0x0800);
+        assert_eq!(start.loc.line, 3);
+    }
+
+    #[test]
+    fn reassigned_constant_becomes_variable() {
+        let mut a = Assembler6502::new();
+        a.assemble_bytes("*=$0800\nN = 1\n.byte N\nN = 2\n.byte N\n").unwrap();
+        let n = a.symbol_info("N").unwrap();
+        assert_eq!(n.kind, SymbolKind::Variable);
+        assert_eq!(n.value, 2);
+        assert_eq!(n.loc.line, 4);
+    }
+
+    #[test]
+    fn sorted_views_hide_internal_labels_by_default() {
+        let mut src = String::from("*=$0800\nstart:\nBEQ far\n");
+        for _ in 0..200 { src.push_str("NOP\n"); }
+        src.push_str("far:\nRTS\n");
+        let mut a = Assembler6502::new();
+        a.assemble_bytes(&src).unwrap();
+
+        let names: Vec<&str> = a.sorted_symbols(SymbolOrder::Address).map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["start", "far"]);
+
+        let internal: Vec<&Symbol> = a
+            .sorted_symbols_with_internal(SymbolOrder::Name)
+            .filter(|s| s.kind == SymbolKind::Internal)
+            .collect();
+        assert_eq!(internal.len(), 1);
+        assert_eq!(internal[0].name, "__skip_0");
+        // The raw map still carries everything for backwards compatibility.
+        assert!(a.symbols().contains_key("__skip_0"));
+    }
+
+    #[test]
+    fn sorted_by_name_and_address() {
+        let mut a = Assembler6502::new();
+        a.assemble_bytes("*=$0800\nzeta:\nNOP\nalpha:\nNOP\nMID = $0801\n").unwrap();
+        let by_name: Vec<&str> = a.sorted_symbols(SymbolOrder::Name).map(|s| s.name.as_str()).collect();
+        assert_eq!(by_name, vec!["MID", "alpha", "zeta"]);
+        let by_addr: Vec<&str> = a.sorted_symbols(SymbolOrder::Address).map(|s| s.name.as_str()).collect();
+        assert_eq!(by_addr, vec!["zeta", "MID", "alpha"]);
+    }
+
+    #[test]
+    fn unused_symbols_report_dead_labels_and_constants() {
+        let src = "\
+*=$0800
+BASE = $0400
+SCREEN = BASE+$10
+COLOR = $D800
+start:
+  LDA #$01
+  STA SCREEN
+loop:
+  BNE loop
+dead:
+  RTS
+table:
+  .word start
+";
+        let mut a = Assembler6502::new();
+        a.assemble_bytes(src).unwrap();
+        let unused: Vec<(&str, SymbolKind, usize)> = a
+            .unused_symbols()
+            .iter()
+            .map(|s| (s.name.as_str(), s.kind, s.loc.line))
+            .collect();
+        // BASE only feeds SCREEN, start is used by .word, loop by the branch.
+        assert_eq!(unused, vec![
+            ("COLOR", SymbolKind::Constant, 4),
+            ("dead", SymbolKind::Label, 10),
+            ("table", SymbolKind::Label, 12),
+        ]);
+    }
+
+    #[test]
+    fn unused_symbols_skip_internal_labels() {
+        let mut src = String::from("*=$0800\nBEQ far\n");
+        for _ in 0..200 { src.push_str("NOP\n"); }
+        src.push_str("far:\nRTS\n");
+        let mut a = Assembler6502::new();
+        a.assemble_bytes(&src).unwrap();
+        assert!(a.unused_symbols().is_empty());
+    }
+
+    #[test]
+    fn cross_reference_records_access_kinds() {
+        let src = "\
+*=$0800
+SCREEN = $0400
+start:
+  LDA SCREEN
+  STA SCREEN
+  INC SCREEN
+  LDA #<SCREEN
+  BNE start
+  JSR start
+  .word SCREEN
+";
+        let mut a = Assembler6502::new();
+        a.assemble_bytes(src).unwrap();
+        let refs: Vec<(u16, AccessKind, &str, usize)> = a
+            .references_to("SCREEN")
+            .map(|r| (r.address, r.access, r.statement.as_str(), r.loc.line))
+            .collect();
+        assert_eq!(refs, vec![
+            (0x0800, AccessKind::Read, "LDA SCREEN", 4),
+            (0x0803, AccessKind::Write, "STA SCREEN", 5),
+            (0x0806, AccessKind::Write, "INC SCREEN", 6),
+            (0x0809, AccessKind::Data, "LDA #<SCREEN", 7),
+            (0x0810, AccessKind::Data, ".word", 10),
+        ]);
+        let start: Vec<AccessKind> = a.references_to("start").map(|r| r.access).collect();
+        assert_eq!(start, vec![AccessKind::Branch, AccessKind::Jump]);
+    }
+
+    #[test]
+    fn cross_reference_is_rebuilt_per_assembly() {
+        let mut a = Assembler6502::new();
+        a.assemble_bytes("*=$0800\nX1 = 1\nLDA X1\nLDA X1\n").unwrap();
+        assert_eq!(a.references().len(), 2);
+        a.assemble_bytes("*=$0800\nNOP\n").unwrap();
+        assert!(a.references().is_empty());
+    }
+}
+
+#[cfg(test)]
+mod case_tests {
+    use super::*;
+
+    #[test]
+    fn mnemonics_registers_and_directives_ignore_case() {
+        let upper = "\
+*=$0800
+PTR = $FB
+  LDA (PTR),Y
+  STA (PTR,X)
+  LDA $10,X
+  LDX $2000,Y
+  JMP (PTR)
+  .BYTE $01,$02
+  .Word $1234
+  DCB $03
+";
+        let lower = "\
+*=$0800
+PTR = $FB
+  lda (PTR), y
+  sta (PTR,x)
+  lda $10,x
+  ldx $2000,y
+  jmp (PTR)
+  .byte $01,$02
+  .word $1234
+  dcb $03
+";
+        let mut a = Assembler6502::new();
+        let expected = a.assemble_bytes(upper).unwrap();
+        assert_eq!(a.assemble_bytes(lower).unwrap(), expected);
+        assert_eq!(&expected[..4], &[0xB1, 0xFB, 0x81, 0xFB]);
+    }
+
+    #[test]
+    fn directive_names_need_a_word_boundary() {
+        let mut a = Assembler6502::new();
+        assert!(a.assemble_bytes("*=$0800\n.bytes $01\n").is_err());
+    }
+
+    #[test]
+    fn symbols_are_case_sensitive_by_default() {
+        let mut a = Assembler6502::new();
+        assert!(a.assemble_bytes("*=$0800\nLoop:\n  JMP LOOP\n").is_err());
+    }
+
+    #[test]
+    fn case_insensitive_symbols_fold_to_uppercase() {
+        let mut a = Assembler6502::new();
+        a.set_case_sensitive_symbols(false);
+        let bytes = a.assemble_bytes("*=$0800\nScreen = $0400\nLoop:\n  sta SCREEN\n  bne loop\n").unwrap();
+        assert_eq!(bytes, vec![0x8D, 0x00, 0x04, 0xD0, 0xFB]);
+        assert_eq!(a.lookup("loop"), Some(0x0800));
+        assert_eq!(a.symbol_info("screen").unwrap().name, "SCREEN");
+        assert_eq!(a.references_to("Screen").count(), 1);
+        assert!(a.unused_symbols().is_empty());
+    }
+
+    #[test]
+    fn macro_parameters_and_loop_variables_fold_too() {
+        let src = "*=$0800
+.macro Poke Addr, Value=0
+  LDA #\\VALUE
+  STA ADDR
+Wait:
+  BNE wait
+.endmacro
+  poke addr=$D020
+.for I = 1, i < 3, I + 1
+  .byte i
+.endfor
+";
+        let mut a = Assembler6502::new();
+        assert!(a.assemble_bytes(src).is_err());
+        a.set_case_sensitive_symbols(false);
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![0xA9, 0x00, 0x8D, 0x20, 0xD0, 0xD0, 0xFE, 1, 2]);
+    }
+}
+
+#[cfg(test)]
+mod accumulator_tests {
+    use super::*;
+
+    #[test]
+    fn explicit_and_implied_accumulator_forms_match() {
+        let mut a = Assembler6502::new();
+        let explicit = a.assemble_bytes("*=$0800\nASL A\nLSR a\nROL A\nROR A\n").unwrap();
+        let implied = a.assemble_bytes("*=$0800\nASL\nLSR\nROL\nROR\n").unwrap();
+        assert_eq!(explicit, vec![0x0A, 0x4A, 0x2A, 0x6A]);
+        assert_eq!(explicit, implied);
+    }
+
+    #[test]
+    fn accumulator_operand_rejected_for_other_instructions() {
+        let mut a = Assembler6502::new();
+        let err = a.assemble_bytes("*=$0800\nLDA A\n").unwrap_err().to_string();
+        assert!(err.contains("Accumulator addressing not supported for LDA"), "{}", err);
+    }
+
+    #[test]
+    fn label_named_a_is_rejected() {
+        let mut a = Assembler6502::new();
+        assert!(a.assemble_bytes("*=$0800\nA:\nNOP\n").is_err());
+        assert!(a.assemble_bytes("*=$0800\na = 5\n").is_err());
+        assert!(a.assemble_bytes("*=$0800\nA: DCB $01\n").is_err());
+        // Longer names starting with A are fine.
+        assert!(a.assemble_bytes("*=$0800\nAB:\nASL AB\n").is_ok());
+    }
+}
+
+#[cfg(test)]
+mod macro_tests {
+    use super::*;
+    use crate::expand::MAX_MACRO_DEPTH;
+
+    #[test]
+    fn named_positional_and_default_parameters() {
+        let src = "*=$0800
+.macro poke addr, value=0
+  LDA #value
+  STA addr
+.endmacro
+  poke $D020, 1
+  poke value=2, addr=$D021
+  poke $0400
+";
+        let mut a = Assembler6502::new();
+        let bytes = a.assemble_bytes(src).unwrap();
+        assert_eq!(
+            bytes,
+            vec![0xA9, 0x01, 0x8D, 0x20, 0xD0, 0xA9, 0x02, 0x8D, 0x21, 0xD0, 0xA9, 0x00, 0x8D, 0x00, 0x04]
+        );
+    }
+
+    #[test]
+    fn argument_count_and_backslash_forms() {
+        let src = "*=$0800
+.macro bytes a1, a2=9
+  .byte \\#, \\1, \\a2
+.endm
+  bytes 5
+  bytes 5, 6
+";
+        let mut a = Assembler6502::new();
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![1, 5, 9, 2, 5, 6]);
+    }
+
+    #[test]
+    fn labels_are_local_to_each_expansion() {
+        let src = "*=$0800
+.macro wait n
+  LDX #n
+loop:
+  DEX
+  BNE loop
+.endmacro
+  wait 1
+  wait 2
+";
+        let mut a = Assembler6502::new();
+        let bytes = a.assemble_bytes(src).unwrap();
+        assert_eq!(bytes, vec![0xA2, 0x01, 0xCA, 0xD0, 0xFD, 0xA2, 0x02, 0xCA, 0xD0, 0xFD]);
+        assert_eq!(a.lookup("wait__0::loop"), Some(0x0802));
+        assert_eq!(a.lookup("wait__1::loop"), Some(0x0807));
+        assert_eq!(a.symbol_info("wait__1::loop").unwrap().scope.as_deref(), Some("wait__1"));
+    }
+
+    #[test]
+    fn macros_can_invoke_macros() {
+        let src = "*=$0800
+.macro one v
+  .byte v
+.endmacro
+.macro two v
+  one v
+  one v+1
+.endmacro
+  two 7
+";
+        let mut a = Assembler6502::new();
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![7, 8]);
+    }
+
+    #[test]
+    fn runaway_recursion_is_bounded() {
+        let src = "*=$0800\n.macro forever\n  forever\n.endmacro\n  forever\n";
+        let mut a = Assembler6502::new();
+        let err = a.assemble_bytes(src).unwrap_err().to_string();
+        let expected = format!("Line 5: expansion of 'forever' nested deeper than {} levels", MAX_MACRO_DEPTH);
+        assert!(err.contains(&expected), "{}", err);
+        assert!(!err.contains("expanded from"), "{}", err);
+    }
+
+    #[test]
+    fn errors_name_macro_line_and_invocation() {
+        let src = "*=$0800\n.macro bad\n  LDA ($10),Z\n.endmacro\n  NOP\n  bad\n";
+        let mut a = Assembler6502::new();
+        let err = a.assemble_bytes(src).unwrap_err().to_string();
+        assert!(err.contains("Line 3 (expanded from bad at Line 6)"), "{}", err);
+    }
+
+    #[test]
+    fn argument_errors() {
+        let mut a = Assembler6502::new();
+        let def = "*=$0800\n.macro m v\n  .byte v\n.endmacro\n";
+        let err = a.assemble_bytes(&format!("{}  m\n", def)).unwrap_err().to_string();
+        assert!(err.contains("missing argument 'v'"), "{}", err);
+        let err = a.assemble_bytes(&format!("{}  m 1, 2\n", def)).unwrap_err().to_string();
+        assert!(err.contains("takes 1 argument(s), got 2"), "{}", err);
+        let err = a.assemble_bytes("*=$0800\n.macro m\n  NOP\n").unwrap_err().to_string();
+        assert!(err.contains("no matching .endmacro"), "{}", err);
+        let err = a.assemble_bytes("*=$0800\n.endmacro\n").unwrap_err().to_string();
+        assert!(err.contains(".endmacro without .macro"), "{}", err);
+        let err = a.assemble_bytes("*=$0800\n.macro lda\n.endmacro\n").unwrap_err().to_string();
+        assert!(err.contains("clashes with an instruction"), "{}", err);
+    }
+
+    #[test]
+    fn registers_and_mnemonics_are_not_parameters() {
+        // A parameter replaces every matching word, so `a` would turn
+        // `ASL a` into `ASL 5` and `x` would break `LDA src,x`
+        let mut a = Assembler6502::new();
+        let err = a.assemble_bytes(".macro m a
+  ASL a
+.endmacro
+").unwrap_err().to_string();
+        assert!(err.contains("'a' is reserved for the accumulator and can't be used as a macro parameter"), "{}", err);
+        let err = a.assemble_bytes(".macro copy src, x=0
+  LDA src,x
+.endmacro
+").unwrap_err().to_string();
+        assert!(err.contains("'x' is an index register"), "{}", err);
+        let err = a.assemble_bytes(".macro m Y
+.endmacro
+").unwrap_err().to_string();
+        assert!(err.contains("'Y' is an index register"), "{}", err);
+        let err = a.assemble_bytes(".macro m nop
+  nop
+.endmacro
+").unwrap_err().to_string();
+        assert!(err.contains("'nop' is an instruction and can't be used as a macro parameter"), "{}", err);
+    }
+
+    #[test]
+    fn braces_pass_commas_through() {
+        let src = "*=$0800\n.macro load op\n  LDA op\n.endmacro\n  load {($10),Y}\n";
+        let mut a = Assembler6502::new();
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![0xB1, 0x10]);
+    }
+}
+
+#[cfg(test)]
+mod conditional_tests {
+    use super::*;
+
+    const VARIANTS: &str = "*=$0800
+.ifdef DEBUG
+  .byte $DB
+.endif
+.if PAL == 1
+  .byte 50
+.elseif PAL == 2
+  .byte 55
+.else
+  .byte 60
+.endif
+";
+
+    #[test]
+    fn predefined_symbols_select_branches() {
+        let mut a = Assembler6502::new();
+        a.define_symbol("PAL", 1);
+        assert_eq!(a.assemble_bytes(VARIANTS).unwrap(), vec![50]);
+        a.define_symbol("PAL", 0);
+        a.define_symbol("DEBUG", 1);
+        assert_eq!(a.assemble_bytes(VARIANTS).unwrap(), vec![0xDB, 60]);
+        a.clear_defined_symbols();
+        a.define_symbol("PAL", 2);
+        assert_eq!(a.assemble_bytes(VARIANTS).unwrap(), vec![55]);
+        assert_eq!(a.symbol_info("PAL").unwrap().kind, SymbolKind::Predefined);
+        assert!(a.unused_symbols().is_empty());
+    }
+
+    #[test]
+    fn constants_above_the_condition_are_visible() {
+        let src = "*=$0800
+SIZE = 4
+WIDE = SIZE > 2
+.if WIDE && !(SIZE == 8)
+  .byte 1
+  .if SIZE < 3
+    .byte 2
+  .else
+    .byte 3
+  .endif
+.else
+  .byte 4
+.endif
+.ifndef MISSING
+  .byte 5
+.endif
+";
+        let mut a = Assembler6502::new();
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![1, 3, 5]);
+    }
+
+    #[test]
+    fn inactive_blocks_define_nothing() {
+        let src = "*=$0800
+.if 0
+skipped:
+  NOP
+.macro m
+.endmacro
+.endif
+.ifdef skipped
+  .byte 1
+.endif
+";
+        let mut a = Assembler6502::new();
+        assert_eq!(a.assemble_bytes(src).unwrap(), Vec::<u8>::new());
+        assert_eq!(a.lookup("skipped"), None);
+    }
+
+    #[test]
+    fn ifdef_sees_labels_defined_above() {
+        let mut a = Assembler6502::new();
+        let bytes = a.assemble_bytes("*=$0800\nstart:\n.ifdef start\n  .byte 1\n.endif\n").unwrap();
+        assert_eq!(bytes, vec![1]);
+    }
+
+    #[test]
+    fn address_dependent_conditions_are_rejected() {
+        let mut a = Assembler6502::new();
+        for src in [
+            "*=$0800\nstart:\n.if start == $0800\n.endif\n",
+            "*=$0800\nHERE = *\n.if HERE\n.endif\n",
+            "*=$0800\n.if * > $0800\n.endif\n",
+        ] {
+            let err = a.assemble_bytes(src).unwrap_err().to_string();
+            assert!(err.contains("isn't known until layout"), "{}", err);
+        }
+        let err = a.assemble_bytes("*=$0800\n.if LATER\n.endif\nLATER = 1\n").unwrap_err().to_string();
+        assert!(err.contains("Undefined label: LATER"), "{}", err);
+    }
+
+    #[test]
+    fn conditionals_inside_macros() {
+        let src = "*=$0800
+.macro put v
+  .if v > 9
+    .byte $FF
+  .else
+    .byte v
+  .endif
+.endmacro
+  put 3
+  put 12
+";
+        let mut a = Assembler6502::new();
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![3, 0xFF]);
+    }
+
+    #[test]
+    fn unbalanced_blocks_are_errors() {
+        let mut a = Assembler6502::new();
+        let cases = [
+            ("*=$0800\n.if 1\n", ".if without matching .endif"),
+            ("*=$0800\n.endif\n", ".endif without .if"),
+            ("*=$0800\n.else\n", ".else without .if"),
+            ("*=$0800\n.if 1\n.else\n.else\n.endif\n", "duplicate .else"),
+            ("*=$0800\n.if 1\n.else\n.elseif 1\n.endif\n", ".elseif after .else"),
+        ];
+        for (src, msg) in cases {
+            let err = a.assemble_bytes(src).unwrap_err().to_string();
+            assert!(err.contains(msg), "{}: {}", msg, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod loop_tests {
+    use super::*;
+
+    #[test]
+    fn rept_repeats_the_body() {
+        let mut a = Assembler6502::new();
+        let bytes = a.assemble_bytes("*=$0800\nN = 3\n.rept N\n  NOP\n.endrept\n  RTS\n").unwrap();
+        assert_eq!(bytes, vec![0xEA, 0xEA, 0xEA, 0x60]);
+        assert_eq!(a.assemble_bytes("*=$0800\n.rept 0\n  NOP\n.endr\n").unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn for_variable_is_usable_in_expressions() {
+        let src = "*=$0800
+table:
+.for i = 0, i < 4, i+1
+  .byte i*i, <(table+i)
+.endfor
+";
+        let mut a = Assembler6502::new();
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![0, 0x00, 1, 0x01, 4, 0x02, 9, 0x03]);
+        // The variable only exists inside the loop
+        assert_eq!(a.lookup("i"), None);
+    }
+
+    #[test]
+    fn for_accepts_assignment_step_and_nesting() {
+        let src = "*=$0800
+.for row = 0, row < 2, row = row + 1
+  .for col = 0, col < 3, col+1
+    .byte row*16 + col
+  .endfor
+.endfor
+";
+        let mut a = Assembler6502::new();
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![0x00, 0x01, 0x02, 0x10, 0x11, 0x12]);
+    }
+
+    #[test]
+    fn labels_are_unique_per_iteration() {
+        let src = "*=$0800
+.rept 2
+  LDX #2
+wait:
+  DEX
+  BNE wait
+.endrept
+";
+        let mut a = Assembler6502::new();
+        let bytes = a.assemble_bytes(src).unwrap();
+        assert_eq!(bytes, vec![0xA2, 0x02, 0xCA, 0xD0, 0xFD, 0xA2, 0x02, 0xCA, 0xD0, 0xFD]);
+        let waits: Vec<u16> = a
+            .sorted_symbols(SymbolOrder::Address)
+            .filter(|s| s.name.ends_with("::wait"))
+            .map(|s| s.value)
+            .collect();
+        assert_eq!(waits, vec![0x0802, 0x0807]);
+    }
+
+    #[test]
+    fn long_branches_inside_unrolled_loops_are_fixed() {
+        let src = "*=$0800
+.rept 2
+top:
+  .rept 130
+    NOP
+  .endrept
+  BNE top
+.endrept
+";
+        let mut a = Assembler6502::new();
+        let bytes = a.assemble_bytes(src).unwrap();
+        // Each BNE becomes BEQ +3 / JMP top
+        assert_eq!(bytes.len(), 2 * (130 + 5));
+        assert_eq!(&bytes[130..135], &[0xF0, 0x03, 0x4C, 0x00, 0x08]);
+    }
+
+    #[test]
+    fn loops_and_macros_combine() {
+        let src = "*=$0800
+.macro fill n, v
+  .rept n
+    .byte v
+  .endrept
+.endmacro
+.for i = 1, i <= 2, i+1
+  fill i, i
+.endfor
+";
+        let mut a = Assembler6502::new();
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![1, 2, 2]);
+    }
+
+    #[test]
+    fn loop_errors() {
+        let mut a = Assembler6502::new();
+        let err = a.assemble_bytes("*=$0800\n.for i = 0, i < 1, i\n.endfor\n").unwrap_err().to_string();
+        assert!(err.contains(".for loop exceeds"), "{}", err);
+        let err = a.assemble_bytes("*=$0800\nx:\n.rept x\n.endrept\n").unwrap_err().to_string();
+        assert!(err.contains("isn't known until layout"), "{}", err);
+        let err = a.assemble_bytes("*=$0800\n.rept 2\n  NOP\n").unwrap_err().to_string();
+        assert!(err.contains(".rept has no matching .endrept"), "{}", err);
+        let err = a.assemble_bytes("*=$0800\n.rept 2\n.endfor\n").unwrap_err().to_string();
+        assert!(err.contains("does not close .rept"), "{}", err);
+        let err = a.assemble_bytes("*=$0800\n.for i = 0\n.endfor\n").unwrap_err().to_string();
+        assert!(err.contains(".for expects"), "{}", err);
+    }
+
+    #[test]
+    fn errors_name_the_iteration() {
+        let src = "*=$0800\n.for i = 0, i < 3, i+1\n  .byte 10 / (2 - i)\n.endfor\n";
+        let mut a = Assembler6502::new();
+        let err = a.assemble_bytes(src).unwrap_err().to_string();
+        assert!(err.contains("Line 3 (expanded from .for i=2 at Line 2)"), "{}", err);
+    }
+
+    #[test]
+    fn registers_and_mnemonics_are_not_loop_variables() {
+        let mut a = Assembler6502::new();
+        let err = a.assemble_bytes(".for a = 0, a < 2, a + 1
+  ASL a
+.endfor
+").unwrap_err().to_string();
+        assert!(err.contains("'a' is reserved for the accumulator and can't be used as a .for variable"), "{}", err);
+        let err = a.assemble_bytes(".for A = 0, A < 2, A + 1
+.endfor
+").unwrap_err().to_string();
+        assert!(err.contains("'A' is reserved for the accumulator"), "{}", err);
+        let err = a.assemble_bytes(".for x = 0, x < 2, x + 1
+  LDA $10,x
+.endfor
+").unwrap_err().to_string();
+        assert!(err.contains("'x' is an index register and can't be used as a .for variable"), "{}", err);
+        let err = a.assemble_bytes(".for inx = 0, inx < 2, inx + 1
+.endfor
+").unwrap_err().to_string();
+        assert!(err.contains("'inx' is an instruction"), "{}", err);
+    }
+}
+
+#[cfg(test)]
+mod include_tests {
+    use super::*;
+    use std::fs;
+
+    /// A scratch directory holding `files`, unique to this test.
+    fn scratch(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("asm6502_{}_{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for (name, text) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn includes_resolve_relative_to_the_including_file() {
+        let dir = scratch("include_relative", &[
+            ("main.s", "*=$0800\n.include \"lib/a.s\"\n.byte 3\n"),
+            ("lib/a.s", ".byte 1\n.include \"b.s\"\n"),
+            ("lib/b.s", "VALUE = 2\n.byte VALUE\n"),
+        ]);
+        let mut a = Assembler6502::new();
+        assert_eq!(a.assemble_file(dir.join("main.s")).unwrap(), vec![1, 2, 3]);
+        let loc = &a.symbol_info("VALUE").unwrap().loc;
+        assert!(loc.file.as_deref().unwrap().ends_with("b.s"), "{}", loc);
+        assert_eq!(loc.line, 1);
+    }
+
+    #[test]
+    fn include_dirs_are_searched_in_order() {
+        let dir = scratch("include_dirs", &[
+            ("one/defs.s", ".byte 1\n"),
+            ("two/defs.s", ".byte 2\n"),
+            ("two/only.s", ".byte 3\n"),
+        ]);
+        let mut a = Assembler6502::new();
+        a.add_include_dir(dir.join("one"));
+        a.add_include_dir(dir.join("two"));
+        let bytes = a.assemble_bytes("*=$0800\n.include \"defs.s\"\n.include \"only.s\"\n").unwrap();
+        assert_eq!(bytes, vec![1, 3]);
+        let err = a.assemble_bytes("*=$0800\n.include \"missing.s\"\n").unwrap_err().to_string();
+        assert!(err.contains("Line 2: .include \"missing.s\": file not found"), "{}", err);
+    }
+
+    #[test]
+    fn circular_includes_are_reported() {
+        let dir = scratch("include_circular", &[
+            ("a.s", ".include \"b.s\"\n"),
+            ("b.s", ".include \"a.s\"\n"),
+        ]);
+        let mut a = Assembler6502::new();
+        let err = a.assemble_file(dir.join("a.s")).unwrap_err().to_string();
+        assert!(err.contains("circular .include"), "{}", err);
+        assert!(err.contains("a.s -> ") && err.contains("b.s -> "), "{}", err);
+    }
+
+    #[test]
+    fn include_once_skips_files_already_included() {
+        let dir = scratch("include_once", &[
+            ("main.s", "*=$0800\n.include \"defs.s\"\n.include_once \"defs.s\"\n.include \"defs.s\"\n"),
+            ("defs.s", ".byte 7\n"),
+        ]);
+        let mut a = Assembler6502::new();
+        assert_eq!(a.assemble_file(dir.join("main.s")).unwrap(), vec![7, 7]);
+    }
+
+    #[test]
+    fn errors_name_the_included_file_and_line() {
+        let dir = scratch("include_errors", &[
+            ("main.s", "*=$0800\n.include \"bad.s\"\n"),
+            ("bad.s", "NOP\nLDA ($10),Z\n"),
+        ]);
+        let mut a = Assembler6502::new();
+        let err = a.assemble_file(dir.join("main.s")).unwrap_err().to_string();
+        assert!(err.contains("bad.s:2: $0801: LDA ($10),Z"), "{}", err);
+    }
+}
+
+#[cfg(test)]
+mod file_provider_tests {
+    use super::*;
+    use crate::files::MemoryFileProvider;
+    use std::cell::Cell;
+    use std::io;
+    use std::rc::Rc;
+
+    #[test]
+    fn memory_files_serve_include_and_incbin() {
+        let files = MemoryFileProvider::new()
+            .with_file("src/main.s", "*=$0800\n.include \"defs.s\"\n.incbin \"data.bin\"\n.byte END\n")
+            .with_file("src/defs.s", "END = $FF\n")
+            .with_file("data.bin", vec![1, 2, 3]);
+        let mut a = Assembler6502::new();
+        a.set_file_provider(files);
+        assert_eq!(a.assemble_file("src/main.s").unwrap(), vec![1, 2, 3, 0xFF]);
+        let (_, map) = a.assemble_with_addr_map("*=$0800\n.incbin \"data.bin\"\n").unwrap();
+        assert_eq!(map, vec![(0, 0x0800), (1, 0x0801), (2, 0x0802)]);
+        let err = a.assemble_bytes("*=$0800\n.incbin \"missing.bin\"\n").unwrap_err().to_string();
+        assert!(err.contains("missing.bin"), "{}", err);
+    }
+
+    /// Grows by one byte on every read.
+    struct Growing(Rc<Cell<usize>>);
+
+    impl FileProvider for Growing {
+        fn read(&self, _path: &Path) -> io::Result<Vec<u8>> {
+            self.0.set(self.0.get() + 1);
+            Ok(vec![0xAA; self.0.get()])
+        }
+
+        fn is_file(&self, _path: &Path) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn files_are_read_once_per_assembly() {
+        let reads = Rc::new(Cell::new(0));
+        let mut a = Assembler6502::new();
+        a.set_file_provider(Growing(Rc::clone(&reads)));
+        // Sizing and emitting agree: the label after the data sits right
+        // after the bytes actually emitted.
+        let src = "*=$0800\n.incbin \"grow.bin\"\nafter:\n.word after\n";
+        let bytes = a.assemble_bytes(src).unwrap();
+        assert_eq!(bytes, vec![0xAA, 0x01, 0x08]);
+        assert_eq!(reads.get(), 1);
+        // A new assembly reads the file again.
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![0xAA, 0xAA, 0x02, 0x08]);
+    }
+}
+
+#[cfg(test)]
+mod incbin_tests {
+    use super::*;
+    use crate::files::MemoryFileProvider;
+
+    fn assembler() -> Assembler6502 {
+        let mut a = Assembler6502::new();
+        a.set_file_provider(
+            MemoryFileProvider::new()
+                .with_file("game.prg", vec![0x01, 0x08, 0xA9, 0x00, 0x60])
+                .with_file("src/main.s", "*=$0800\n.incbin \"../assets/chr.bin\", $2, 2\n")
+                .with_file("assets/chr.bin", vec![0, 1, 2, 3, 4, 5]),
+        );
+        a
+    }
+
+    #[test]
+    fn offset_and_length_select_a_slice() {
+        let mut a = assembler();
+        // Skip the two-byte PRG load address
+        assert_eq!(a.assemble_bytes("*=$0800\n.incbin \"game.prg\", 2\n").unwrap(), vec![0xA9, 0x00, 0x60]);
+        let src = "*=$0800\nHEADER = 1\n.incbin \"game.prg\", HEADER+1, 2\nend:\n";
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![0xA9, 0x00]);
+        assert_eq!(a.lookup("end"), Some(0x0802));
+        // An offset at the very end includes nothing
+        assert_eq!(a.assemble_bytes("*=$0800\n.incbin \"game.prg\", 5\n").unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn paths_resolve_relative_to_the_source_file() {
+        let mut a = assembler();
+        assert_eq!(a.assemble_file("src/main.s").unwrap(), vec![2, 3]);
+    }
+
+    #[test]
+    fn slices_are_bounds_checked() {
+        let mut a = assembler();
+        let err = a.assemble_bytes("*=$0800\n.incbin \"game.prg\", 6\n").unwrap_err().to_string();
+        assert!(err.contains("offset 6 is past the end of the file (5 bytes)"), "{}", err);
+        let err = a.assemble_bytes("*=$0800\n.incbin \"game.prg\", 2, 4\n").unwrap_err().to_string();
+        assert!(err.contains("offset 2 + length 4 runs past the end of the file (5 bytes)"), "{}", err);
+        assert!(a.assemble_bytes("*=$0800\n.incbin \"game.prg\" 2\n").is_err());
+        assert!(a.assemble_bytes("*=$0800\n.incbin \"game.prg\", 1, 1, 1\n").is_err());
+    }
+
+    #[cfg(feature = "listing")]
+    #[test]
+    fn listing_shows_the_slice() {
+        let mut a = assembler();
+        let (_, items) = a.assemble_full("*=$0800\n.incbin \"game.prg\", 2, 2\n").unwrap();
+        let mut out = Vec::new();
+        a.write_listing(&mut out, &items).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("$0800: $A9 $00      .incbin \"game.prg\", $2, 2 (2 bytes)"), "{}", text);
+    }
+}
+
+#[cfg(test)]
+mod storage_tests {
+    use super::*;
+
+    #[test]
+    fn align_pads_to_the_next_multiple() {
+        let mut a = Assembler6502::new();
+        let src = "*=$0801\n.align 4, $EA\ntable:\n.align 4\n.byte 1\n";
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![0xEA, 0xEA, 0xEA, 0x01]);
+        assert_eq!(a.lookup("table"), Some(0x0804));
+        assert!(a.assemble_bytes("*=$0800\n.align 0\n").unwrap_err().to_string().contains("between 1 and $10000"));
+    }
+
+    #[test]
+    fn fill_res_and_ds_reserve_space() {
+        let mut a = Assembler6502::new();
+        let src = "*=$0800\n.fill 2, $FF\n.res 1\n.ds 2, 7\n.byte $AA\n";
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![0xFF, 0xFF, 0x00, 0x07, 0x07, 0xAA]);
+        // The count can depend on the current address
+        let src = "*=$0800\nNOP\n.fill $0804 - *, $FF\nRTS\n";
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![0xEA, 0xFF, 0xFF, 0xFF, 0x60]);
+        let err = a.assemble_bytes("*=$0800\nNOP\n.fill $0800 - *\n").unwrap_err().to_string();
+        assert!(err.contains("Line 3") && err.contains("larger than the address space"), "{}", err);
+    }
+
+    #[test]
+    fn sizes_cant_depend_on_later_symbols() {
+        let mut a = Assembler6502::new();
+        let err = a.assemble_bytes("*=$0800\n  NOP\n.fill end-*\nend:\n").unwrap_err().to_string();
+        assert!(
+            err.contains("Line 3: .fill size depends on 'end', which is defined later; a storage size can only use"),
+            "{}",
+            err
+        );
+        let err = a.assemble_bytes("*=$0800\n.skip size\nsize = 4\n").unwrap_err().to_string();
+        assert!(err.contains("Line 2: .skip size depends on 'size', which is defined later"), "{}", err);
+        let err = a.assemble_bytes("*=$0800\n.res missing\n").unwrap_err().to_string();
+        assert!(err.contains("Line 2: .fill: Undefined label: missing"), "{}", err);
+    }
+
+    #[test]
+    fn skip_advances_without_emitting() {
+        let mut a = Assembler6502::new();
+        let src = "*=$0800\nbuffer:\n.skip 4\nafter:\nLDA buffer\n";
+        let (bytes, map) = a.assemble_with_addr_map(src).unwrap();
+        assert_eq!(bytes, vec![0xAD, 0x00, 0x08]);
+        assert_eq!(a.lookup("after"), Some(0x0804));
+        assert_eq!(map[0], (0, 0x0804));
+        assert!(a.assemble_bytes("*=$0800\n.skip 1, 2\n").is_err());
+    }
+
+    #[test]
+    fn align_follows_code_that_grows_during_layout() {
+        // The branch only turns long once the padding is counted, and the
+        // padding then shrinks to absorb the 3 bytes the long form adds
+        let src = "*=$0800\n  BEQ far\n.align $100\nfar:\n  RTS\n";
+        let mut a = Assembler6502::new();
+        let bytes = a.assemble_bytes(src).unwrap();
+        assert_eq!(&bytes[..5], &[0xD0, 0x03, 0x4C, 0x00, 0x09]);
+        assert_eq!(bytes.len(), 0x101);
+        assert_eq!(a.lookup("far"), Some(0x0900));
+    }
+
+    #[cfg(feature = "listing")]
+    #[test]
+    fn listing_shows_storage() {
+        let mut a = Assembler6502::new();
+        let (_, items) = a.assemble_full("*=$0801\n.align 4, $EA\n.fill 2\n.skip 3\n").unwrap();
+        let mut out = Vec::new();
+        a.write_listing(&mut out, &items).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("$0801:              .align $4 (3 bytes of $EA)"), "{}", text);
+        assert!(text.contains("$0804:              .fill 2 bytes of $00"), "{}", text);
+        assert!(text.contains("$0806:              .skip 3 bytes"), "{}", text);
+    }
+}
+
+#[cfg(test)]
+mod encoding_tests {
+    use super::*;
+
+    #[test]
+    fn string_and_char_literals_use_the_active_encoding() {
+        let src = "*=$0800
+.string \"Hi\"
+.encoding \"petscii\"
+.string \"Hi\"
+  LDA #'a'
+.encoding \"screen\"
+.byte 'a', 'A'+1
+SPACE = ' '
+.encoding \"apple2\"
+.string \"A\"
+";
+        let mut a = Assembler6502::new();
+        let bytes = a.assemble_bytes(src).unwrap();
+        assert_eq!(
+            bytes,
+            vec![0x48, 0x69, 0xC8, 0x49, 0xA9, 0x41, 0x01, 0x42, 0xC1]
+        );
+        assert_eq!(a.lookup("SPACE"), Some(0x20));
+    }
+
+    #[test]
+    fn charmap_defines_a_custom_font() {
+        let src = "*=$0800
+.encoding \"font\"
+.charmap 'A', $01
+.charmap ',', FONT_BASE + 2
+.string \"A,A\"
+  CMP #','
+";
+        let mut a = Assembler6502::new();
+        a.define_symbol("FONT_BASE", 0x40);
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![0x01, 0x42, 0x01, 0xC9, 0x42]);
+    }
+
+    #[test]
+    fn encoding_follows_macro_expansion_and_conditionals() {
+        let src = "*=$0800
+.macro say text
+.string text
+.endm
+PAL = 0
+.if PAL
+.encoding \"atascii\"
+.else
+.encoding \"petscii\"
+.endif
+say \"ok\"
+";
+        let mut a = Assembler6502::new();
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![0x4F, 0x4B]);
+    }
+
+    #[test]
+    fn unmapped_characters_are_errors() {
+        let mut a = Assembler6502::new();
+        let err = a.assemble_bytes("*=$0800\n.encoding \"petscii\"\n.string \"a_b\"\n").unwrap_err().to_string();
+        assert!(err.contains("Line 3") && err.contains("'_' has no mapping in the \"petscii\" encoding"), "{}", err);
+        let err = a.assemble_bytes("*=$0800\n.encoding \"font\"\n  LDA #'x'\n").unwrap_err().to_string();
+        assert!(err.contains("Line 3") && err.contains("unknown encoding \"font\" has no .charmap entries"), "{}", err);
+        let src = "*=$0800\n.encoding \"font\"\n.charmap 'A', 1\n  LDA #'x'\n";
+        let err = a.assemble_bytes(src).unwrap_err().to_string();
+        assert!(err.contains("Line 4") && err.contains("'x' has no mapping in the \"font\" encoding"), "{}", err);
+        assert!(a.assemble_bytes(".charmap 'A', $100\n").unwrap_err().to_string().contains("doesn't fit in a byte"));
+        assert!(a.assemble_bytes(".charmap A, 1\n").is_err());
+    }
+
+    #[test]
+    fn unknown_encodings_need_charmap_entries() {
+        let mut a = Assembler6502::new();
+        let err = a.assemble_bytes("*=$0800\n.encoding \"petsci\"\n  NOP\n").unwrap_err().to_string();
+        assert!(err.contains("Line 2: unknown encoding \"petsci\"; the built-in ones are ascii, petscii"), "{}", err);
+        let err = a.assemble_bytes(".encoding \"petsci\"\n.encoding \"ascii\"\n").unwrap_err().to_string();
+        assert!(err.contains("Line 1: unknown encoding \"petsci\""), "{}", err);
+        // A custom table only exists once it has entries; switching back to it is fine
+        let src = ".encoding \"Font\"\n.charmap 'A', 1\n.encoding \"ascii\"\n.encoding \"font\"\n.string \"A\"\n";
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![1]);
+    }
+}
+
+#[cfg(test)]
+mod string_tests {
+    use super::*;
+
+    #[test]
+    fn escapes_and_semicolons_inside_strings() {
+        let mut a = Assembler6502::new();
+        let src = "*=$0800\n.string \"a\\\"b;c\\\\\" ; comment\n.string \"\\n\\x7F\"\n  LDA #';'\n";
+        assert_eq!(
+            a.assemble_bytes(src).unwrap(),
+            vec![b'a', b'"', b'b', b';', b'c', b'\\', 0x0A, 0x7F, 0xA9, b';']
+        );
+        assert!(a.assemble_bytes(".string \"\\q\"\n").unwrap_err().to_string().contains("unknown escape"));
+    }
+
+    #[test]
+    fn terminated_prefixed_and_shifted_strings() {
+        let mut a = Assembler6502::new();
+        let src = "*=$0800\n.asciiz \"AB\"\n.cstring \"\"\n.pstring \"ABC\"\n.shift \"AB\"\n";
+        assert_eq!(
+            a.assemble_bytes(src).unwrap(),
+            vec![0x41, 0x42, 0x00, 0x00, 0x03, 0x41, 0x42, 0x43, 0x41, 0xC2]
+        );
+        let err = a.assemble_bytes("*=$0800\n.shift \"A\", end\nend:\n").unwrap_err().to_string();
+        assert!(err.contains("Line 2: .shift: the last byte"), "{}", err);
+    }
+
+    #[test]
+    fn strings_mix_with_expressions() {
+        let mut a = Assembler6502::new();
+        let src = "*=$0800\nCR = 13\n.text \"HI, \", CR, 0, <msg\nmsg:\n.pstring \"OK\", '!'\n";
+        assert_eq!(
+            a.assemble_bytes(src).unwrap(),
+            vec![b'H', b'I', b',', b' ', 13, 0, 0x07, 3, b'O', b'K', b'!']
+        );
+        // \xNN bytes bypass the encoding; other characters go through it
+        let src = "*=$0800\n.encoding \"petscii\"\n.text \"a\\x01\", 'b'\n";
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![0x41, 0x01, 0x42]);
+    }
+
+    #[test]
+    fn macro_arguments_keep_escaped_quotes() {
+        let src = "*=$0800\n.macro msg text\n.asciiz text\n.endm\nmsg \"say \\\"hi\\\"; bye\"\n";
+        let mut a = Assembler6502::new();
+        assert_eq!(a.assemble_bytes(src).unwrap(), b"say \"hi\"; bye\0".to_vec());
+    }
+
+    #[cfg(feature = "listing")]
+    #[test]
+    fn listing_shows_the_directive_as_written() {
+        let mut a = Assembler6502::new();
+        let (_, items) = a.assemble_full("*=$0800\n.asciiz \"HI\", 13\n").unwrap();
+        let mut out = Vec::new();
+        a.write_listing(&mut out, &items).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("$0800: $48 $49 $0D $00 .asciiz \"HI\", 13"), "{}", text);
+    }
+}
+
+#[cfg(test)]
+mod data_tests {
+    use super::*;
+
+    #[test]
+    fn wide_and_big_endian_values() {
+        let mut a = Assembler6502::new();
+        let src = "*=$0800\n.dword $12345678, 1\n.long $10000\n.dbyte $1234\n.bigword 'A'\n";
+        assert_eq!(
+            a.assemble_bytes(src).unwrap(),
+            vec![
+                0x78, 0x56, 0x34, 0x12, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00,
+                0x12, 0x34, 0x00, 0x41,
+            ]
+        );
+    }
+
+    #[test]
+    fn rts_dispatch_and_split_tables() {
+        let src = "*=$0800
+  LDA hi,X
+  PHA
+  LDA lo,X
+  PHA
+  RTS
+rta:
+  .rta one, two
+lo:
+  .lobytes one-1, two-1
+hi:
+  .hibytes one-1, two-1
+*=$1234
+one:
+  RTS
+two:
+  RTS
+";
+        let mut a = Assembler6502::new();
+        let bytes = a.assemble_bytes(src).unwrap();
+        let rta = (a.lookup("rta").unwrap() - 0x0800) as usize;
+        assert_eq!(&bytes[rta..rta + 8], &[0x33, 0x12, 0x34, 0x12, 0x33, 0x34, 0x12, 0x12]);
+    }
+
+    #[test]
+    fn address_map_covers_every_byte() {
+        let mut a = Assembler6502::new();
+        let (bytes, map) = a.assemble_with_addr_map("*=$0800\n.dword 0\n.lobytes 1, 2\nend:\n").unwrap();
+        assert_eq!(bytes.len(), 6);
+        assert_eq!(map.last(), Some(&(5, 0x0805)));
+        assert_eq!(a.lookup("end"), Some(0x0806));
+        assert!(a.assemble_bytes(".dword\n").is_err());
+    }
+
+    #[cfg(feature = "listing")]
+    #[test]
+    fn listing_shows_values() {
+        let mut a = Assembler6502::new();
+        let (_, items) = a.assemble_full("*=$0800\n.dword $12345678\n.rta $C000\n.hibytes $1234, $5678\n").unwrap();
+        let mut out = Vec::new();
+        a.write_listing(&mut out, &items).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("$0800: $78 $56 $34 $12 .dword $12345678"), "{}", text);
+        assert!(text.contains("$0804: $FF $BF      .rta $C000"), "{}", text);
+        assert!(text.contains("$0806: $12 $56      .hibytes $1234,$5678"), "{}", text);
+    }
+}
+
+#[cfg(test)]
+mod assert_tests {
+    use super::*;
+
+    #[test]
+    fn assertions_see_final_addresses() {
+        // `far` only moves past $0900 once the branch to it is expanded
+        let src = "*=$0800
+  BEQ far
+.fill $FC
+far:
+.assert * > $0900, \"far moved after layout\"
+.assert >far == >(far+3)
+  RTS
+";
+        let mut a = Assembler6502::new();
+        assert!(a.assemble_bytes(src).is_ok());
+        assert_eq!(a.lookup("far"), Some(0x0901));
+    }
+
+    #[test]
+    fn failed_assertions_report_location_and_message() {
+        let mut a = Assembler6502::new();
+        let err = a
+            .assemble_bytes("*=$9FFE\n  NOP\n  NOP\n  NOP\n.assert * <= $A000, \"code overflows into BASIC ROM\"\n")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Line 5: assertion failed: code overflows into BASIC ROM"), "{}", err);
+        let err = a.assemble_bytes("*=$0800\n.assert 1 == 2\n").unwrap_err().to_string();
+        assert!(err.contains("Line 2: assertion failed: 1 == 2"), "{}", err);
+        let err = a.assemble_bytes("*=$0800\n.assert missing\n").unwrap_err().to_string();
+        assert!(err.contains("Line 2: .assert missing: Undefined label: missing"), "{}", err);
+    }
+
+    #[test]
+    fn error_and_warning_directives() {
+        let src = "*=$0800
+SIZE = 300
+.if SIZE > 255
+.warning \"table is \\\"large\\\"\"
+.endif
+.if SIZE > 1000
+.error \"table too big\"
+.endif
+  NOP
+";
+        let mut a = Assembler6502::new();
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![0xEA]);
+        assert_eq!(a.warnings().len(), 1);
+        assert_eq!(a.warnings()[0].to_string(), "Line 4: warning: table is \"large\"");
+
+        let err = a.assemble_bytes(&src.replace("1000", "256")).unwrap_err().to_string();
+        assert!(err.contains("Line 7: table too big"), "{}", err);
+        // Warnings are per assembly
+        assert!(a.assemble_bytes("NOP\n").is_ok());
+        assert!(a.warnings().is_empty());
+    }
+
+    #[cfg(feature = "listing")]
+    #[test]
+    fn listing_reports_warnings() {
+        let mut a = Assembler6502::new();
+        let (_, items) = a.assemble_full("*=$0800\n.warning \"check me\"\n  NOP\n").unwrap();
+        let mut out = Vec::new();
+        a.write_listing(&mut out, &items).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("Warnings:\n") && text.contains("Line 2: warning: check me"), "{}", text);
+    }
+}
+
+#[cfg(test)]
+mod segment_tests {
+    use super::*;
+
+    fn c64_config() -> MemoryConfig {
+        let mut config = MemoryConfig::new();
+        config.add_area("ZP", 0x0002, 0xFE, None).unwrap();
+        config.add_area("MAIN", 0x0801, 0x10, None).unwrap();
+        config.add_area("RAM", 0xC000, 0x1000, None).unwrap();
+        config.add_segment("ZEROPAGE", "ZP", SegmentKind::Bss).unwrap();
+        config.add_segment("CODE", "MAIN", SegmentKind::Code).unwrap();
+        config.add_segment("RODATA", "MAIN", SegmentKind::Code).unwrap();
+        config.add_segment("BSS", "RAM", SegmentKind::Bss).unwrap();
+        config
+    }
+
+    #[test]
+    fn segments_are_gathered_and_placed_by_area() {
+        let src = "
+.segment \"ZEROPAGE\"
+ptr:
+.res 2
+.segment \"CODE\"
+start:
+  LDA msg
+  STA ptr
+.segment \"BSS\"
+buffer:
+.res 256
+.segment \"RODATA\"
+msg:
+.byte 1, 2
+.segment \"CODE\"
+  STA buffer
+  RTS
+";
+        let mut a = Assembler6502::new();
+        a.set_memory_config(c64_config());
+        let bytes = a.assemble_bytes(src).unwrap();
+        assert_eq!(a.lookup("ptr"), Some(0x0002));
+        assert_eq!(a.lookup("start"), Some(0x0801));
+        assert_eq!(a.lookup("msg"), Some(0x080A));
+        assert_eq!(a.lookup("buffer"), Some(0xC000));
+        // BSS segments emit nothing; CODE is followed by RODATA
+        assert_eq!(
+            bytes,
+            vec![0xAD, 0x0A, 0x08, 0x85, 0x02, 0x8D, 0x00, 0xC0, 0x60, 0x01, 0x02]
+        );
+    }
+
+    #[test]
+    fn code_before_the_first_segment_goes_to_code() {
+        let mut a = Assembler6502::new();
+        a.set_memory_config(c64_config());
+        let src = "COUNT = 2\n  LDX #COUNT\n.segment \"RODATA\"\n.byte 9\n.segment \"CODE\"\n  RTS\n";
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![0xA2, 0x02, 0x60, 0x09]);
+
+        let mut config = MemoryConfig::new();
+        config.add_area("MAIN", 0x1000, 0x100, None).unwrap();
+        config.add_segment("STARTUP", "MAIN", SegmentKind::Code).unwrap();
+        a.set_memory_config(config);
+        assert_eq!(a.assemble_bytes("ONE = 1\n.segment \"STARTUP\"\n.byte ONE\n").unwrap(), vec![1]);
+        let err = a.assemble_bytes("NOP\n").unwrap_err().to_string();
+        assert!(err.contains("Line 1: no segment selected"), "{}", err);
+    }
+
+    #[test]
+    fn filled_areas_pad_gaps_and_the_end() {
+        let mut config = MemoryConfig::new();
+        config.add_area("ROM", 0xE000, 8, Some(0xFF)).unwrap();
+        config.add_segment("CODE", "ROM", SegmentKind::Code).unwrap();
+        config.add_segment("VECTORS", "ROM", SegmentKind::Code).unwrap();
+        let mut a = Assembler6502::new();
+        a.set_memory_config(config);
+        let src = "NOP\n.skip 2\nNOP\n.segment \"VECTORS\"\n.byte $AA\n";
+        let (bytes, map) = a.assemble_with_addr_map(src).unwrap();
+        assert_eq!(bytes, vec![0xEA, 0xFF, 0xFF, 0xEA, 0xAA, 0xFF, 0xFF, 0xFF]);
+        let addrs: Vec<u16> = map.iter().map(|&(_, addr)| addr).collect();
+        assert_eq!(addrs, (0xE000..0xE008).collect::<Vec<u16>>());
+    }
+
+    #[test]
+    fn a_filled_area_is_padded_once_before_the_next() {
+        let mut config = MemoryConfig::new();
+        config.add_area("LOW", 0x1000, 4, Some(0xFF)).unwrap();
+        config.add_area("HIGH", 0x2000, 4, None).unwrap();
+        config.add_segment("CODE", "LOW", SegmentKind::Code).unwrap();
+        config.add_segment("DATA", "HIGH", SegmentKind::Code).unwrap();
+        let mut a = Assembler6502::new();
+        a.set_memory_config(config);
+        let (bytes, map) = a.assemble_with_addr_map("NOP
+.segment \"DATA\"\n.byte 1\n").unwrap();
+        assert_eq!(bytes, vec![0xEA, 0xFF, 0xFF, 0xFF, 0x01]);
+        assert_eq!(map.last(), Some(&(4, 0x2000)));
+    }
+
+    #[test]
+    fn bss_gaps_before_code_are_padded() {
+        let mut config = MemoryConfig::new();
+        config.add_area("RAM", 0x0200, 0x100, None).unwrap();
+        config.add_segment("BSS", "RAM", SegmentKind::Bss).unwrap();
+        config.add_segment("CODE", "RAM", SegmentKind::Code).unwrap();
+        let mut a = Assembler6502::new();
+        a.set_memory_config(config);
+        let bytes = a.assemble_bytes(".segment \"BSS\"\nflag:\n.res 2\n.segment \"CODE\"\nINC flag\n").unwrap();
+        assert_eq!(bytes, vec![0x00, 0x00, 0xEE, 0x00, 0x02]);
+    }
+
+    #[test]
+    fn overflow_and_misuse_are_reported() {
+        let mut a = Assembler6502::new();
+        a.set_memory_config(c64_config());
+        let err = a.assemble_bytes(".fill 15\n.segment \"RODATA\"\n.word 1\n").unwrap_err().to_string();
+        assert!(
+            err.contains("Line 3: segment \"RODATA\" overflows memory area \"MAIN\" ($0801-$0810) by 1 byte"),
+            "{}",
+            err
+        );
+        let err = a.assemble_bytes(".segment \"BSS\"\n.byte 1\n").unwrap_err().to_string();
+        assert!(err.contains("Line 2: segment \"BSS\" is BSS"), "{}", err);
+        let err = a.assemble_bytes(".segment \"DATA\"\n").unwrap_err().to_string();
+        assert!(err.contains("Line 1: segment \"DATA\" is not in the memory configuration"), "{}", err);
+        let err = a.assemble_bytes("*=$1000\nNOP\n").unwrap_err().to_string();
+        assert!(err.contains("Line 1: *= can't be used with a memory configuration"), "{}", err);
+
+        a.clear_memory_config();
+        let err = a.assemble_bytes(".segment \"CODE\"\nNOP\n").unwrap_err().to_string();
+        assert!(err.contains("needs a memory configuration"), "{}", err);
+    }
+
+    #[test]
+    fn config_is_validated() {
+        let mut config = MemoryConfig::new();
+        assert!(config.add_area("HI", 0xFF00, 0x100, None).is_ok());
+        assert!(config.add_area("OVER", 0xFF00, 0x101, None).is_err());
+        assert!(config.add_area("EMPTY", 0x1000, 0, None).is_err());
+        assert!(config.add_area("HI", 0x1000, 1, None).is_err());
+        assert!(config.add_segment("CODE", "NOWHERE", SegmentKind::Code).is_err());
+        assert!(config.add_segment("CODE", "HI", SegmentKind::Code).is_ok());
+        assert!(config.add_segment("CODE", "HI", SegmentKind::Bss).is_err());
+        assert_eq!(config.area("HI").unwrap().end(), 0xFFFF);
+    }
+
+    #[test]
+    fn macros_can_switch_segments() {
+        let src = "
+.macro message text
+.segment \"RODATA\"
+text_byte:
+.byte text
+.segment \"CODE\"
+  LDA text_byte
+.endmacro
+  message 7
+  message 8
+";
+        let mut a = Assembler6502::new();
+        a.set_memory_config(c64_config());
+        let bytes = a.assemble_bytes(src).unwrap();
+        assert_eq!(bytes, vec![0xAD, 0x07, 0x08, 0xAD, 0x08, 0x08, 0x07, 0x08]);
+    }
+}
+
+#[cfg(test)]
+mod logical_tests {
+    use super::*;
+
+    #[test]
+    fn labels_use_the_logical_address_and_bytes_the_physical_stream() {
+        let src = "*=$0800
+  LDX #end_copy - copy
+.pseudopc $C000
+copy:
+  LDA #<*
+  JMP copy
+end_copy:
+.realpc
+after:
+  RTS
+";
+        let mut a = Assembler6502::new();
+        let (bytes, map) = a.assemble_with_addr_map(src).unwrap();
+        assert_eq!(bytes, vec![0xA2, 0x05, 0xA9, 0x00, 0x4C, 0x00, 0xC0, 0x60]);
+        assert_eq!(a.lookup("copy"), Some(0xC000));
+        assert_eq!(a.lookup("end_copy"), Some(0xC005));
+        assert_eq!(a.lookup("after"), Some(0x0807));
+        // The address map follows the physical stream
+        assert_eq!(map.last(), Some(&(7, 0x0807)));
+        assert_eq!(map[2], (2, 0x0802));
+    }
+
+    #[test]
+    fn blocks_nest_and_accept_aliases() {
+        let src = "*=$1000
+.logical $0200
+outer:
+  NOP
+.pseudopc $0300
+inner:
+  NOP
+.here
+back:
+  NOP
+.realpc
+done:
+";
+        let mut a = Assembler6502::new();
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![0xEA, 0xEA, 0xEA]);
+        assert_eq!(a.lookup("outer"), Some(0x0200));
+        assert_eq!(a.lookup("inner"), Some(0x0300));
+        assert_eq!(a.lookup("back"), Some(0x0202));
+        assert_eq!(a.lookup("done"), Some(0x1003));
+    }
+
+    #[test]
+    fn unbalanced_blocks_are_errors() {
+        let mut a = Assembler6502::new();
+        let err = a.assemble_bytes("*=$0800\n.pseudopc $C000\nNOP\n").unwrap_err().to_string();
+        assert!(err.contains("Line 2: .pseudopc without .realpc"), "{}", err);
+        let err = a.assemble_bytes("*=$0800\nNOP\n.realpc\n").unwrap_err().to_string();
+        assert!(err.contains("Line 3: .realpc without .pseudopc"), "{}", err);
+        let err = a.assemble_bytes(".pseudopc $C000\n*=$0900\n.realpc\n").unwrap_err().to_string();
+        assert!(err.contains("Line 2: *= inside the .pseudopc block opened at Line 1"), "{}", err);
+        assert!(a.assemble_bytes(".pseudopc\n").is_err());
+        assert!(a.assemble_bytes(".pseudopc $C000\n.realpc $0800\n").is_err());
+    }
+
+    #[test]
+    fn reserved_ranges_apply_to_the_physical_block() {
+        let mut a = Assembler6502::new();
+        a.add_reserved_range(0x0810, 0x081F).unwrap();
+        let src = format!(
+            "*=$0800\n{}.pseudopc $C000\nrelocated:\n{}.realpc\n",
+            "  NOP\n".repeat(10),
+            "  LDA $1234\n".repeat(3)
+        );
+        let (bytes, map) = a.assemble_with_addr_map(&src).unwrap();
+        // The block is moved past the range as a whole
+        assert_eq!(a.lookup("relocated"), Some(0xC000));
+        assert_eq!(&bytes[10..13], &[0x4C, 0x20, 0x08]);
+        assert_eq!(bytes.len(), 0x20 + 9);
+        assert_eq!(map[0x20], (0x20, 0x0820));
+        assert_eq!(&bytes[0x20..0x23], &[0xAD, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn segments_place_blocks_by_physical_address() {
+        let mut config = MemoryConfig::new();
+        config.add_area("MAIN", 0x0801, 8, None).unwrap();
+        config.add_segment("CODE", "MAIN", SegmentKind::Code).unwrap();
+        let mut a = Assembler6502::new();
+        a.set_memory_config(config.clone());
+        assert!(a.assemble_bytes(".pseudopc $0002\n.fill 8\n.realpc\n").is_ok());
+        let err = a.assemble_bytes(".pseudopc $0002\n.fill 9\n.realpc\n").unwrap_err().to_string();
+        assert!(err.contains("Line 2: segment \"CODE\" overflows memory area \"MAIN\""), "{}", err);
+
+        config.add_segment("RODATA", "MAIN", SegmentKind::Code).unwrap();
+        a.set_memory_config(config);
+        let err = a.assemble_bytes(".pseudopc $0002\nNOP\n.segment \"RODATA\"\n.realpc\n").unwrap_err().to_string();
+        assert!(err.contains("Line 1: .pseudopc without .realpc"), "{}", err);
+    }
+
+    #[cfg(feature = "listing")]
+    #[test]
+    fn listing_shows_logical_and_physical_addresses() {
+        let mut a = Assembler6502::new();
+        let (_, items) = a.assemble_full("*=$0800\n.pseudopc $C000\nirq:\n  RTI\n.realpc\n  NOP\n").unwrap();
+        let mut out = Vec::new();
+        a.write_listing(&mut out, &items).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("$0800:          .pseudopc $C000"), "{}", text);
+        assert!(text.contains("$C000 ($0800):          irq:"), "{}", text);
+        assert!(text.contains("$C000 ($0800): $40"), "{}", text);
+        assert!(text.contains("$C001 ($0801):          .realpc"), "{}", text);
+        assert!(text.contains("$0801: $EA"), "{}", text);
+    }
+}
+
+#[cfg(test)]
+mod image_tests {
+    use super::*;
+    use crate::image::Chunk;
+
+    #[test]
+    fn regions_keep_their_addresses() {
+        let src = "*=$C000\nirq:\n  RTI\n*=$0800\n  LDA #1\n*=$0803\n  RTS\n";
+        let mut a = Assembler6502::new();
+        // The flat output stays in emission order
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![0x40, 0xA9, 0x01, 0x60]);
+        let image = a.assemble_image(src).unwrap();
+        assert_eq!(
+            image.chunks(),
+            &[
+                Chunk { bank: None, start: 0x0800, bytes: vec![0xA9, 0x01] },
+                Chunk { bank: None, start: 0x0803, bytes: vec![0x60] },
+                Chunk { bank: None, start: 0xC000, bytes: vec![0x40] },
+            ]
+        );
+        let flat = image.to_flat(0xFF);
+        assert_eq!(flat.len(), 0xC001 - 0x0800);
+        assert_eq!(&flat[..4], &[0xA9, 0x01, 0xFF, 0x60]);
+        assert_eq!(a.image(), &image);
+    }
+
+    #[test]
+    fn reset_drops_the_last_image() {
+        let mut a = Assembler6502::new();
+        a.assemble_bytes("*=$0800
+.warning \"old\"
+  NOP
+").unwrap();
+        assert!(!a.image().is_empty());
+        assert_eq!(a.warnings().len(), 1);
+        a.reset();
+        assert!(a.image().is_empty());
+        assert!(a.warnings().is_empty());
+    }
+
+    #[test]
+    fn overlapping_writes_name_both_lines() {
+        let mut a = Assembler6502::new();
+        let err = a.assemble_bytes("*=$0800\n.byte 1, 2, 3\n*=$0801\n.byte 4\n").unwrap_err().to_string();
+        assert!(err.contains("Line 4: output at $0801-$0801 overlaps bytes already written by Line 2"), "{}", err);
+        let err = a.assemble_bytes("*=$0810\n.word 1\n*=$0800\n.fill $11\n").unwrap_err().to_string();
+        assert!(err.contains("Line 4: output at $0810-$0810 overlaps bytes already written by Line 2"), "{}", err);
+        // Skipped space and BSS write nothing, so reusing it is fine
+        assert!(a.assemble_bytes("*=$0800\n.skip 4\n*=$0800\n.byte 1, 2, 3, 4\n").is_ok());
+    }
+
+    #[test]
+    fn segment_areas_and_logical_blocks_use_physical_addresses() {
+        let mut config = MemoryConfig::new();
+        config.add_area("LOW", 0x0200, 0x10, None).unwrap();
+        config.add_area("HIGH", 0xE000, 0x10, None).unwrap();
+        config.add_segment("CODE", "HIGH", SegmentKind::Code).unwrap();
+        config.add_segment("DATA", "LOW", SegmentKind::Code).unwrap();
+        let mut a = Assembler6502::new();
+        a.set_memory_config(config);
+        let image = a
+            .assemble_image("  NOP\n.pseudopc $0400\n  RTS\n.realpc\n.segment \"DATA\"\n.byte 7\n")
+            .unwrap();
+        assert_eq!(
+            image.chunks(),
+            &[
+                Chunk { bank: None, start: 0x0200, bytes: vec![7] },
+                Chunk { bank: None, start: 0xE000, bytes: vec![0xEA, 0x60] },
+            ]
+        );
+    }
+}
+
+#[cfg(test)]
+mod struct_tests {
+    use super::*;
+
+    #[test]
+    fn struct_fields_are_offsets() {
+        let src = ".struct Actor
+x .byte
+y .byte     ; row
+pos .word
+.endstruct
+*=$0800
+  LDA actors + Actor::pos
+  LDX #Actor::size
+actors:
+";
+        let mut a = Assembler6502::new();
+        let bytes = a.assemble_bytes(src).unwrap();
+        assert_eq!(a.lookup("Actor::x"), Some(0));
+        assert_eq!(a.lookup("Actor::y"), Some(1));
+        assert_eq!(a.lookup("Actor::pos"), Some(2));
+        assert_eq!(a.lookup("Actor::size"), Some(4));
+        assert_eq!(bytes, vec![0xAD, 0x07, 0x08, 0xA2, 0x04]);
+    }
+
+    #[test]
+    fn arrays_padding_and_nested_structs() {
+        let src = ".struct Point
+x .word
+y .word
+.endstruct
+.struct Sprite
+name .byte 8
+  .res 2
+home .tag Point
+flags .dword
+.ends
+";
+        let mut a = Assembler6502::new();
+        a.assemble_bytes(src).unwrap();
+        assert_eq!(a.lookup("Sprite::home"), Some(10));
+        assert_eq!(a.lookup("Sprite::home::y"), Some(12));
+        assert_eq!(a.lookup("Sprite::flags"), Some(14));
+        assert_eq!(a.lookup("Sprite::size"), Some(18));
+    }
+
+    #[test]
+    fn enums_count_up_from_zero() {
+        let src = ".enum Color
+BLACK
+WHITE
+RED = 5, CYAN
+.endenum
+.enum
+FIRST = Color::CYAN * 2
+SECOND
+.ende
+*=$0800
+  LDA #Color::RED
+";
+        let mut a = Assembler6502::new();
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![0xA9, 0x05]);
+        assert_eq!(a.lookup("Color::WHITE"), Some(1));
+        assert_eq!(a.lookup("Color::CYAN"), Some(6));
+        assert_eq!(a.lookup("SECOND"), Some(13));
+    }
+
+    #[test]
+    fn instances_take_named_initialisers() {
+        let src = ".struct Actor
+x .byte
+y .byte
+pos .word
+hp .dword
+.endstruct
+*=$0800
+player:
+.dstruct Actor, pos = player, x = 'A'
+.dstruct Actor
+";
+        let mut a = Assembler6502::new();
+        let bytes = a.assemble_bytes(src).unwrap();
+        assert_eq!(&bytes[..8], &[0x41, 0, 0x00, 0x08, 0, 0, 0, 0]);
+        assert_eq!(bytes.len(), 16);
+        assert!(bytes[8..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn struct_errors() {
+        let mut a = Assembler6502::new();
+        let err = a.assemble_bytes(".struct S\nsize .byte\n.endstruct\n").unwrap_err().to_string();
+        assert!(err.contains("Line 2: a struct field can't be named 'size'"), "{}", err);
+        let err = a.assemble_bytes(".struct S\na .byte\na .word\n.endstruct\n").unwrap_err().to_string();
+        assert!(err.contains("Line 3: field 'a' already defined in struct 'S'"), "{}", err);
+        let err = a.assemble_bytes(".struct S\np .tag Point\n.endstruct\n").unwrap_err().to_string();
+        assert!(err.contains("Line 2: .tag: no struct 'Point' defined above"), "{}", err);
+        let err = a.assemble_bytes(".struct S\na .byte\n.endstruct\n.struct S\n.endstruct\n").unwrap_err().to_string();
+        assert!(err.contains("Line 4: struct 'S' already defined at Line 1"), "{}", err);
+        let src = ".struct S\na .byte 2\nb .byte\n.endstruct\n";
+        let err = a.assemble_bytes(&format!("{}.dstruct S, a = 1\n", src)).unwrap_err().to_string();
+        assert!(err.contains("Line 5: field 'a' isn't a single .byte, .word or .dword"), "{}", err);
+        let err = a.assemble_bytes(&format!("{}.dstruct S, c = 1\n", src)).unwrap_err().to_string();
+        assert!(err.contains("Line 5: struct 'S' has no field 'c'"), "{}", err);
+        let err = a.assemble_bytes(&format!("{}.dstruct S, b = 1, b = 2\n", src)).unwrap_err().to_string();
+        assert!(err.contains("Line 5: field 'b' is initialised twice"), "{}", err);
+        let err = a.assemble_bytes(".dstruct Nope\n").unwrap_err().to_string();
+        assert!(err.contains("Line 1: .dstruct: no struct 'Nope' defined above"), "{}", err);
+        let err = a.assemble_bytes(".struct S\nLDA #1\n.endstruct\n").unwrap_err().to_string();
+        assert!(err.contains("Line 2: expected a field"), "{}", err);
+        assert!(a.assemble_bytes(".struct S\na .byte\n").is_err());
+    }
+}
+
+#[cfg(test)]
+mod bank_tests {
+    use super::*;
+
+    #[test]
+    fn banks_share_the_window_and_fill_the_rom() {
+        let src = ".macro farjsr target
+  LDA #bank(target)
+  JSR switch
+  JSR target
+.endm
+.bank 0, $4000, $8000
+reset:
+  farjsr handler
+switch:
+  RTS
+.bank 1, $4000, $8000
+handler:
+  RTS
+.bank 0
+more:
+  NOP
+";
+        let mut a = Assembler6502::new();
+        let rom = a.assemble_bytes(src).unwrap();
+        assert_eq!(rom.len(), 0x8000);
+        assert_eq!(&rom[..10], &[0xA9, 0x01, 0x20, 0x08, 0x80, 0x20, 0x00, 0x80, 0x60, 0xEA]);
+        assert!(rom[10..0x4000].iter().all(|&b| b == 0));
+        assert_eq!(rom[0x4000], 0x60);
+        assert_eq!(a.lookup("more"), Some(0x8009));
+        assert_eq!(a.bank_of("handler"), Some(1));
+        assert_eq!(a.bank_of("more"), Some(0));
+        assert_eq!(a.image().bank_byte_at(1, 0x8000), Some(0x60));
+        assert_eq!(a.image().bank_byte_at(0, 0x8000), Some(0xA9));
+    }
+
+    #[test]
+    fn org_moves_forward_inside_a_bank() {
+        let src = ".bank 0, $1000, $F000\nreset:\n  NOP\n*=$FFFC\n  .word reset\n";
+        let mut a = Assembler6502::new();
+        let rom = a.assemble_bytes(src).unwrap();
+        assert_eq!(rom.len(), 0x1000);
+        assert_eq!((rom[0], rom[0xFFC], rom[0xFFD]), (0xEA, 0x00, 0xF0));
+        let err = a.assemble_bytes(".bank 0, $1000, $F000\n*=$F800\n  NOP\n*=$F000\n  NOP\n").unwrap_err().to_string();
+        assert!(err.contains("Line 5: output at $F000 is behind bytes already placed"), "{}", err);
+    }
+
+    #[test]
+    fn reserved_ranges_can_be_limited_to_a_bank() {
+        let nops = "  NOP\n".repeat(20);
+        let src = format!(".bank 0, $2000, $8000\n{}.bank 1, $2000, $8000\n{}", nops, nops);
+        let mut a = Assembler6502::new();
+        a.add_bank_reserved_range(1, 0x8010, 0x801F).unwrap();
+        // Another bank's range may use the same addresses
+        a.add_bank_reserved_range(0, 0x8010, 0x8014).unwrap();
+        assert!(a.add_reserved_range(0x8012, 0x8020).is_err());
+        a.clear_reserved_ranges();
+        a.add_bank_reserved_range(1, 0x8010, 0x801F).unwrap();
+        a.assemble_bytes(&src).unwrap();
+        let image = a.image();
+        assert_eq!(image.bank_byte_at(0, 0x800D), Some(0xEA));
+        assert_eq!(image.bank_byte_at(0, 0x8013), Some(0xEA));
+        assert_eq!(image.bank_byte_at(1, 0x800D), Some(0x4C));
+        assert_eq!(image.bank_byte_at(1, 0x8020), Some(0xEA));
+    }
+
+    #[test]
+    fn segments_in_banked_memory_areas() {
+        let mut config = MemoryConfig::new();
+        config.add_banked_area("PRG0", 0, 0x8000, 0x2000, Some(0xFF)).unwrap();
+        config.add_banked_area("PRG1", 1, 0x8000, 0x2000, Some(0xFF)).unwrap();
+        assert!(config.add_banked_area("AGAIN", 1, 0xA000, 0x100, None).is_err());
+        config.add_segment("CODE", "PRG0", SegmentKind::Code).unwrap();
+        config.add_segment("EXTRA", "PRG1", SegmentKind::Code).unwrap();
+        let mut a = Assembler6502::new();
+        a.set_memory_config(config);
+        let rom = a.assemble_bytes("  LDA #bank(far)\n  JSR far\n.segment \"EXTRA\"\nfar:\n  RTS\n").unwrap();
+        assert_eq!(rom.len(), 0x4000);
+        assert_eq!(&rom[..5], &[0xA9, 0x01, 0x20, 0x00, 0x80]);
+        assert_eq!((rom[5], rom[0x2000]), (0xFF, 0x60));
+        let err = a.assemble_bytes(".bank 0, $100, $8000\n").unwrap_err().to_string();
+        assert!(err.contains("Line 1: .bank can't be used with a memory configuration"), "{}", err);
+    }
+
+    #[test]
+    fn bank_errors() {
+        let mut a = Assembler6502::new();
+        let err = a.assemble_bytes("  NOP\n.bank 0, $100, $8000\n").unwrap_err().to_string();
+        assert!(err.contains("Line 1: code before the first .bank"), "{}", err);
+        let err = a.assemble_bytes(".bank 0\n").unwrap_err().to_string();
+        assert!(err.contains("Line 1: bank 0 isn't defined yet"), "{}", err);
+        let err = a.assemble_bytes(".bank 0, $100, $8000\n.bank 0, $200, $8000\n").unwrap_err().to_string();
+        assert!(err.contains("Line 2: bank 0 was defined at Line 1 as $100 bytes at $8000"), "{}", err);
+        let err = a.assemble_bytes(".bank 0, $100, $8000\n.bank 2, $100, $8000\n").unwrap_err().to_string();
+        assert!(err.contains("Line 2: bank 2 is defined but bank 1 isn't"), "{}", err);
+        let err = a.assemble_bytes(".bank 0, $9000, $8000\n").unwrap_err().to_string();
+        assert!(err.contains("Line 1: bank 0 at $8000 with size $9000 doesn't fit"), "{}", err);
+        let err = a.assemble_bytes(".bank 0, 0, $8000\n").unwrap_err().to_string();
+        assert!(err.contains("Line 1: bank 0 size must be greater than 0"), "{}", err);
+        let err = a.assemble_bytes(".bank 0, 2, $8000\n  NOP\n  NOP\n  NOP\n").unwrap_err().to_string();
+        assert!(err.contains("Line 4: segment \"BANK0\" overflows memory area \"BANK0\" ($8000-$8001) by 1 byte"), "{}", err);
+        let err = a.assemble_bytes("*=$0800\nhere:\n  LDA #bank(here)\n").unwrap_err().to_string();
+        assert!(err.contains("'here' isn't in a bank"), "{}", err);
+        let err = a.assemble_bytes(".bank 0, $100, $8000\nx:\n.if bank(x) == 0\n.endif\n").unwrap_err().to_string();
+        assert!(err.contains("depends on 'bank(x)'"), "{}", err);
+    }
+}
+
+#[cfg(test)]
+mod label_line_tests {
+    use super::*;
+
+    #[test]
+    fn labels_before_any_statement() {
+        let src = "*=$0800
+start: LDX #0
+loop: LDA msg,X
+  BEQ done
+  INX
+  JMP loop
+done: RTS
+msg: .string \"HI\"
+ptr: .res 2
+table: .rept 2
+  .byte 7
+.endr
+end:
+";
+        let mut a = Assembler6502::new();
+        let bytes = a.assemble_bytes(src).unwrap();
+        assert_eq!(&bytes[..3], &[0xA2, 0x00, 0xBD]);
+        assert_eq!(a.lookup("loop"), Some(0x0802));
+        assert_eq!(a.lookup("done"), Some(0x080B));
+        assert_eq!(a.lookup("msg"), Some(0x080C));
+        assert_eq!(a.lookup("ptr"), Some(0x080E));
+        assert_eq!(a.lookup("table"), Some(0x0810));
+        assert_eq!(a.lookup("end"), Some(0x0812));
+        assert!(a.assemble_bytes("A: NOP\n").is_err());
+    }
+
+    #[test]
+    fn a_label_on_a_block_end_follows_the_block() {
+        let src = "*=$1000
+.rept 2
+  NOP
+after: .endr
+";
+        let mut a = Assembler6502::new();
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![0xEA, 0xEA]);
+        assert_eq!(a.lookup("after"), Some(0x1002));
+    }
+
+    #[test]
+    fn colonless_labels_in_column_0() {
+        let src = "        *=$0800
+COUNT = 3
+start   LDX #COUNT
+loop    DEX
+        BNE loop
+done
+        RTS
+";
+        let mut a = Assembler6502::new();
+        assert!(a.assemble_bytes(src).is_err());
+        a.set_dialect(Dialect { colonless_labels: true, ..Dialect::default() });
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![0xA2, 0x03, 0xCA, 0xD0, 0xFD, 0x60]);
+        assert_eq!(a.lookup("loop"), Some(0x0802));
+        assert_eq!(a.lookup("done"), Some(0x0805));
+        // Indented words are still instructions, and colons still work
+        assert_eq!(a.assemble_bytes("  NOP\nhere: NOP\n").unwrap(), vec![0xEA, 0xEA]);
+    }
+
+    #[test]
+    fn instructions_directives_and_macros_in_column_0() {
+        let mut a = Assembler6502::new();
+        a.set_dialect(Dialect::acme());
+        assert_eq!(a.assemble_bytes("*=$1000\nNOP\nrts\n").unwrap(), vec![0xEA, 0x60]);
+        assert_eq!(a.lookup("NOP"), None);
+        a.set_dialect(Dialect::tass64());
+        assert_eq!(a.assemble_bytes("*=$1000\nlda #1\nloop dex\n").unwrap(), vec![0xA9, 0x01, 0xCA]);
+        assert_eq!(a.lookup("loop"), Some(0x1002));
+        a.set_dialect(Dialect::dasm());
+        let src = "ORG $1000\n  MAC twice\n  NOP\n  NOP\n  ENDM\ntwice\nend twice\n";
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![0xEA; 4]);
+        assert_eq!(a.lookup("end"), Some(0x1002));
+    }
+
+    #[test]
+    fn colonless_labels_in_macro_bodies_are_local() {
+        let src = ".macro wait n
+        LDX #n
+again   DEX
+        BNE again
+.endm
+        wait 1
+        wait 2
+";
+        let mut a = Assembler6502::new();
+        a.set_dialect(Dialect { colonless_labels: true, ..Dialect::default() });
+        assert_eq!(a.assemble_bytes(src).unwrap(), vec![0xA2, 0x01, 0xCA, 0xD0, 0xFD, 0xA2, 0x02, 0xCA, 0xD0, 0xFD]);
+    }
+}
+
+#[cfg(test)]
+mod statement_tests {
+    use super::*;
+
+    fn err(src: &str) -> String {
+        Assembler6502::new().assemble_bytes(src).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn several_statements_on_a_line() {
+        let src = "*=$1000
+init: LDA #0 : STA $D020 : STA $D021
+.text \"a:b;c\" : .byte ':' ; a comment: with a colon
+loop: DEX : BNE loop
+";
+        let mut a = Assembler6502::new();
+        let bytes = a.assemble_bytes(src).unwrap();
+        assert_eq!(&bytes[..8], &[0xA9, 0x00, 0x8D, 0x20, 0xD0, 0x8D, 0x21, 0xD0]);
+        assert_eq!(&bytes[8..14], b"a:b;c:");
+        assert_eq!(&bytes[14..], &[0xCA, 0xD0, 0xFD]);
+        assert_eq!(a.lookup("loop"), Some(0x100E));
+    }
+
+    #[test]
+    fn blocks_on_one_line() {
+        let src = ".macro twice op : op : op : .endm
+.rept 2 : NOP : .endr
+twice INX
+";
+        assert_eq!(Assembler6502::new().assemble_bytes(src).unwrap(), vec![0xEA, 0xEA, 0xE8, 0xE8]);
+    }
+
+    #[test]
+    fn block_comments() {
+        let src = "LDA #1 /* inline */ : NOP
+/* disabled:
+  LDA #2 ; a ; doesn't hide the end */ INX
+.comment
+  this isn't code: LDA #3
+.endcomment
+INY
+";
+        assert_eq!(Assembler6502::new().assemble_bytes(src).unwrap(), vec![0xA9, 0x01, 0xEA, 0xE8, 0xC8]);
+    }
+
+    #[test]
+    fn line_numbers_after_comments_and_statements() {
+        assert!(err("/*\n*/\nNOP : .align\n").contains("Line 3: NOP : .align - .align expects"));
+        assert!(err(".comment\nx\n.endcomment\nNOP\nNOP : BAD #1\n").contains("Line 5: $0082: BAD #1"));
+        assert!(err("NOP\n/* open\nNOP\n").contains("Line 2: /* has no matching */"));
+        assert!(err("NOP\n.comment\n").contains("Line 2: .comment has no matching .endcomment"));
+        assert!(err("NOP : .endcomment\n").contains(".endcomment without .comment"));
+    }
+
+    #[test]
+    fn mnemonics_are_not_labels() {
+        // Without the space "INX:INY" would be a label INX and just INY
+        assert!(err("INX:INY\n").contains("Line 1: INX:INY - 'INX' is an instruction and can't be used as a symbol name"));
+        assert!(err("LDA: NOP\n").contains("'LDA' is an instruction"));
+        assert!(err("nop = 1\n").contains("'nop' is an instruction"));
+        assert!(err("JMP LDA\n").contains("Undefined label: LDA"));
+        assert_eq!(Assembler6502::new().assemble_bytes("INX : INY\n").unwrap(), vec![0xE8, 0xC8]);
+    }
+}
+
+#[cfg(test)]
+mod dialect_tests {
+    use super::*;
+
+    fn assemble(dialect: Dialect, src: &str) -> (Vec<u8>, Assembler6502) {
+        let mut a = Assembler6502::new();
+        a.set_dialect(dialect);
+        let bytes = a.assemble_bytes(src).unwrap_or_else(|e| panic!("{}", e));
+        (bytes, a)
+    }
+
+    #[test]
+    fn ca65_sources() {
+        let src = "        .setcpu \"6502\"
+        .org $C000
+ptr     := $FB
+main:   LDX #2
+@loop:  DEX
+        BNE @loop
+other:  LDY #1
+@loop:  DEY
+        BNE @loop
+        .byt 1, 2
+        .addr main
+";
+        let (bytes, a) = assemble(Dialect::ca65(), src);
+        assert_eq!(bytes, vec![0xA2, 0x02, 0xCA, 0xD0, 0xFD, 0xA0, 0x01, 0x88, 0xD0, 0xFD, 0x01, 0x02, 0x00, 0xC0]);
+        assert_eq!(a.lookup("main::loop"), Some(0xC002));
+        assert_eq!(a.lookup("other::loop"), Some(0xC007));
+        assert_eq!(a.lookup("ptr"), Some(0xFB));
+    }
+
+    #[test]
+    fn acme_sources() {
+        let src = "!to \"out.prg\", cbm
+*=$0801
+start   lda #%..##..##
+        !by 1, 2 : !wo start
+        !text \"HI\"
+";
+        let (bytes, a) = assemble(Dialect::acme(), src);
+        assert_eq!(bytes, vec![0xA9, 0x33, 0x01, 0x02, 0x01, 0x08, b'H', b'I']);
+        assert_eq!(a.lookup("start"), Some(0x0801));
+    }
+
+    #[test]
+    fn dasm_sources() {
+        let src = "        processor 6502
+        SEG code
+COLS    EQU 017
+        ORG $F000
+reset   ldx #COLS
+        HEX 1A 02ff
+        DC.B 1, 2
+        DC.W reset
+        DS 2, $EA
+        MAC twice
+        nop
+        nop
+        ENDM
+        twice
+";
+        let (bytes, a) = assemble(Dialect::dasm(), src);
+        assert_eq!(bytes, vec![0xA2, 0x0F, 0x1A, 0x02, 0xFF, 0x01, 0x02, 0x00, 0xF0, 0xEA, 0xEA, 0xEA, 0xEA]);
+        assert_eq!(a.lookup("COLS"), Some(15));
+        let mut a = Assembler6502::new();
+        a.set_dialect(Dialect::dasm());
+        let err = a.assemble_bytes("        SEG.U vars\n").unwrap_err().to_string();
+        assert!(err.contains("SEG.U"), "{}", err);
+    }
+
+    #[test]
+    fn tass64_sources() {
+        let src = "*=$1000
+wait    LDX #3
+_loop   DEX
+        BNE _loop
+        .null \"A\"
+count   := 2
+        .char count
+";
+        let (bytes, a) = assemble(Dialect::tass64(), src);
+        assert_eq!(bytes, vec![0xA2, 0x03, 0xCA, 0xD0, 0xFD, b'A', 0x00, 0x02]);
+        assert_eq!(a.lookup("wait::_loop"), Some(0x1002));
+    }
+
+    #[test]
+    fn kick_sources() {
+        let src = ".pc = $0810 // the start
+.const BORDER = $D020
+.label zp = $FB
+start: LDA #0 ; also a comment
+       STA BORDER
+       .by 1 /* inline */ .wo start
+";
+        let mut a = Assembler6502::new();
+        a.set_dialect(Dialect::kick());
+        assert!(a.assemble_bytes(src).is_err());
+        let src = src.replace(" .wo", " : .wo");
+        let (bytes, a) = assemble(Dialect::kick(), &src);
+        assert_eq!(bytes, vec![0xA9, 0x00, 0x8D, 0x20, 0xD0, 0x01, 0x10, 0x08]);
+        assert_eq!(a.lookup("zp"), Some(0xFB));
+    }
+
+    #[test]
+    fn nesasm_sources() {
+        let src = "  .org $8000
+table .db $01, $02
+  .dw table
+";
+        let (bytes, a) = assemble(Dialect::nesasm(), src);
+        assert_eq!(bytes, vec![0x01, 0x02, 0x00, 0x80]);
+        assert_eq!(a.lookup("table"), Some(0x8000));
+    }
+
+    #[test]
+    fn local_labels_in_macros_are_per_expansion() {
+        let src = ".macro wait n
+        LDX #n
+@loop:  DEX
+        BNE @loop
+.endmacro
+main:   wait 1
+        wait 2
+        JMP @done
+@done:  RTS
+";
+        let (bytes, a) = assemble(Dialect::ca65(), src);
+        assert_eq!(&bytes[..10], &[0xA2, 0x01, 0xCA, 0xD0, 0xFD, 0xA2, 0x02, 0xCA, 0xD0, 0xFD]);
+        assert_eq!(a.lookup("main::done"), Some(0x008D));
+    }
+
+    #[test]
+    fn the_native_syntax_is_unchanged() {
+        let mut a = Assembler6502::new();
+        assert!(a.assemble_bytes("!byte 1\n").is_err());
+        assert!(a.assemble_bytes("@x: NOP\n").is_err());
+        assert_eq!(a.assemble_bytes("* = $1000\nNOP\n").unwrap(), vec![0xEA]);
+    }
+}
+
+#[cfg(test)]
+mod export_tests {
+    use super::*;
+
+    const PROGRAM: &str = "ptr = $FB
+count = 3
+count = count + 1
+        *= $10
+zpvar:  .byte 0
+        *= $0200
+start:  LDX #count
+        LDA #<table
+        STA ptr
+        LDA zpvar
+        STA >$12
+loop:   LDA table,X
+        ASL A
+        DEX
+        BNE far
+        .fill 200, $EA
+far:    JMP (vector)
+vector: .word start, table+1
+table:  .byte 1, 'A', $1FF, ptr == $FB
+        .rta start
+        .dbyte $1234
+        .lobytes start, table
+        .text \"HI\"
+        .align 4, $FF
+        .skip 2
+        .byte * - start
+";
+
+    const TARGETS: [ExportTarget; 3] = [ExportTarget::Ca65, ExportTarget::Acme, ExportTarget::Tass64];
+
+    /// Assemble `src` with `reserved` ranges, export it for `target` and
+    /// assemble the export again, without the ranges, reading it with the
+    /// target's dialect.
+    fn round_trip(src: &str, reserved: &[(u16, u16)], target: ExportTarget) -> (Assembler6502, Assembler6502, String) {
+        let mut a = Assembler6502::new();
+        for &(start, end) in reserved {
+            a.add_reserved_range(start, end).unwrap();
+        }
+        let (_, items) = a.assemble_full(src).unwrap_or_else(|e| panic!("{}", e));
+        let text = a.export_source(&items, target).unwrap();
+        let b = reassemble(&text, target);
+        (a, b, text)
+    }
+
+    /// Assemble the export `text` for `target`. ca65 output is placed the
+    /// way its linker would: from the first `.org`, and each `ORG_XXXX`
+    /// segment at its address.
+    fn reassemble(text: &str, target: ExportTarget) -> Assembler6502 {
+        let mut b = Assembler6502::new();
+        b.set_dialect(target.dialect());
+        let address = |l: &str| u16::from_str_radix(l.trim_end_matches('"'), 16).unwrap();
+        let start = text.lines().find_map(|l| l.strip_prefix("        .org $")).map_or(0, address);
+        b.set_origin(start);
+        let mut segments: Vec<u16> =
+            text.lines().filter_map(|l| l.strip_prefix("        .segment \"ORG_")).map(address).collect();
+        if target == ExportTarget::Ca65 && !segments.is_empty() {
+            segments.push(start);
+            segments.sort_unstable();
+            let mut config = MemoryConfig::new();
+            for (i, &at) in segments.iter().enumerate() {
+                let end = segments.get(i + 1).map_or(0x10000, |&next| next as u32);
+                let name = if at == start { "CODE".to_string() } else { format!("ORG_{:04X}", at) };
+                config.add_area(&name, at, end - at as u32, None).unwrap();
+                config.add_segment(&name, &name, SegmentKind::Code).unwrap();
+            }
+            b.set_memory_config(config);
+        }
+        b.assemble_image(text).unwrap_or_else(|e| panic!("{:?}: {}\n{}", target, e, text));
+        b
+    }
+
+    /// `b`'s image is `a`'s. ca65 has no way to skip output, so there the
+    /// gaps are compared as the zeros its `.res` writes.
+    fn assert_same_image(a: &Assembler6502, b: &Assembler6502, target: ExportTarget, text: &str) {
+        if target == ExportTarget::Ca65 {
+            assert_eq!(a.image().start(), b.image().start(), "{}", text);
+            assert_eq!(a.image().to_flat(0), b.image().to_flat(0), "{}", text);
+        } else {
+            assert_eq!(a.image(), b.image(), "{:?}:\n{}", target, text);
+        }
+    }
+
+    #[test]
+    fn exported_programs_assemble_to_the_same_image() {
+        for target in TARGETS {
+            let (a, b, text) = round_trip(PROGRAM, &[(0x0220, 0x022F)], target);
+            assert_same_image(&a, &b, target, &text);
+            assert_eq!(a.lookup("far"), b.lookup("far"));
+        }
+    }
+
+    #[test]
+    fn expansions_bridges_and_address_sizes_are_written_out() {
+        let (_, _, ca65) = round_trip(PROGRAM, &[(0x0220, 0x022F)], ExportTarget::Ca65);
+        for line in [
+            "count .set count+$01", "start:", "        LDA z:zpvar", "        STA a:$12", "        STA ptr",
+            "        BEQ __skip_0", "        JMP far", "__skip_0:", "        JMP $0230", "        .res $18, $00",
+            "        .word start-1", "        .res $02",
+            "        .org $10", "        .res $01EF, $00", "        .byte $01, $41, <$01FF, $01",
+        ] {
+            assert!(ca65.lines().any(|l| l == line), "{:?} missing from\n{}", line, ca65);
+        }
+        let (_, _, acme) = round_trip(PROGRAM, &[(0x0220, 0x022F)], ExportTarget::Acme);
+        for line in ["!set count = $03", "start", "        LDA+1 zpvar", "        STA+2 $12", "        !be16 $1234"] {
+            assert!(acme.lines().any(|l| l == line), "{:?} missing from\n{}", line, acme);
+        }
+        let (_, _, tass) = round_trip(PROGRAM, &[(0x0220, 0x022F)], ExportTarget::Tass64);
+        for line in ["count := $03", "        LDA @b zpvar", "        STA @w $12", "        .rta start"] {
+            assert!(tass.lines().any(|l| l == line), "{:?} missing from\n{}", line, tass);
+        }
+    }
+
+    #[test]
+    fn scoped_names_logical_blocks_and_predefined_symbols() {
+        let src = ".macro wait n
+    LDX #n
+loop:
+    DEX
+    BNE loop
+.endm
+        *= $1000
+main:   wait 2
+        wait 3
+        LDA #COLOR
+        .pseudopc $C000
+copy:   INX
+        BNE copy
+        .realpc
+        JMP copy
+";
+        for target in TARGETS {
+            let mut a = Assembler6502::new();
+            a.define_symbol("COLOR", 7);
+            let (_, items) = a.assemble_full(src).unwrap();
+            let text = a.export_source(&items, target).unwrap();
+            assert!(text.contains("BNE wait__1__loop") && text.starts_with("COLOR = $07\n"), "{}", text);
+            let b = reassemble(&text, target);
+            assert_same_image(&a, &b, target, &text);
+            assert_eq!(b.lookup("copy"), Some(0xC000));
+        }
+    }
+
+    #[test]
+    fn ca65_org_sets_the_pc_without_moving_the_output() {
+        let mut a = Assembler6502::new();
+        a.set_dialect(Dialect::ca65());
+        a.set_origin(0x0800);
+        let image = a.assemble_image(".org $1000\nstart: JMP start\n.org $C000\nirq: RTI\n.reloc\n.reloc\nNOP\n").unwrap();
+        assert_eq!(image.chunks().len(), 1);
+        assert_eq!(image.byte_at(0x0800), Some(0x4C));
+        assert_eq!(image.byte_at(0x0803), Some(0x40));
+        assert_eq!(image.byte_at(0x0804), Some(0xEA));
+        assert_eq!((a.lookup("start"), a.lookup("irq")), (Some(0x1000), Some(0xC000)));
+        // Exported, a gap between regions is filled, as ca65's .org can't
+        // leave one, and code placed lower gets a segment of its own
+        let mut a = Assembler6502::new();
+        let (_, items) = a.assemble_full("*=$0800\nNOP\n*=$0804\nRTS\n*=$0400\nBRK\n").unwrap();
+        let text = a.export_source(&items, ExportTarget::Ca65).unwrap();
+        let expected = "        .org $0800\n        NOP\n        .res $03, $00\n        RTS\n        .segment \"ORG_0400\"\n";
+        assert!(text.contains(expected), "{}", text);
+        let b = reassemble(&text, ExportTarget::Ca65);
+        assert_same_image(&a, &b, ExportTarget::Ca65, &text);
+    }
+
+    #[test]
+    fn dialects_read_address_size_markers() {
+        let mut a = Assembler6502::new();
+        a.set_dialect(Dialect::ca65());
+        assert_eq!(a.assemble_bytes("LDA a:$12,X\nLDA z:$12\n").unwrap(), vec![0xBD, 0x12, 0x00, 0xA5, 0x12]);
+        a.set_dialect(Dialect::acme());
+        assert_eq!(a.assemble_bytes(" lda+2 $12 : lda+1 $12\n").unwrap(), vec![0xAD, 0x12, 0x00, 0xA5, 0x12]);
+        a.set_dialect(Dialect::tass64());
+        assert_eq!(a.assemble_bytes(" lda @w $12\n").unwrap(), vec![0xAD, 0x12, 0x00]);
+    }
+}
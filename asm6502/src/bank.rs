@@ -0,0 +1,110 @@
+//! Bank-switched layouts described in the source.
+//!
+//! `.bank n, size, cpu_base` starts bank `n`: `size` bytes that the CPU
+//! sees at `cpu_base` while the bank is switched in. Banks share their
+//! address window, so each has its own PC, and they are written to the
+//! output one after another in bank order, each padded to its full size,
+//! which is the layout of a ROM image. `.bank n` on its own goes back to
+//! a bank defined earlier and continues where it left off.
+//!
+//! Under the hood a program with banks gets a memory configuration of
+//! its own: one banked area and one segment per bank, both named
+//! `BANKn`.
+
+use std::collections::BTreeMap;
+
+use crate::parser::expression::Expr;
+use crate::parser::lexer::Item;
+use crate::segment::{MemoryConfig, SegmentKind};
+use crate::source::SourceLoc;
+
+/// The byte banks are padded to their full size with.
+const BANK_FILL: u8 = 0x00;
+
+/// The area and segment name of bank `n`.
+fn bank_name(n: u32) -> String {
+    format!("BANK{}", n)
+}
+
+struct Window {
+    size: u32,
+    base: u16,
+    loc: SourceLoc,
+}
+
+/// Replace each `.bank` with a switch to its segment and build the
+/// memory configuration that places the banks.
+pub(crate) fn link_banks(items: Vec<Item>) -> Result<(MemoryConfig, Vec<Item>), String> {
+    let mut windows: BTreeMap<u32, Window> = BTreeMap::new();
+    let mut in_bank = false;
+    let mut loc = SourceLoc::default();
+    let mut out = Vec::with_capacity(items.len());
+    for item in items {
+        match &item {
+            Item::Line(l) => loc = l.clone(),
+            Item::Bank { number, window } => {
+                let number = fixed(number);
+                match (window, windows.get(&number)) {
+                    (Some((size, base)), None) => {
+                        let (size, base) = (fixed(size), fixed(base));
+                        if size == 0 {
+                            return Err(format!("{}: bank {} size must be greater than 0", loc, number));
+                        }
+                        if base.saturating_add(size) > 0x10000 {
+                            return Err(format!(
+                                "{}: bank {} at ${:04X} with size ${:X} doesn't fit the address space",
+                                loc, number, base, size
+                            ));
+                        }
+                        windows.insert(number, Window { size, base: base as u16, loc: loc.clone() });
+                    }
+                    (Some((size, base)), Some(prev)) if (fixed(size), fixed(base)) != (prev.size, prev.base as u32) => {
+                        return Err(format!(
+                            "{}: bank {} was defined at {} as ${:X} bytes at ${:04X}",
+                            loc, number, prev.loc, prev.size, prev.base
+                        ));
+                    }
+                    (None, None) => {
+                        return Err(format!(
+                            "{}: bank {} isn't defined yet; give its size and CPU address: .bank {}, size, address",
+                            loc, number, number
+                        ));
+                    }
+                    _ => {}
+                }
+                in_bank = true;
+                out.push(Item::Segment(bank_name(number)));
+                continue;
+            }
+            Item::Constant(..) | Item::Assert { .. } | Item::Error(_) | Item::Warning(_) => {}
+            _ if !in_bank => {
+                return Err(format!("{}: code before the first .bank; in a banked program everything goes in a bank", loc));
+            }
+            _ => {}
+        }
+        out.push(item);
+    }
+
+    let mut config = MemoryConfig::new();
+    for (expected, (&number, window)) in windows.iter().enumerate() {
+        if number != expected as u32 {
+            return Err(format!(
+                "{}: bank {} is defined but bank {} isn't; banks are numbered from 0",
+                window.loc, number, expected
+            ));
+        }
+        let name = bank_name(number);
+        // Checked above, so neither can fail
+        config.add_banked_area(&name, number, window.base, window.size, Some(BANK_FILL)).unwrap();
+        config.add_segment(&name, &name, SegmentKind::Code).unwrap();
+    }
+    Ok((config, out))
+}
+
+/// A bank number, size or address; expansion has already evaluated it.
+fn fixed(expr: &Expr) -> u32 {
+    match expr {
+        Expr::Number(n) => *n,
+        _ => unreachable!("bank operands are evaluated during expansion"),
+    }
+}
@@ -0,0 +1,562 @@
+//! Source syntax options for programs written for other assemblers
+//!
+//! A dialect doesn't add a parser of its own: each statement is
+//! translated into this assembler's syntax before it is parsed, so
+//! `!byte 1, 2` (ACME) and `DC.B 1, 2` (DASM) both become `.byte 1, 2`.
+
+use std::borrow::Cow;
+
+use crate::parser::lexer::strip_directive;
+use crate::parser::text::literal_end;
+use crate::parser::ExpressionParser;
+
+/// What a dialect's directive becomes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DirectiveMap {
+    /// Replaced by this native directive, keeping its operands: `!byte`
+    /// is `.byte`, `ORG` is `*=`.
+    Native(&'static str),
+    /// Introduces a native statement: `.const X = 1` (Kick Assembler) is
+    /// `X = 1`.
+    Prefix,
+    /// Like `Native`, for a directive whose block is in braces:
+    /// `!pseudopc $C000 {` (ACME) is `.pseudopc $C000`. The closing `}`
+    /// is a directive of its own.
+    Braced(&'static str),
+    /// Hex digit pairs, `HEX 01 02FF` (DASM), emitted as bytes.
+    HexBytes,
+    /// Assembler settings with no effect on the output here (`.setcpu`,
+    /// `PROCESSOR`); the statement is skipped.
+    Ignore,
+    /// A directive this assembler has no equivalent for; using it is an
+    /// error with this explanation.
+    Unsupported(&'static str),
+}
+
+/// How source lines are read. The default is this assembler's own syntax;
+/// the constructors give the settings for other assemblers' sources.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Dialect {
+    /// A name starting in column 0 is a label even without a colon
+    /// (`loop LDA #1`), as in DASM, ACME and 64tass sources, unless it is
+    /// an instruction, a directive or a macro defined before it.
+    pub colonless_labels: bool,
+    /// Labels starting with this character are local to the label before
+    /// them: with `@`, `@loop` after `main:` is `main::loop`.
+    pub local_label_prefix: Option<char>,
+    /// A line comment marker besides `;`, e.g. `//`.
+    pub line_comment: Option<&'static str>,
+    /// Directives and what they become, matched case-insensitively and
+    /// in order, so a longer name must come before its prefix (`DC.B`
+    /// before `DC`).
+    pub directives: Vec<(&'static str, DirectiveMap)>,
+    /// Words that define a constant like `=`: `SIZE EQU 4`, `X := 1`.
+    pub equates: Vec<&'static str>,
+    /// Numbers with a leading zero are octal (`017` is 15), as in DASM.
+    pub leading_zero_octal: bool,
+    /// Binary numbers may use `.` for 0 and `#` for 1 (`%..##..##`), as
+    /// in ACME, for drawing sprites and characters.
+    pub binary_dots: bool,
+    /// The markers that force zero-page and absolute addressing, as
+    /// `(zero page, absolute)`: `LDA z:ptr` in ca65, `LDA+1 ptr` in ACME
+    /// and `LDA @b ptr` in 64tass are the native `LDA <ptr`.
+    pub address_size_markers: Option<(&'static str, &'static str)>,
+    /// `.pseudopc` needs no `.realpc`: a block ends at the next
+    /// `.pseudopc`, `*=` or `.segment` and at the end of the source, and
+    /// `.realpc` outside one does nothing. This is ca65's `.org`, which
+    /// sets the PC but never moves the output.
+    pub open_logical_blocks: bool,
+}
+
+impl Dialect {
+    /// ca65 (cc65): `.byt`, `.addr`, `.org`/`.reloc`, `.res`, `:=`, `.set`,
+    /// `@` cheap local labels, `z:`/`a:` address sizes, and the segment
+    /// shorthands `.code`, `.rodata`, ... As in ca65, `.org` only sets the
+    /// PC; where the output goes is up to the start address or memory
+    /// configuration, which stand in for the linker configuration.
+    pub fn ca65() -> Self {
+        use DirectiveMap::*;
+        Dialect {
+            local_label_prefix: Some('@'),
+            directives: vec![
+                (".byt", Native(".byte")),
+                (".addr", Native(".word")),
+                (".dbyt", Native(".dbyte")),
+                (".org", Native(".pseudopc")),
+                (".reloc", Native(".realpc")),
+                (".repeat", Native(".rept")),
+                (".endrepeat", Native(".endrept")),
+                (".endrep", Native(".endrept")),
+                (".endmac", Native(".endmacro")),
+                (".code", Native(".segment \"CODE\"")),
+                (".rodata", Native(".segment \"RODATA\"")),
+                (".data", Native(".segment \"DATA\"")),
+                (".bss", Native(".segment \"BSS\"")),
+                (".zeropage", Native(".segment \"ZEROPAGE\"")),
+                (".setcpu", Ignore),
+                (".p02", Ignore),
+                (".feature", Ignore),
+                (".debuginfo", Ignore),
+                (".export", Ignore),
+                (".exportzp", Ignore),
+                (".global", Ignore),
+                (".globalzp", Ignore),
+                (".smart", Ignore),
+            ],
+            equates: vec![":=", ".set"],
+            address_size_markers: Some(("z:", "a:")),
+            open_logical_blocks: true,
+            ..Dialect::default()
+        }
+    }
+
+    /// ACME: `!byte`/`!by`/`!8`, `!word`/`!wo`/`!16`, `!text`, `!fill`,
+    /// `!source`, `!binary`, `!set`, `!pseudopc … { }`, `LDA+2` address
+    /// sizes, colon-less labels and `%..##..` binary.
+    pub fn acme() -> Self {
+        use DirectiveMap::*;
+        Dialect {
+            colonless_labels: true,
+            directives: vec![
+                ("!byte", Native(".byte")),
+                ("!by", Native(".byte")),
+                ("!08", Native(".byte")),
+                ("!8", Native(".byte")),
+                ("!word", Native(".word")),
+                ("!wo", Native(".word")),
+                ("!16", Native(".word")),
+                ("!be16", Native(".dbyte")),
+                ("!32", Native(".dword")),
+                ("!text", Native(".text")),
+                ("!tx", Native(".text")),
+                ("!fill", Native(".fill")),
+                ("!fi", Native(".fill")),
+                ("!skip", Native(".skip")),
+                ("!source", Native(".include")),
+                ("!src", Native(".include")),
+                ("!binary", Native(".incbin")),
+                ("!bin", Native(".incbin")),
+                ("!error", Native(".error")),
+                ("!warn", Native(".warning")),
+                ("!set", Prefix),
+                ("!pseudopc", Braced(".pseudopc")),
+                ("}", Native(".realpc")),
+                ("!to", Ignore),
+                ("!cpu", Ignore),
+                ("!sl", Ignore),
+            ],
+            binary_dots: true,
+            address_size_markers: Some(("+1", "+2")),
+            ..Dialect::default()
+        }
+    }
+
+    /// 64tass: `.char`, `.addr`, `.null`, `.ptext`, `.binary`, `:=`,
+    /// `@b`/`@w` address sizes, colon-less labels and `_` local labels.
+    pub fn tass64() -> Self {
+        use DirectiveMap::*;
+        Dialect {
+            colonless_labels: true,
+            local_label_prefix: Some('_'),
+            directives: vec![
+                (".char", Native(".byte")),
+                (".addr", Native(".word")),
+                (".dint", Native(".dword")),
+                (".null", Native(".asciiz")),
+                (".ptext", Native(".pstring")),
+                (".binary", Native(".incbin")),
+                (".next", Native(".endrept")),
+                (".cpu", Ignore),
+            ],
+            equates: vec![":="],
+            address_size_markers: Some(("@b", "@w")),
+            ..Dialect::default()
+        }
+    }
+
+    /// DASM: `ORG`, `HEX`, `DC.B`/`DC.W`, `DS`, `EQU`, `MAC`/`ENDM`,
+    /// `REPEAT`/`REPEND`, `IF`/`ENDIF`, colon-less labels in column 0
+    /// and octal numbers with a leading zero.
+    pub fn dasm() -> Self {
+        use DirectiveMap::*;
+        Dialect {
+            colonless_labels: true,
+            directives: vec![
+                ("ORG", Native("*=")),
+                ("RORG", Native(".pseudopc")),
+                ("REND", Native(".realpc")),
+                ("HEX", HexBytes),
+                ("DC.B", Native(".byte")),
+                ("DC.W", Native(".word")),
+                ("DC", Native(".byte")),
+                ("BYTE", Native(".byte")),
+                ("WORD", Native(".word")),
+                ("DS.B", Native(".ds")),
+                ("DS.W", Unsupported("word storage (DS.W) isn't supported; reserve twice the bytes with DS.B")),
+                ("DS.L", Unsupported("long storage (DS.L) isn't supported; reserve four times the bytes with DS.B")),
+                ("DS", Native(".ds")),
+                ("ALIGN", Native(".align")),
+                ("INCLUDE", Native(".include")),
+                ("INCBIN", Native(".incbin")),
+                ("MAC", Native(".macro")),
+                ("MACRO", Native(".macro")),
+                ("ENDM", Native(".endm")),
+                ("REPEAT", Native(".rept")),
+                ("REPEND", Native(".endr")),
+                ("IFCONST", Native(".ifdef")),
+                ("IFNCONST", Native(".ifndef")),
+                ("IF", Native(".if")),
+                ("ELSE", Native(".else")),
+                ("ENDIF", Native(".endif")),
+                ("EIF", Native(".endif")),
+                ("PROCESSOR", Ignore),
+                ("SEG.U", Unsupported("uninitialized segments (SEG.U) aren't supported; define RAM variables as constants")),
+                ("SEG", Ignore),
+            ],
+            equates: vec!["EQU", "SET"],
+            leading_zero_octal: true,
+            ..Dialect::default()
+        }
+    }
+
+    /// Kick Assembler: `//` comments, `.by`/`.wo`/`.te`, `.const`,
+    /// `.var` and `.label` definitions, `.pc = addr` and `.import`.
+    pub fn kick() -> Self {
+        use DirectiveMap::*;
+        Dialect {
+            line_comment: Some("//"),
+            directives: vec![
+                (".by", Native(".byte")),
+                (".wo", Native(".word")),
+                (".te", Native(".text")),
+                (".const", Prefix),
+                (".var", Prefix),
+                (".label", Prefix),
+                (".pc", Native("*")),
+                (".import source", Native(".include")),
+                (".import binary", Native(".incbin")),
+                (".cpu", Ignore),
+            ],
+            ..Dialect::default()
+        }
+    }
+
+    /// NESASM, and the `.db`/`.dw`/`.ds` data directives WLA-DX shares:
+    /// `.org`, colon-less labels.
+    pub fn nesasm() -> Self {
+        use DirectiveMap::*;
+        Dialect {
+            colonless_labels: true,
+            directives: vec![
+                (".db", Native(".byte")),
+                (".dw", Native(".word")),
+                (".org", Native("*=")),
+            ],
+            ..Dialect::default()
+        }
+    }
+
+    /// `statement` in native syntax: its directive mapped, an equate
+    /// turned into `=`, and dialect numbers rewritten. Ignored
+    /// directives become an empty statement.
+    pub(crate) fn translate<'a>(&self, statement: &'a str) -> Result<Cow<'a, str>, String> {
+        let mut text = Cow::Borrowed(statement);
+        if let Some((rest, map)) = self
+            .directives
+            .iter()
+            .find_map(|(name, map)| strip_directive(statement, name).map(|rest| (rest.trim(), map)))
+        {
+            text = match map {
+                DirectiveMap::Native(native) => Cow::Owned(format!("{} {}", native, rest)),
+                DirectiveMap::Braced(native) => {
+                    let operands = rest.strip_suffix('{').ok_or("the block's { must end the line")?;
+                    Cow::Owned(format!("{} {}", native, operands.trim_end()))
+                }
+                DirectiveMap::Prefix => Cow::Borrowed(rest),
+                DirectiveMap::HexBytes => Cow::Owned(hex_bytes(rest)?),
+                DirectiveMap::Ignore => return Ok(Cow::Borrowed("")),
+                DirectiveMap::Unsupported(why) => return Err(why.to_string()),
+            };
+        } else if let Some((name, value)) = self.equate(statement) {
+            text = Cow::Owned(format!("{} = {}", name, value));
+        }
+        Ok(match self.translate_numbers(&text) {
+            Cow::Owned(numbers) => Cow::Owned(numbers),
+            Cow::Borrowed(_) => text,
+        })
+    }
+
+    /// `NAME EQU value` as its name and value.
+    fn equate<'a>(&self, statement: &'a str) -> Option<(&'a str, &'a str)> {
+        self.equates.iter().find_map(|eq| {
+            let (name, value) = if eq.starts_with(|c: char| c.is_ascii_alphabetic() || c == '.') {
+                // A word like EQU is the token after the name: FREQUENCY EQU 5
+                let (name, rest) = statement.split_once(char::is_whitespace)?;
+                let value = strip_directive(rest.trim_start(), eq)?;
+                if !value.is_empty() && !value.starts_with(char::is_whitespace) {
+                    return None;
+                }
+                (name, value)
+            } else {
+                statement.split_once(eq)?
+            };
+            ExpressionParser::is_valid_label(name.trim()).then(|| (name.trim(), value.trim()))
+        })
+    }
+
+    /// Whether `statement` starts with one of the dialect's directives.
+    pub(crate) fn is_directive(&self, statement: &str) -> bool {
+        self.directives.iter().any(|(name, _)| strip_directive(statement, name).is_some())
+    }
+
+    /// Whether the statement after a column-0 name defines it as a
+    /// constant (`SIZE EQU 4`), so the name isn't a label.
+    pub(crate) fn is_equate(&self, rest: &str) -> bool {
+        let word = rest.split_whitespace().next().unwrap_or("");
+        self.equates.iter().any(|eq| word.eq_ignore_ascii_case(eq))
+    }
+
+    /// Octal and dotted binary numbers as native ones, outside literals.
+    fn translate_numbers<'a>(&self, s: &'a str) -> Cow<'a, str> {
+        if !self.leading_zero_octal && !self.binary_dots {
+            return Cow::Borrowed(s);
+        }
+        let bytes = s.as_bytes();
+        let mut out = String::new();
+        let mut copied = 0;
+        let mut i = 0;
+        while i < bytes.len() {
+            let token_start = i == 0 || !is_word_byte(bytes[i - 1]);
+            match bytes[i] {
+                b'"' | b'\'' => {
+                    i = literal_end(s, i);
+                    continue;
+                }
+                b'0' if self.leading_zero_octal && token_start => {
+                    let end = i + s[i..].bytes().take_while(|&b| is_word_byte(b)).count();
+                    let token = &s[i..end];
+                    if token.len() > 1
+                        && token.bytes().all(|b| (b'0'..=b'7').contains(&b))
+                        && let Ok(value) = u32::from_str_radix(token, 8)
+                    {
+                        out.push_str(&s[copied..i]);
+                        out.push_str(&value.to_string());
+                        copied = end;
+                    }
+                    i = end;
+                }
+                b'%' if self.binary_dots && token_start => {
+                    let end = i + 1 + s[i + 1..].bytes().take_while(|b| b"01.#".contains(b)).count();
+                    let digits = &s[i + 1..end];
+                    if digits.contains(['.', '#']) {
+                        out.push_str(&s[copied..=i]);
+                        out.extend(digits.chars().map(|c| if c == '#' || c == '1' { '1' } else { '0' }));
+                        copied = end;
+                    }
+                    i = end;
+                }
+                _ => i += 1,
+            }
+        }
+        if copied == 0 {
+            return Cow::Borrowed(s);
+        }
+        out.push_str(&s[copied..]);
+        Cow::Owned(out)
+    }
+
+    /// `text` with its address size markers as the native `<`/`>`
+    /// operand prefixes: `LDA z:ptr,X` is `LDA <ptr,X`. It runs on the
+    /// whole line, before the `:` of ca65's markers can split it.
+    pub(crate) fn address_sizes<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let Some((zp, abs)) = self.address_size_markers else {
+            return Cow::Borrowed(text);
+        };
+        let bytes = text.as_bytes();
+        let mut out = String::new();
+        let mut copied = 0;
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] == b'"' || bytes[i] == b'\'' {
+                i = literal_end(text, i);
+                continue;
+            }
+            // A marker follows a mnemonic: directly for `+2`, after a space otherwise
+            let mnemonic = (i == 0 || !is_word_byte(bytes[i - 1]))
+                && bytes.len() >= i + 3
+                && bytes[i..i + 3].iter().all(u8::is_ascii_alphabetic)
+                && bytes.get(i + 3).is_none_or(|&b| !is_word_byte(b));
+            if !mnemonic {
+                i += 1;
+                continue;
+            }
+            let after = i + 3;
+            let operand = text[after..].trim_start();
+            let spaced = operand.len() < text.len() - after;
+            let found = [(zp, '<'), (abs, '>')].into_iter().find(|(marker, _)| {
+                operand.get(..marker.len()).is_some_and(|m| m.eq_ignore_ascii_case(marker))
+                    && spaced != marker.starts_with('+')
+                    && (!marker.ends_with(|c: char| c.is_ascii_alphanumeric())
+                        || operand.as_bytes().get(marker.len()).is_none_or(|&b| !is_word_byte(b)))
+            });
+            i = after;
+            if let Some((marker, prefix)) = found {
+                out.push_str(&text[copied..after]);
+                out.push(' ');
+                out.push(prefix);
+                let rest = &operand[marker.len()..];
+                i = text.len() - rest.trim_start().len();
+                copied = i;
+            }
+        }
+        if copied == 0 {
+            return Cow::Borrowed(text);
+        }
+        out.push_str(&text[copied..]);
+        Cow::Owned(out)
+    }
+
+    /// The name a local label is kept under if `label` is one: `@loop`
+    /// is `loop`; with `_` as the prefix, `_loop` stays `_loop`.
+    pub(crate) fn local_name<'a>(&self, label: &'a str) -> Option<&'a str> {
+        let prefix = self.local_label_prefix?;
+        let rest = label.strip_prefix(prefix)?;
+        let name = if prefix == '_' || prefix.is_ascii_alphanumeric() { label } else { rest };
+        ExpressionParser::is_valid_label(name).then_some(name)
+    }
+
+    /// `text` with its local labels qualified by `scope`, the label they
+    /// belong to: `BNE @loop` after `main:` is `BNE main::loop`. Without a
+    /// scope, as in macro and loop bodies, they are plain names.
+    pub(crate) fn localize<'a>(&self, text: &'a str, scope: Option<&str>) -> Cow<'a, str> {
+        let Some(prefix) = self.local_label_prefix else {
+            return Cow::Borrowed(text);
+        };
+        let bytes = text.as_bytes();
+        let mut out = String::new();
+        let mut copied = 0;
+        let mut i = 0;
+        while i < bytes.len() {
+            let c = bytes[i];
+            if c == b'"' || c == b'\'' {
+                i = literal_end(text, i);
+                continue;
+            }
+            let after_scope = i >= 2 && &bytes[i - 2..i] == b"::";
+            let token_start = (i == 0 || !is_word_byte(bytes[i - 1])) && !after_scope;
+            if c as char != prefix || !token_start {
+                i += 1;
+                continue;
+            }
+            let end = i + 1 + text[i + 1..].bytes().take_while(|&b| b.is_ascii_alphanumeric() || b == b'_').count();
+            let Some(name) = self.local_name(&text[i..end]) else {
+                i = end;
+                continue;
+            };
+            out.push_str(&text[copied..i]);
+            // `@m__2::loop`: a body's label already renamed for its copy
+            match scope {
+                Some(scope) if !text[end..].starts_with("::") => {
+                    out.push_str(scope);
+                    out.push_str("::");
+                }
+                _ => {}
+            }
+            out.push_str(name);
+            copied = end;
+            i = end;
+        }
+        if copied == 0 {
+            return Cow::Borrowed(text);
+        }
+        out.push_str(&text[copied..]);
+        Cow::Owned(out)
+    }
+}
+
+/// Part of a word, so a `0` or `%` after it doesn't start a number.
+fn is_word_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || matches!(b, b'_' | b'$' | b'%' | b'@' | b'.')
+}
+
+/// DASM's `HEX 1A45 02`: digit pairs, spaces anywhere between them.
+fn hex_bytes(digits: &str) -> Result<String, String> {
+    let digits: Vec<char> = digits.chars().filter(|c| !c.is_whitespace()).collect();
+    if digits.is_empty() || !digits.len().is_multiple_of(2) || !digits.iter().all(char::is_ascii_hexdigit) {
+        return Err("HEX expects pairs of hex digits".to_string());
+    }
+    let bytes: Vec<String> = digits.chunks(2).map(|pair| format!("${}{}", pair[0], pair[1])).collect();
+    Ok(format!(".byte {}", bytes.join(", ")))
+}
+
+#[cfg(test)]
+mod dialect_tests {
+    use super::*;
+
+    #[test]
+    fn directives_and_equates() {
+        let dasm = Dialect::dasm();
+        assert_eq!(dasm.translate("dc.b 1, 2").unwrap(), ".byte 1, 2");
+        assert_eq!(dasm.translate("DC 1").unwrap(), ".byte 1");
+        assert_eq!(dasm.translate("ORG $F000").unwrap(), "*= $F000");
+        assert_eq!(dasm.translate("HEX 1A45 0f").unwrap(), ".byte $1A, $45, $0f");
+        assert!(dasm.translate("HEX 1A4").is_err());
+        assert_eq!(dasm.translate("processor 6502").unwrap(), "");
+        assert_eq!(dasm.translate("SEG code").unwrap(), "");
+        assert!(dasm.translate("SEG.U vars").is_err());
+        assert_eq!(dasm.translate("SIZE equ 4").unwrap(), "SIZE = 4");
+        assert_eq!(dasm.translate("LDA EQUAL").unwrap(), "LDA EQUAL");
+        assert_eq!(dasm.translate("LABEL_EQU EQU 5").unwrap(), "LABEL_EQU = 5");
+        assert_eq!(dasm.translate("FREQUENCY EQU 5").unwrap(), "FREQUENCY = 5");
+        assert_eq!(dasm.translate("RESET SET 1").unwrap(), "RESET = 1");
+        assert_eq!(dasm.translate("SETUP\tset 1").unwrap(), "SETUP = 1");
+        assert_eq!(dasm.translate("DS.B 2").unwrap(), ".ds 2");
+        assert!(dasm.translate("DS.W 2").unwrap_err().contains("DS.W"));
+        assert_eq!(Dialect::ca65().translate("x .set x+1").unwrap(), "x = x+1");
+        let acme = Dialect::acme();
+        assert_eq!(acme.translate("!pseudopc $C000 {").unwrap(), ".pseudopc $C000");
+        assert_eq!(acme.translate("}").unwrap(), ".realpc ");
+        assert!(acme.translate("!pseudopc $C000").is_err());
+        assert_eq!(Dialect::ca65().translate("ptr := $FB").unwrap(), "ptr = $FB");
+        assert_eq!(Dialect::kick().translate(".const BORDER = $D020").unwrap(), "BORDER = $D020");
+        assert_eq!(Dialect::default().translate("!byte 1").unwrap(), "!byte 1");
+    }
+
+    #[test]
+    fn numbers() {
+        let dasm = Dialect::dasm();
+        assert_eq!(dasm.translate("LDA #017").unwrap(), "LDA #15");
+        assert_eq!(dasm.translate("LDA $0800+0").unwrap(), "LDA $0800+0");
+        assert_eq!(dasm.translate(".byte \"017\", 08").unwrap(), ".byte \"017\", 08");
+        assert_eq!(Dialect::acme().translate("!byte %..##..#.").unwrap(), ".byte %00110010");
+    }
+
+    #[test]
+    fn local_labels() {
+        let ca65 = Dialect::ca65();
+        assert_eq!(ca65.localize("BNE @loop", Some("main")), "BNE main::loop");
+        assert_eq!(ca65.localize("BNE @loop", None), "BNE loop");
+        assert_eq!(ca65.localize("JMP @m__2::loop", Some("main")), "JMP m__2::loop");
+        assert_eq!(ca65.localize(".byte \"@x\"", Some("main")), ".byte \"@x\"");
+        let tass = Dialect::tass64();
+        assert_eq!(tass.localize("BNE _loop", Some("main")), "BNE main::_loop");
+        assert_eq!(tass.localize("JMP m__2::_loop", Some("main")), "JMP m__2::_loop");
+        assert_eq!(tass.local_name("_loop"), Some("_loop"));
+        assert_eq!(ca65.local_name("@loop"), Some("loop"));
+        assert_eq!(ca65.local_name("loop"), None);
+    }
+
+    #[test]
+    fn address_size_markers() {
+        let ca65 = Dialect::ca65();
+        assert_eq!(ca65.address_sizes("LDA z:ptr,X"), "LDA <ptr,X");
+        assert_eq!(ca65.address_sizes("sta a:$12"), "sta >$12");
+        assert_eq!(ca65.address_sizes(".byte \"a:\""), ".byte \"a:\"");
+        let acme = Dialect::acme();
+        assert_eq!(acme.address_sizes("LDA+2 $12 : LDA+1 $12"), "LDA >$12 : LDA <$12");
+        assert_eq!(acme.address_sizes("ADC +2"), "ADC +2");
+        assert_eq!(Dialect::tass64().address_sizes("lda @w $12"), "lda >$12");
+        assert_eq!(Dialect::tass64().address_sizes("lda @wide"), "lda @wide");
+        assert_eq!(Dialect::default().address_sizes("LDA z:ptr"), "LDA z:ptr");
+    }
+}
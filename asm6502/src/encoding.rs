@@ -0,0 +1,154 @@
+//! Text encodings for `.string` and character literals
+
+use std::collections::HashMap;
+
+use crate::parser::text::TextChar;
+
+/// The encoding every assembly starts in: 7-bit ASCII, unchanged.
+pub const DEFAULT_ENCODING: &str = "ascii";
+
+/// The encodings `.encoding` knows without any `.charmap` entries.
+pub const BUILTIN_ENCODINGS: [&str; 5] = ["ascii", "petscii", "screen", "atascii", "apple2"];
+
+/// The byte a built-in encoding gives `c`. PETSCII and screen codes
+/// follow the usual cross-assembler convention: lowercase source letters
+/// become the letters of the power-up character set, uppercase ones the
+/// shifted letters.
+fn builtin(encoding: &str, c: char) -> Option<u8> {
+    let code = c as u32;
+    let byte = match encoding {
+        "ascii" => c.is_ascii().then_some(code)?,
+        "petscii" => match c {
+            ' '..='@' | '[' | ']' => code,
+            'a'..='z' => code - 0x20,
+            'A'..='Z' => code + 0x80,
+            '£' => 0x5C,
+            '↑' => 0x5E,
+            '←' => 0x5F,
+            'π' => 0xDE,
+            '\n' | '\r' => 0x0D,
+            _ => return None,
+        },
+        "screen" => match c {
+            '@' => 0x00,
+            'a'..='z' => code - 0x60,
+            '[' => 0x1B,
+            '£' => 0x1C,
+            ']' => 0x1D,
+            '↑' => 0x1E,
+            '←' => 0x1F,
+            ' '..='?' => code,
+            'A'..='Z' => code,
+            'π' => 0x5E,
+            _ => return None,
+        },
+        "atascii" => match c {
+            ' '..='_' | 'a'..='z' | '|' => code,
+            '\n' => 0x9B,
+            _ => return None,
+        },
+        "apple2" => match c {
+            ' '..='~' => code | 0x80,
+            '\n' | '\r' => 0x8D,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(byte as u8)
+}
+
+/// The active encoding and the `.charmap` entries made in each encoding.
+/// Entries belong to the encoding that was active when they were made,
+/// so switching away and back keeps them. A name that isn't built in
+/// starts out empty: only its `.charmap` entries map, and until it has
+/// one the name is unknown.
+#[derive(Debug, Clone)]
+pub struct Encodings {
+    active: String,
+    custom: HashMap<String, HashMap<char, u8>>,
+}
+
+impl Default for Encodings {
+    fn default() -> Self {
+        Self { active: DEFAULT_ENCODING.to_string(), custom: HashMap::new() }
+    }
+}
+
+impl Encodings {
+    /// Make `name` (case-insensitive) the active encoding.
+    pub fn select(&mut self, name: &str) {
+        self.active = name.to_ascii_lowercase();
+    }
+
+    pub fn active(&self) -> &str {
+        &self.active
+    }
+
+    /// Map `c` to `byte` in the active encoding.
+    pub fn define(&mut self, c: char, byte: u8) {
+        self.custom.entry(self.active.clone()).or_default().insert(c, byte);
+    }
+
+    /// A built-in encoding, or one with `.charmap` entries.
+    pub fn is_known(&self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        BUILTIN_ENCODINGS.contains(&name.as_str()) || self.custom.contains_key(&name)
+    }
+
+    pub fn encode(&self, c: char) -> Result<u8, String> {
+        if !self.is_known(&self.active) {
+            return Err(format!("unknown encoding \"{}\" has no .charmap entries yet", self.active));
+        }
+        self.custom
+            .get(&self.active)
+            .and_then(|map| map.get(&c).copied())
+            .or_else(|| builtin(&self.active, c))
+            .ok_or_else(|| format!("'{}' has no mapping in the \"{}\" encoding", c.escape_default(), self.active))
+    }
+
+    /// A string or character-literal character; `\xNN` bytes pass through.
+    pub fn encode_char(&self, c: TextChar) -> Result<u8, String> {
+        match c {
+            TextChar::Char(c) => self.encode(c),
+            TextChar::Byte(b) => Ok(b),
+        }
+    }
+}
+
+#[cfg(test)]
+mod encoding_tests {
+    use super::*;
+
+    fn encode_str(enc: &Encodings, s: &str) -> Result<Vec<u8>, String> {
+        s.chars().map(|c| enc.encode(c)).collect()
+    }
+
+    #[test]
+    fn builtin_tables() {
+        let mut enc = Encodings::default();
+        assert_eq!(encode_str(&enc, "Hi!").unwrap(), b"Hi!");
+        enc.select("PETSCII");
+        assert_eq!(encode_str(&enc, "Hello £").unwrap(), vec![0xC8, 0x45, 0x4C, 0x4C, 0x4F, 0x20, 0x5C]);
+        enc.select("screen");
+        assert_eq!(encode_str(&enc, "@az AZ0").unwrap(), vec![0x00, 0x01, 0x1A, 0x20, 0x41, 0x5A, 0x30]);
+        enc.select("atascii");
+        assert_eq!(encode_str(&enc, "Ok\n").unwrap(), vec![0x4F, 0x6B, 0x9B]);
+        enc.select("apple2");
+        assert_eq!(encode_str(&enc, "A1").unwrap(), vec![0xC1, 0xB1]);
+    }
+
+    #[test]
+    fn charmap_entries_stay_with_their_encoding() {
+        let mut enc = Encodings::default();
+        enc.select("font");
+        assert!(enc.encode('A').unwrap_err().contains("unknown encoding \"font\""));
+        enc.define('A', 0x01);
+        assert!(enc.encode('B').unwrap_err().contains("no mapping in the \"font\" encoding"));
+        enc.select("ascii");
+        assert_eq!(enc.encode('A').unwrap(), 0x41);
+        enc.select("font");
+        assert_eq!(enc.encode('A').unwrap(), 0x01);
+        enc.select("petscii");
+        assert!(enc.encode('_').is_err());
+    }
+}
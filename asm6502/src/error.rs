@@ -2,6 +2,8 @@
 
 use std::fmt;
 
+use crate::source::SourceLoc;
+
 #[derive(Debug)]
 pub enum AsmError {
     Asm(String),
@@ -19,6 +21,20 @@ impl fmt::Display for AsmError {
 
 impl std::error::Error for AsmError {}
 
+/// A `.warning` from the program: reported with its location, but the
+/// assembly still succeeds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub loc: SourceLoc,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: warning: {}", self.loc, self.message)
+    }
+}
+
 impl From<std::io::Error> for AsmError {
     fn from(e: std::io::Error) -> Self {
         AsmError::Io(e)
@@ -1,11 +1,45 @@
 //! Expression evaluation with symbol resolution
 
+use std::cell::{Cell, RefCell};
+
 use crate::parser::expression::Expr;
 use crate::symbol::SymbolTable;
 
+/// Collects the symbol names evaluators resolve while it is switched on.
+/// The assembler only turns it on for the final passes, so the fix-up
+/// iterations don't pay for (or pollute) the bookkeeping.
+#[derive(Default)]
+pub struct ReferenceLog {
+    active: Cell<bool>,
+    names: RefCell<Vec<String>>,
+}
+
+impl ReferenceLog {
+    pub fn start(&self) {
+        self.names.borrow_mut().clear();
+        self.active.set(true);
+    }
+
+    pub fn stop(&self) {
+        self.active.set(false);
+    }
+
+    pub fn record(&self, name: &str) {
+        if self.active.get() {
+            self.names.borrow_mut().push(name.to_string());
+        }
+    }
+
+    /// Hand over everything recorded so far.
+    pub fn take(&self) -> Vec<String> {
+        std::mem::take(&mut *self.names.borrow_mut())
+    }
+}
+
 pub struct ExpressionEvaluator<'a> {
     symbols: &'a SymbolTable,
     current_address: u16,
+    log: Option<&'a ReferenceLog>,
 }
 
 impl<'a> ExpressionEvaluator<'a> {
@@ -13,9 +47,16 @@ impl<'a> ExpressionEvaluator<'a> {
         Self {
             symbols,
             current_address,
+            log: None,
         }
     }
 
+    /// Record every label this evaluator resolves into `log`.
+    pub fn with_log(mut self, log: &'a ReferenceLog) -> Self {
+        self.log = Some(log);
+        self
+    }
+
     /// Evaluate an expression to a u32 value
     /// Returns u32 to handle intermediate calculations like $10000 - offset
     pub fn evaluate(&self, expr: &Expr) -> Result<u32, String> {
@@ -23,14 +64,33 @@ impl<'a> ExpressionEvaluator<'a> {
             Expr::Number(n) => Ok(*n),
 
             Expr::Label(name) => {
-                self.symbols
+                let value = self.symbols
                     .get(name)
                     .map(|v| v as u32)
-                    .ok_or_else(|| format!("Undefined label: {}", name))
+                    .ok_or_else(|| format!("Undefined label: {}", name))?;
+                if let Some(log) = self.log {
+                    log.record(&self.symbols.key(name));
+                }
+                Ok(value)
             }
 
             Expr::CurrentAddress => Ok(self.current_address as u32),
 
+            Expr::Bank(name) => {
+                if self.symbols.get(name).is_none() {
+                    return Err(format!("Undefined label: {}", name));
+                }
+                if let Some(log) = self.log {
+                    log.record(&self.symbols.key(name));
+                }
+                self.symbols.bank(name).ok_or_else(|| format!("bank({}): '{}' isn't in a bank", name, name))
+            }
+
+            // The assembler maps character literals through the active
+            // encoding before layout; left alone, one is plain ASCII
+            Expr::Char(c) if c.is_ascii() => Ok(*c as u32),
+            Expr::Char(c) => Err(format!("'{}' has no mapping in the \"ascii\" encoding", c)),
+
             Expr::Immediate(inner) => {
                 // Immediate mode - evaluate the inner expression
                 self.evaluate(inner)
@@ -74,6 +134,18 @@ impl<'a> ExpressionEvaluator<'a> {
                 }
                 Ok(l / r)
             }
+
+            Expr::Eq(left, right) => Ok((self.evaluate(left)? == self.evaluate(right)?) as u32),
+            Expr::Ne(left, right) => Ok((self.evaluate(left)? != self.evaluate(right)?) as u32),
+            Expr::Lt(left, right) => Ok((self.evaluate(left)? < self.evaluate(right)?) as u32),
+            Expr::Le(left, right) => Ok((self.evaluate(left)? <= self.evaluate(right)?) as u32),
+            Expr::Gt(left, right) => Ok((self.evaluate(left)? > self.evaluate(right)?) as u32),
+            Expr::Ge(left, right) => Ok((self.evaluate(left)? >= self.evaluate(right)?) as u32),
+
+            // Short-circuit: the right side is only evaluated when needed
+            Expr::And(left, right) => Ok((self.evaluate(left)? != 0 && self.evaluate(right)? != 0) as u32),
+            Expr::Or(left, right) => Ok((self.evaluate(left)? != 0 || self.evaluate(right)? != 0) as u32),
+            Expr::Not(inner) => Ok((self.evaluate(inner)? == 0) as u32),
         }
     }
 
@@ -157,6 +229,27 @@ mod tests {
         assert!(evaluator.evaluate(&expr).is_err());
     }
 
+    #[test]
+    fn test_reference_log() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("A1".to_string(), 1);
+        symbols.insert("B2".to_string(), 2);
+        let log = ReferenceLog::default();
+        let expr = Expr::Add(
+            Box::new(Expr::Label("A1".to_string())),
+            Box::new(Expr::Label("B2".to_string())),
+        );
+
+        // Inactive log ignores evaluations
+        ExpressionEvaluator::new(&symbols, 0).with_log(&log).evaluate(&expr).unwrap();
+        assert!(log.take().is_empty());
+
+        log.start();
+        ExpressionEvaluator::new(&symbols, 0).with_log(&log).evaluate(&expr).unwrap();
+        log.stop();
+        assert_eq!(log.take(), vec!["A1".to_string(), "B2".to_string()]);
+    }
+
     #[test]
     fn test_u32_overflow() {
         let symbols = SymbolTable::new();
@@ -183,4 +276,19 @@ mod tests {
         let expr_high = Expr::HighByte(Box::new(Expr::Number(0x1234)));
         assert_eq!(evaluator.evaluate(&expr_high).unwrap(), 0x12);
     }
+
+    #[test]
+    fn test_comparisons_and_logic() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("PAL".to_string(), 1);
+        let evaluator = ExpressionEvaluator::new(&symbols, 0x1000);
+        let parse = |s| crate::parser::ExpressionParser::parse(s).unwrap();
+
+        assert_eq!(evaluator.evaluate(&parse("PAL == 1")).unwrap(), 1);
+        assert_eq!(evaluator.evaluate(&parse("PAL != 1")).unwrap(), 0);
+        assert_eq!(evaluator.evaluate(&parse("3 < 2 || 2 <= 2")).unwrap(), 1);
+        assert_eq!(evaluator.evaluate(&parse("!PAL")).unwrap(), 0);
+        // The right side of a decided && is never evaluated
+        assert_eq!(evaluator.evaluate(&parse("PAL == 0 && MISSING")).unwrap(), 0);
+    }
 }
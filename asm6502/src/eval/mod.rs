@@ -2,4 +2,4 @@
 
 pub mod expression;
 
-pub use expression::ExpressionEvaluator;
+pub use expression::{ExpressionEvaluator, ReferenceLog};
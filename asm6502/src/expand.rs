@@ -0,0 +1,1077 @@
+//! Macro expansion, conditional assembly, loops, source inclusion and
+//! text encoding, run once between `parse_source` and the layout loop
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::dialect::Dialect;
+use crate::encoding::{BUILTIN_ENCODINGS, Encodings};
+use crate::eval::ExpressionEvaluator;
+use crate::files::FileCache;
+use crate::opcodes::OpcodeTables;
+use crate::parser::expression::Expr;
+use crate::parser::lexer::{
+    block_close, block_open, split_line_label, BlockBody, Item, MacroParam, TextLayout, TextPart, ValueLayout,
+};
+use crate::parser::structs::{parse_enum_members, parse_field, FieldKind};
+use crate::parser::text::{literal_end, parse_char_literal, strip_comment};
+use crate::parser::{parse_file, parse_lines, Condition, ExpressionParser};
+use crate::source::SourceLoc;
+use crate::symbol::SymbolTable;
+
+/// How deeply macro invocations and loops may nest before expansion gives
+/// up. Only a macro that (directly or indirectly) invokes itself gets near it.
+pub const MAX_MACRO_DEPTH: usize = 64;
+
+/// Upper bound on the iterations of a single `.rept` or `.for`, so a
+/// condition that never becomes false is reported instead of hanging.
+pub const MAX_LOOP_ITERATIONS: usize = 65536;
+
+struct MacroDef {
+    name: String,
+    params: Vec<MacroParam>,
+    body: BlockBody,
+    /// Labels defined in the body; renamed per expansion.
+    locals: HashSet<String>,
+    loc: SourceLoc,
+}
+
+/// A `.struct` layout: its fields at their offsets.
+struct StructDef {
+    fields: Vec<StructField>,
+    size: u32,
+    /// Every named offset, including the fields of embedded structs as
+    /// `field::inner`.
+    members: Vec<(String, u32)>,
+    loc: SourceLoc,
+}
+
+struct StructField {
+    name: Option<String>,
+    kind: FieldKind,
+    count: u32,
+    size: u32,
+}
+
+/// One open `.if` block.
+struct CondFrame {
+    loc: SourceLoc,
+    /// Whether items are currently kept.
+    active: bool,
+    /// Whether the enclosing block is active at all.
+    parent_active: bool,
+    /// Whether some branch of this block has already been taken.
+    taken: bool,
+    seen_else: bool,
+}
+
+/// Replaces every macro definition with nothing and every invocation with
+/// the parsed body of the macro, keeps only the taken branch of each
+/// conditional block, unrolls loops and splices in included files.
+/// Macros must be defined before use. Strings and character literals are
+/// encoded with the encoding active where they end up.
+///
+/// Conditions are evaluated in source order, before layout, so they can
+/// only see predefined symbols and the constants assigned above them.
+/// A condition that needs a label address or `*` is an error rather than
+/// something the fix-up passes could flip between iterations.
+pub struct Expander<'a> {
+    opcodes: &'a OpcodeTables,
+    macros: HashMap<String, Rc<MacroDef>>,
+    structs: HashMap<String, StructDef>,
+    expansions: usize,
+    /// Predefined symbols plus constants whose value is already known.
+    symbols: SymbolTable,
+    /// Labels defined so far (they exist, but have no address yet).
+    labels: HashSet<String>,
+    /// Constants defined so far whose value depends on addresses.
+    unresolved: HashSet<String>,
+    include_dirs: &'a [PathBuf],
+    files: &'a FileCache,
+    /// Files being expanded, outermost first (the circular-include guard).
+    include_stack: Vec<PathBuf>,
+    /// Every file read so far, for `.include_once`.
+    included: HashSet<PathBuf>,
+    encodings: Encodings,
+    /// Where a name that isn't an encoding yet was selected; its
+    /// `.charmap` entries must follow.
+    new_encoding: Option<SourceLoc>,
+    dialect: &'a Dialect,
+}
+
+impl<'a> Expander<'a> {
+    pub fn new(
+        opcodes: &'a OpcodeTables,
+        symbols: SymbolTable,
+        include_dirs: &'a [PathBuf],
+        files: &'a FileCache,
+        dialect: &'a Dialect,
+    ) -> Self {
+        Self {
+            opcodes,
+            macros: HashMap::new(),
+            structs: HashMap::new(),
+            expansions: 0,
+            symbols,
+            labels: HashSet::new(),
+            unresolved: HashSet::new(),
+            include_dirs,
+            files,
+            include_stack: Vec::new(),
+            included: HashSet::new(),
+            encodings: Encodings::default(),
+            new_encoding: None,
+            dialect,
+        }
+    }
+
+    /// Expand the items parsed from `file` (`None` for a source string).
+    pub fn expand(&mut self, items: Vec<Item>, file: Option<&str>) -> Result<Vec<Item>, String> {
+        if let Some(file) = file {
+            let id = self.files.canonical(Path::new(file));
+            self.included.insert(id.clone());
+            self.include_stack.push(id);
+        }
+        let mut out = Vec::with_capacity(items.len());
+        self.expand_into(items, 0, &mut out)?;
+        self.check_new_encoding()?;
+        if self.dialect.open_logical_blocks {
+            out = close_logical_blocks(out);
+        }
+        Ok(out)
+    }
+
+    fn expand_into(&mut self, items: Vec<Item>, depth: usize, out: &mut Vec<Item>) -> Result<(), String> {
+        let mut loc = SourceLoc::default();
+        let mut conds: Vec<CondFrame> = Vec::new();
+        for item in items {
+            if let Item::Line(l) = &item {
+                loc = l.clone();
+            }
+            let active = conds.last().is_none_or(|f| f.active);
+            match item {
+                Item::If(cond) => {
+                    let taken = active && self.test(&cond, &loc)?;
+                    conds.push(CondFrame { loc: loc.clone(), active: taken, parent_active: active, taken, seen_else: false });
+                }
+                Item::ElseIf(cond) => {
+                    let frame = conds.last_mut().ok_or_else(|| format!("{}: .elseif without .if", loc))?;
+                    if frame.seen_else {
+                        return Err(format!("{}: .elseif after .else", loc));
+                    }
+                    frame.active = frame.parent_active && !frame.taken && self.test(&cond, &loc)?;
+                    frame.taken |= frame.active;
+                }
+                Item::Else => {
+                    let frame = conds.last_mut().ok_or_else(|| format!("{}: .else without .if", loc))?;
+                    if frame.seen_else {
+                        return Err(format!("{}: duplicate .else", loc));
+                    }
+                    frame.active = frame.parent_active && !frame.taken;
+                    frame.taken = true;
+                    frame.seen_else = true;
+                }
+                Item::EndIf => {
+                    conds.pop().ok_or_else(|| format!("{}: .endif without .if", loc))?;
+                }
+                _ if !active => {}
+                Item::Line(l) => out.push(Item::Line(l)),
+                Item::Label(name) => {
+                    self.labels.insert(self.symbols.key(&name).into_owned());
+                    out.push(Item::Label(name));
+                }
+                Item::Constant(name, expr) => {
+                    let expr = self.encode_expr(expr, &loc)?;
+                    self.track_constant(&name, &expr);
+                    out.push(Item::Constant(name, expr));
+                }
+                Item::Macro { name, params, body } => {
+                    self.define(name, params, body, &loc)?;
+                }
+                Item::Bank { number, window } => {
+                    // Banks are laid out before layout, so the numbers
+                    // must be known now
+                    let fixed = |expr: Expr, what: &str| -> Result<Expr, String> {
+                        let expr = self.encode_expr(expr, &loc)?;
+                        Ok(Expr::Number(self.evaluate_static(&expr, &loc, what)?))
+                    };
+                    let number = fixed(number, ".bank number")?;
+                    let window = match window {
+                        Some((size, base)) => Some((fixed(size, ".bank size")?, fixed(base, ".bank address")?)),
+                        None => None,
+                    };
+                    out.push(Item::Bank { number, window });
+                }
+                Item::Struct { name, body } => self.define_struct(name, &body, &loc, out)?,
+                Item::Enum { name, body } => self.define_enum(name.as_deref(), &body, out)?,
+                Item::StructData { name, inits } => self.struct_data(&name, inits, &loc, out)?,
+                Item::Instruction { ref mnemonic, ref operand } if self.macros.contains_key(mnemonic) => {
+                    let def = Rc::clone(&self.macros[mnemonic]);
+                    let bound = bind_args(&def, operand.as_deref(), &loc, self.symbols.is_case_sensitive())?;
+                    let count = split_args(operand.as_deref().unwrap_or("")).len();
+                    let block = Block { name: &def.name, scope: &def.name, body: &def.body, locals: &def.locals };
+                    self.expand_block(&block, &bound, count, &loc, depth, out)?;
+                }
+                Item::Rept { count, body } => {
+                    let count = self.encode_expr(count, &loc)?;
+                    let n = self.evaluate_static(&count, &loc, ".rept count")? as usize;
+                    if n > MAX_LOOP_ITERATIONS {
+                        return Err(format!("{}: .rept count {} exceeds {}", loc, n, MAX_LOOP_ITERATIONS));
+                    }
+                    let locals = body_locals(&body, &[], self.dialect);
+                    for i in 0..n {
+                        let name = format!(".rept #{}", i);
+                        let block = Block { name: &name, scope: "rept", body: &body, locals: &locals };
+                        self.expand_block(&block, &[], 0, &loc, depth, out)?;
+                    }
+                }
+                Item::For { var, start, cond, step, body } => {
+                    let [start, cond, step] = [start, cond, step].map(|e| self.encode_expr(e, &loc));
+                    self.expand_for(&var, &start?, &cond?, &step?, &body, &loc, depth, out)?;
+                }
+                Item::Include { path, once } => {
+                    self.include(&path, once, &loc, depth, out)?;
+                }
+                Item::IncBin { path, offset, length } => {
+                    // Resolved like an include; if nothing matches, the
+                    // path is kept and reading it reports the error.
+                    let path = match self.resolve(&path, &loc) {
+                        Some(found) => found.display().to_string(),
+                        None => path,
+                    };
+                    let offset = offset.map(|e| self.encode_expr(e, &loc)).transpose()?;
+                    let length = length.map(|e| self.encode_expr(e, &loc)).transpose()?;
+                    out.push(Item::IncBin { path, offset, length });
+                }
+                Item::Encoding(name) => {
+                    self.check_new_encoding()?;
+                    if !self.encodings.is_known(&name) {
+                        self.new_encoding = Some(loc.clone());
+                    }
+                    self.encodings.select(&name);
+                }
+                Item::CharMap(c, value) => {
+                    let value = self.encode_expr(value, &loc)?;
+                    let byte = self.evaluate_static(&value, &loc, ".charmap value")?;
+                    let byte = u8::try_from(byte)
+                        .map_err(|_| format!("{}: .charmap value ${:X} doesn't fit in a byte", loc, byte))?;
+                    self.encodings.define(c, byte);
+                    self.new_encoding = None;
+                }
+                Item::String { directive, layout, parts, text } => {
+                    let bytes = self
+                        .encode_text(layout, parts, &loc)
+                        .map_err(|e| format!("{}: {}: {}", loc, directive, e))?;
+                    out.push(Item::Text { directive, text, bytes });
+                }
+                other => out.push(self.encode_item(other, &loc)?),
+            }
+        }
+        if let Some(frame) = conds.last() {
+            return Err(format!("{}: .if without matching .endif", frame.loc));
+        }
+        Ok(())
+    }
+
+    /// Record a constant's value if it can be known before layout.
+    fn track_constant(&mut self, name: &str, expr: &Expr) {
+        let key = self.symbols.key(name).into_owned();
+        let value = match self.address_dependency(expr) {
+            Some(_) => None,
+            None => ExpressionEvaluator::new(&self.symbols, 0).evaluate_u16(expr).ok(),
+        };
+        match value {
+            Some(v) => {
+                self.unresolved.remove(&key);
+                self.symbols.insert(key, v);
+            }
+            None => {
+                self.symbols.remove(&key);
+                self.unresolved.insert(key);
+            }
+        }
+    }
+
+    /// The first part of `expr` that can't be known before layout: `*`, a
+    /// label, or a constant computed from one.
+    fn address_dependency(&self, expr: &Expr) -> Option<String> {
+        let mut found = None;
+        expr.visit(&mut |e| {
+            if found.is_some() {
+                return;
+            }
+            match e {
+                Expr::CurrentAddress => found = Some("*".to_string()),
+                // Banks are only assigned when segments are placed
+                Expr::Bank(name) => found = Some(format!("bank({})", name)),
+                Expr::Label(name) => {
+                    let key = self.symbols.key(name);
+                    if self.labels.contains(key.as_ref()) || self.unresolved.contains(key.as_ref()) {
+                        found = Some(name.clone());
+                    }
+                }
+                _ => {}
+            }
+        });
+        found
+    }
+
+    fn test(&self, cond: &Condition, loc: &SourceLoc) -> Result<bool, String> {
+        match cond {
+            Condition::Defined(name) => Ok(self.is_defined(name)),
+            Condition::NotDefined(name) => Ok(!self.is_defined(name)),
+            Condition::Expr(expr) => {
+                let expr = self.encode_expr(expr.clone(), loc)?;
+                Ok(self.evaluate_static(&expr, loc, "condition")? != 0)
+            }
+        }
+    }
+
+    /// Replace the character literals in `expr` by their encoded values.
+    fn encode_expr(&self, mut expr: Expr, loc: &SourceLoc) -> Result<Expr, String> {
+        let mut error = None;
+        expr.visit_mut(&mut |e| {
+            if let Expr::Char(c) = *e {
+                match self.encodings.encode(c) {
+                    Ok(byte) => *e = Expr::Number(byte as u32),
+                    Err(msg) => {
+                        error.get_or_insert(msg);
+                    }
+                }
+            }
+        });
+        match error {
+            Some(msg) => Err(format!("{}: {}", loc, msg)),
+            None => Ok(expr),
+        }
+    }
+
+    /// The bytes of a text directive: strings encoded, expressions kept
+    /// for layout, then the terminator, length or high bit added.
+    fn encode_text(&self, layout: TextLayout, parts: Vec<TextPart>, loc: &SourceLoc) -> Result<Vec<Expr>, String> {
+        let mut bytes = Vec::new();
+        for part in parts {
+            match part {
+                TextPart::Str(chars) => {
+                    for c in chars {
+                        bytes.push(Expr::Number(self.encodings.encode_char(c)? as u32));
+                    }
+                }
+                TextPart::Expr(expr) => bytes.push(self.encode_expr(expr, loc)?),
+            }
+        }
+        match layout {
+            TextLayout::Plain => {}
+            TextLayout::NulTerminated => bytes.push(Expr::Number(0)),
+            TextLayout::LengthPrefixed => {
+                if bytes.len() > 0xFF {
+                    return Err(format!("{} bytes don't fit a one-byte length", bytes.len()));
+                }
+                bytes.insert(0, Expr::Number(bytes.len() as u32));
+            }
+            TextLayout::LastBitSet => match bytes.last_mut() {
+                Some(Expr::Number(n)) => *n |= 0x80,
+                Some(_) => return Err("the last byte must be a character or a number to set its bit 7".to_string()),
+                None => return Err("nothing to set bit 7 of".to_string()),
+            },
+        }
+        Ok(bytes)
+    }
+
+    /// Encode the character literals of an item that is passed through.
+    fn encode_item(&self, item: Item, loc: &SourceLoc) -> Result<Item, String> {
+        let encode_all = |exprs: Vec<Expr>| -> Result<Vec<Expr>, String> {
+            exprs.into_iter().map(|e| self.encode_expr(e, loc)).collect()
+        };
+        let encode_opt = |expr: Option<Expr>| expr.map(|e| self.encode_expr(e, loc)).transpose();
+        Ok(match item {
+            Item::Instruction { mnemonic, operand: Some(op) } => Item::Instruction {
+                mnemonic,
+                operand: Some(self.encode_operand(&op).map_err(|e| format!("{}: {}", loc, e))?),
+            },
+            Item::Data(exprs) => Item::Data(encode_all(exprs)?),
+            Item::Words(exprs) => Item::Words(encode_all(exprs)?),
+            Item::Values { layout, exprs } => Item::Values { layout, exprs: encode_all(exprs)? },
+            Item::Org(expr) => Item::Org(self.encode_expr(expr, loc)?),
+            Item::Logical(expr) => Item::Logical(self.encode_expr(expr, loc)?),
+            Item::Align { boundary, fill } => Item::Align { boundary: self.encode_expr(boundary, loc)?, fill: encode_opt(fill)? },
+            Item::Fill { count, value } => Item::Fill { count: self.encode_expr(count, loc)?, value: encode_opt(value)? },
+            Item::Skip(count) => Item::Skip(self.encode_expr(count, loc)?),
+            Item::Assert { cond, text, message } => Item::Assert { cond: self.encode_expr(cond, loc)?, text, message },
+            other => other,
+        })
+    }
+
+    /// An instruction operand with each character literal replaced by its
+    /// encoded value in hex, since operands are only parsed during layout.
+    fn encode_operand(&self, operand: &str) -> Result<String, String> {
+        let mut out = String::with_capacity(operand.len());
+        let mut i = 0;
+        while let Some(offset) = operand[i..].find('\'') {
+            let start = i + offset;
+            let end = literal_end(operand, start);
+            out.push_str(&operand[i..start]);
+            match parse_char_literal(&operand[start..end]) {
+                Some(c) => out.push_str(&format!("${:02X}", self.encodings.encode_char(c)?)),
+                None => out.push_str(&operand[start..end]),
+            }
+            i = end;
+        }
+        out.push_str(&operand[i..]);
+        Ok(out)
+    }
+
+    fn is_defined(&self, name: &str) -> bool {
+        let key = self.symbols.key(name);
+        self.symbols.get(&key).is_some() || self.labels.contains(key.as_ref()) || self.unresolved.contains(key.as_ref())
+    }
+
+    /// Find `path` next to the including file, then in each include
+    /// directory, and splice its expanded contents into `out`.
+    fn include(&mut self, path: &str, once: bool, loc: &SourceLoc, depth: usize, out: &mut Vec<Item>) -> Result<(), String> {
+        let resolved = self
+            .resolve(path, loc)
+            .ok_or_else(|| format!("{}: .include \"{}\": file not found", loc, path))?;
+        let id = self.files.canonical(&resolved);
+        if self.include_stack.contains(&id) {
+            let chain: Vec<String> = self
+                .include_stack
+                .iter()
+                .chain(std::iter::once(&id))
+                .map(|p| p.display().to_string())
+                .collect();
+            return Err(format!("{}: circular .include: {}", loc, chain.join(" -> ")));
+        }
+        if once && self.included.contains(&id) {
+            return Ok(());
+        }
+        if depth >= MAX_MACRO_DEPTH {
+            return Err(format!("{}: .include nested deeper than {} levels", loc, MAX_MACRO_DEPTH));
+        }
+        let bytes = self
+            .files
+            .read(&resolved)
+            .map_err(|e| format!("{}: .include \"{}\": {}", loc, resolved.display(), e))?;
+        let source = std::str::from_utf8(&bytes)
+            .map_err(|_| format!("{}: .include \"{}\": not UTF-8 text", loc, resolved.display()))?;
+        let name: Arc<str> = Arc::from(resolved.display().to_string());
+        let items = parse_file(source, Some(name), self.dialect, &|name| self.macros.contains_key(name))?;
+
+        self.included.insert(id.clone());
+        self.include_stack.push(id);
+        let result = self.expand_into(items, depth + 1, out);
+        self.include_stack.pop();
+        result
+    }
+
+    /// Find `path` next to the file holding `loc`, then in the include
+    /// directories.
+    fn resolve(&self, path: &str, loc: &SourceLoc) -> Option<PathBuf> {
+        let path = Path::new(path);
+        if path.is_absolute() {
+            return self.files.is_file(path).then(|| path.to_path_buf());
+        }
+        let base = loc
+            .file
+            .as_deref()
+            .and_then(|f| Path::new(f).parent())
+            .unwrap_or(Path::new(""));
+        std::iter::once(base)
+            .chain(self.include_dirs.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(path))
+            .find(|candidate| self.files.is_file(candidate))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn expand_for(
+        &mut self,
+        var: &str,
+        start: &Expr,
+        cond: &Expr,
+        step: &Expr,
+        body: &BlockBody,
+        loc: &SourceLoc,
+        depth: usize,
+        out: &mut Vec<Item>,
+    ) -> Result<(), String> {
+        // The variable is a constant while the loop runs, so the
+        // condition and step can use it; the body gets it substituted.
+        let shadowed = self.symbols.get(var);
+        let locals = body_locals(body, &[var], self.dialect);
+        let mut value = self.evaluate_static(start, loc, ".for start")?;
+        let mut iterations = 0;
+        let result = loop {
+            self.symbols.insert(var.to_string(), value as u16);
+            match self.evaluate_static(cond, loc, ".for condition") {
+                Ok(0) => break Ok(()),
+                Ok(_) => {}
+                Err(e) => break Err(e),
+            }
+            iterations += 1;
+            if iterations > MAX_LOOP_ITERATIONS {
+                break Err(format!("{}: .for loop exceeds {} iterations", loc, MAX_LOOP_ITERATIONS));
+            }
+            let name = format!(".for {}={}", var, value);
+            let bound = [(var, value.to_string())];
+            let block = Block { name: &name, scope: "for", body, locals: &locals };
+            if let Err(e) = self.expand_block(&block, &bound, 0, loc, depth, out) {
+                break Err(e);
+            }
+            // The body may have reassigned the variable name; step from ours
+            self.symbols.insert(var.to_string(), value as u16);
+            match self.evaluate_static(step, loc, ".for step") {
+                Ok(next) => value = next,
+                Err(e) => break Err(e),
+            }
+        };
+        match shadowed {
+            Some(v) => self.symbols.insert(var.to_string(), v),
+            None => self.symbols.remove(var),
+        }
+        result
+    }
+
+    /// Substitute one copy of a block body, parse it and expand it in turn.
+    /// Lines are located in the body and tagged with the invocation.
+    fn expand_block(
+        &mut self,
+        block: &Block,
+        bound: &[(&str, String)],
+        count: usize,
+        call: &SourceLoc,
+        depth: usize,
+        out: &mut Vec<Item>,
+    ) -> Result<(), String> {
+        if depth >= MAX_MACRO_DEPTH {
+            // Every level adds an "expanded from" clause; name only where it started
+            return Err(format!(
+                "{}: expansion of '{}' nested deeper than {} levels (recursive macro?)",
+                call.outermost(),
+                block.name,
+                MAX_MACRO_DEPTH
+            ));
+        }
+        let ctx = Substitution {
+            bound,
+            count,
+            locals: block.locals,
+            scope: format!("{}__{}", block.scope, self.expansions),
+            case_sensitive: self.symbols.is_case_sensitive(),
+        };
+        self.expansions += 1;
+        let lines = block
+            .body
+            .iter()
+            .map(|(loc, line)| {
+                let text = ctx.apply(line).map_err(|e| format!("{}: {}", call, e))?;
+                Ok((loc.expanded(block.name, call), text))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let items = parse_lines(lines, self.dialect, &|name| self.macros.contains_key(name))?;
+        self.expand_into(items, depth + 1, out)
+    }
+
+    /// A name `.encoding` doesn't know creates a custom table, so it needs
+    /// `.charmap` entries before the next switch; otherwise it's a typo.
+    fn check_new_encoding(&mut self) -> Result<(), String> {
+        match self.new_encoding.take() {
+            Some(loc) if !self.encodings.is_known(self.encodings.active()) => Err(format!(
+                "{}: unknown encoding \"{}\"; the built-in ones are {}, and a custom one needs .charmap entries",
+                loc,
+                self.encodings.active(),
+                BUILTIN_ENCODINGS.join(", ")
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Evaluate a value that must be known before layout: a loop count,
+    /// bound or step, or a condition.
+    fn evaluate_static(&self, expr: &Expr, loc: &SourceLoc, what: &str) -> Result<u32, String> {
+        if let Some(dep) = self.address_dependency(expr) {
+            return Err(format!(
+                "{}: {} depends on '{}', whose value isn't known until layout; \
+                 only constants and predefined symbols can be used here",
+                loc, what, dep
+            ));
+        }
+        ExpressionEvaluator::new(&self.symbols, 0)
+            .evaluate(expr)
+            .map_err(|e| format!("{}: {}: {} (symbols must be defined above it)", loc, what, e))
+    }
+
+    /// Lay out a `.struct` and define `Name::field` as each field's offset
+    /// and `Name::size` as the total.
+    fn define_struct(&mut self, name: String, body: &BlockBody, loc: &SourceLoc, out: &mut Vec<Item>) -> Result<(), String> {
+        let key = self.symbols.key(&name).into_owned();
+        if let Some(prev) = self.structs.get(&key) {
+            return Err(format!("{}: struct '{}' already defined at {}", loc, name, prev.loc));
+        }
+        let mut fields: Vec<StructField> = Vec::new();
+        let mut members = Vec::new();
+        let mut offset = 0;
+        for (line_loc, text) in body {
+            let text = strip_comment(text).trim();
+            if text.is_empty() {
+                continue;
+            }
+            let decl = parse_field(text).map_err(|e| format!("{}: {}", line_loc, e))?;
+            if let Some(field) = &decl.name {
+                if field.eq_ignore_ascii_case("size") {
+                    return Err(format!("{}: a struct field can't be named 'size'", line_loc));
+                }
+                let field_key = self.symbols.key(field);
+                if fields.iter().any(|f| f.name.as_deref().is_some_and(|n| self.symbols.key(n) == field_key)) {
+                    return Err(format!("{}: field '{}' already defined in struct '{}'", line_loc, field, name));
+                }
+            }
+            let count = match decl.count {
+                Some(count) => {
+                    let count = self.encode_expr(count, line_loc)?;
+                    self.evaluate_static(&count, line_loc, "field size")?
+                }
+                None => 1,
+            };
+            let size = match &decl.kind {
+                FieldKind::Byte | FieldKind::Res => count,
+                FieldKind::Word => count.saturating_mul(2),
+                FieldKind::Dword => count.saturating_mul(4),
+                FieldKind::Tag(tag) => {
+                    let inner = self
+                        .structs
+                        .get(self.symbols.key(tag).as_ref())
+                        .ok_or_else(|| format!("{}: .tag: no struct '{}' defined above", line_loc, tag))?;
+                    if let Some(field) = &decl.name {
+                        members.extend(inner.members.iter().map(|(m, o)| (format!("{}::{}", field, m), offset + o)));
+                    }
+                    inner.size
+                }
+            };
+            if let Some(field) = &decl.name {
+                members.push((field.clone(), offset));
+            }
+            fields.push(StructField { name: decl.name, kind: decl.kind, count, size });
+            offset = offset.saturating_add(size);
+            if offset > 0x10000 {
+                return Err(format!("{}: struct '{}' is larger than 64K", line_loc, name));
+            }
+        }
+        members.sort_by_key(|(_, o)| *o);
+        for (member, value) in members.iter().map(|(m, o)| (m.as_str(), *o)).chain([("size", offset)]) {
+            let constant = format!("{}::{}", name, member);
+            self.track_constant(&constant, &Expr::Number(value));
+            out.push(Item::Constant(constant, Expr::Number(value)));
+        }
+        self.structs.insert(key, StructDef { fields, size: offset, members, loc: loc.clone() });
+        Ok(())
+    }
+
+    /// Number the members of an `.enum` from 0, each one more than the
+    /// last unless given a value. A named enum defines `Name::member`.
+    fn define_enum(&mut self, name: Option<&str>, body: &BlockBody, out: &mut Vec<Item>) -> Result<(), String> {
+        let mut next = 0u32;
+        for (line_loc, text) in body {
+            let text = strip_comment(text).trim();
+            if text.is_empty() {
+                continue;
+            }
+            out.push(Item::Line(line_loc.clone()));
+            for (member, value) in parse_enum_members(text).map_err(|e| format!("{}: {}", line_loc, e))? {
+                if let Some(value) = value {
+                    let value = self.encode_expr(value, line_loc)?;
+                    next = self.evaluate_static(&value, line_loc, "enum value")?;
+                }
+                let constant = match name {
+                    Some(name) => format!("{}::{}", name, member),
+                    None => member,
+                };
+                self.track_constant(&constant, &Expr::Number(next));
+                out.push(Item::Constant(constant, Expr::Number(next)));
+                next = next.wrapping_add(1);
+            }
+        }
+        Ok(())
+    }
+
+    /// An instance of a struct: the named fields initialised, every other
+    /// field and all padding zero.
+    fn struct_data(&self, name: &str, inits: Vec<(String, Expr)>, loc: &SourceLoc, out: &mut Vec<Item>) -> Result<(), String> {
+        let def = self
+            .structs
+            .get(self.symbols.key(name).as_ref())
+            .ok_or_else(|| format!("{}: .dstruct: no struct '{}' defined above", loc, name))?;
+        let mut values: Vec<Option<Expr>> = vec![None; def.fields.len()];
+        for (field, expr) in inits {
+            let key = self.symbols.key(&field);
+            let index = def
+                .fields
+                .iter()
+                .position(|f| f.name.as_deref().is_some_and(|n| self.symbols.key(n) == key))
+                .ok_or_else(|| format!("{}: struct '{}' has no field '{}'", loc, name, field))?;
+            let f = &def.fields[index];
+            if f.count != 1 || matches!(f.kind, FieldKind::Res | FieldKind::Tag(_)) {
+                return Err(format!(
+                    "{}: field '{}' isn't a single .byte, .word or .dword, so it can't be initialised",
+                    loc, field
+                ));
+            }
+            if values[index].replace(self.encode_expr(expr, loc)?).is_some() {
+                return Err(format!("{}: field '{}' is initialised twice", loc, field));
+            }
+        }
+        for (field, value) in def.fields.iter().zip(values) {
+            out.push(match (value, &field.kind) {
+                (Some(e), FieldKind::Byte) => Item::Data(vec![e]),
+                (Some(e), FieldKind::Word) => Item::Words(vec![e]),
+                (Some(e), _) => Item::Values { layout: ValueLayout::Dword, exprs: vec![e] },
+                (None, _) => Item::Fill { count: Expr::Number(field.size), value: None },
+            });
+        }
+        Ok(())
+    }
+
+    fn define(
+        &mut self,
+        name: String,
+        params: Vec<MacroParam>,
+        body: BlockBody,
+        loc: &SourceLoc,
+    ) -> Result<(), String> {
+        let key = name.to_ascii_uppercase();
+        if self.opcodes.opcodes.contains_key(key.as_str()) {
+            return Err(format!("{}: macro name '{}' clashes with an instruction", loc, name));
+        }
+        if let Some(prev) = self.macros.get(&key) {
+            return Err(format!("{}: macro '{}' already defined at {}", loc, name, prev.loc));
+        }
+        let names: Vec<&str> = params.iter().map(|(p, _)| p.as_str()).collect();
+        let locals = body_locals(&body, &names, self.dialect);
+        self.macros.insert(key, Rc::new(MacroDef { name, params, body, locals, loc: loc.clone() }));
+        Ok(())
+    }
+}
+
+/// One copy of a block body to expand.
+struct Block<'b> {
+    /// Shown in locations: `poke`, `.rept #2`, `.for i=3`.
+    name: &'b str,
+    /// Prefix of the per-copy label scope (`poke__N`, `rept__N`).
+    scope: &'b str,
+    body: &'b BlockBody,
+    locals: &'b HashSet<String>,
+}
+
+/// Give each `.pseudopc` of a dialect with open logical blocks its
+/// `.realpc`: before the next `.pseudopc`, `*=`, `.segment` or `.bank`,
+/// or at the end. A `.realpc` with no block open is dropped.
+fn close_logical_blocks(items: Vec<Item>) -> Vec<Item> {
+    let mut out = Vec::with_capacity(items.len());
+    let mut open = false;
+    for item in items {
+        match item {
+            Item::Logical(_) | Item::Org(_) | Item::Segment(_) | Item::Bank { .. } if open => {
+                out.push(Item::RealPc);
+                open = false;
+            }
+            Item::RealPc if !open => continue,
+            _ => {}
+        }
+        match item {
+            Item::Logical(_) => open = true,
+            Item::RealPc => open = false,
+            _ => {}
+        }
+        out.push(item);
+    }
+    if open {
+        out.push(Item::RealPc);
+    }
+    out
+}
+
+/// Labels defined directly in a body (not in nested blocks, which rename
+/// their own), except names the body receives from outside.
+fn body_locals(body: &BlockBody, exclude: &[&str], dialect: &Dialect) -> HashSet<String> {
+    let mut nesting = 0usize;
+    let mut locals = HashSet::new();
+    for (_, raw) in body {
+        // A label on a block's opening or closing line is outside the block.
+        // Body lines keep their label before a colon, so no macro names are needed.
+        let (label, line) = split_line_label(strip_comment(raw).trim_end(), dialect, &|_| false);
+        if block_close(line).is_some() {
+            nesting = nesting.saturating_sub(1);
+        }
+        if nesting == 0
+            && let Some(label) = label.map(|label| dialect.local_name(label).unwrap_or(label))
+            && ExpressionParser::is_identifier(label)
+            && !exclude.contains(&label)
+        {
+            locals.insert(label.to_string());
+        }
+        if block_open(line).is_some() {
+            nesting += 1;
+        }
+    }
+    locals
+}
+
+/// Match an invocation's arguments to the macro's parameters: positional
+/// first, then `name=value`, then defaults.
+fn bind_args<'d>(
+    def: &'d MacroDef,
+    operand: Option<&str>,
+    call: &SourceLoc,
+    case_sensitive: bool,
+) -> Result<Vec<(&'d str, String)>, String> {
+    let args = split_args(operand.unwrap_or(""));
+
+    let mut positional: Vec<String> = Vec::new();
+    let mut values: Vec<Option<String>> = vec![None; def.params.len()];
+    let mut seen_named = false;
+    for arg in &args {
+        if let Some((name, value)) = arg.split_once('=')
+            && let Some(idx) = def.params.iter().position(|(p, _)| same_name(p, name.trim(), case_sensitive))
+        {
+            if values[idx].is_some() {
+                return Err(format!("{}: argument '{}' of macro '{}' given twice", call, name.trim(), def.name));
+            }
+            values[idx] = Some(unbrace(value.trim()).to_string());
+            seen_named = true;
+            continue;
+        }
+        if seen_named {
+            return Err(format!("{}: positional argument after named arguments in call to '{}'", call, def.name));
+        }
+        let idx = positional.len();
+        if idx >= def.params.len() {
+            return Err(format!(
+                "{}: macro '{}' takes {} argument(s), got {}",
+                call,
+                def.name,
+                def.params.len(),
+                args.len()
+            ));
+        }
+        if values[idx].is_some() {
+            return Err(format!("{}: argument '{}' of macro '{}' given twice", call, def.params[idx].0, def.name));
+        }
+        positional.push(unbrace(arg).to_string());
+        values[idx] = Some(positional[idx].clone());
+    }
+
+    let mut bound = Vec::with_capacity(def.params.len());
+    for ((param, default), value) in def.params.iter().zip(values) {
+        match value.or_else(|| default.clone()) {
+            Some(v) => bound.push((param.as_str(), v)),
+            None => {
+                return Err(format!("{}: missing argument '{}' for macro '{}'", call, param, def.name));
+            }
+        }
+    }
+    Ok(bound)
+}
+
+/// Per-invocation replacements for one macro body.
+struct Substitution<'s> {
+    bound: &'s [(&'s str, String)],
+    count: usize,
+    locals: &'s HashSet<String>,
+    scope: String,
+    /// Off when symbols fold case, so `Addr` also matches `ADDR`.
+    case_sensitive: bool,
+}
+
+/// Compare a parameter, loop variable or local label with a word in the body.
+fn same_name(a: &str, b: &str, case_sensitive: bool) -> bool {
+    if case_sensitive { a == b } else { a.eq_ignore_ascii_case(b) }
+}
+
+impl Substitution<'_> {
+    fn param(&self, name: &str) -> Option<&String> {
+        self.bound.iter().find(|(p, _)| same_name(p, name, self.case_sensitive)).map(|(_, v)| v)
+    }
+
+    fn is_local(&self, name: &str) -> bool {
+        if self.case_sensitive {
+            self.locals.contains(name)
+        } else {
+            self.locals.iter().any(|l| l.eq_ignore_ascii_case(name))
+        }
+    }
+
+    /// Rewrite one body line. Parameters are replaced where they appear as
+    /// whole identifiers (`name`, `\name`), by position (`\1`..`\9`), and
+    /// `\#` becomes the argument count. Labels defined in the body become
+    /// `macro__N::label`. String and character literals, comments, number
+    /// literals and directive names are copied untouched.
+    fn apply(&self, line: &str) -> Result<String, String> {
+        let chars: Vec<char> = line.chars().collect();
+        let mut out = String::with_capacity(line.len());
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            match c {
+                ';' => {
+                    out.extend(&chars[i..]);
+                    break;
+                }
+                '"' | '\'' => {
+                    let mut end = i + 1;
+                    while end < chars.len() && chars[end] != c {
+                        end += if chars[end] == '\\' { 2 } else { 1 };
+                    }
+                    let end = (end + 1).min(chars.len());
+                    out.extend(&chars[i..end]);
+                    i = end;
+                }
+                '\\' => {
+                    let next = chars.get(i + 1).copied();
+                    match next {
+                        Some('#') => {
+                            out.push_str(&self.count.to_string());
+                            i += 2;
+                        }
+                        Some(d @ '1'..='9') => {
+                            let idx = d as usize - '1' as usize;
+                            let (_, value) = self
+                                .bound
+                                .get(idx)
+                                .ok_or_else(|| format!("macro has no parameter \\{}", d))?;
+                            out.push_str(value);
+                            i += 2;
+                        }
+                        Some(n) if n.is_ascii_alphabetic() || n == '_' => {
+                            let end = ident_end(&chars, i + 1);
+                            let name: String = chars[i + 1..end].iter().collect();
+                            let value = self.param(&name).ok_or_else(|| format!("macro has no parameter '{}'", name))?;
+                            out.push_str(value);
+                            i = end;
+                        }
+                        _ => {
+                            out.push(c);
+                            i += 1;
+                        }
+                    }
+                }
+                '$' | '%' | '.' | '0'..='9' => {
+                    let end = ident_end(&chars, i + 1);
+                    out.extend(&chars[i..end]);
+                    i = end;
+                }
+                c if c.is_ascii_alphabetic() || c == '_' => {
+                    let mut end = ident_end(&chars, i);
+                    let scoped = chars.get(end) == Some(&':') && chars.get(end + 1) == Some(&':');
+                    let word: String = chars[i..end].iter().collect();
+                    if scoped {
+                        // `scope::name` is already qualified; copy it whole
+                        while chars.get(end) == Some(&':') && chars.get(end + 1) == Some(&':') {
+                            end = ident_end(&chars, end + 2);
+                        }
+                        out.extend(&chars[i..end]);
+                    } else if let Some(value) = self.param(&word) {
+                        out.push_str(value);
+                    } else if self.is_local(&word) {
+                        out.push_str(&self.scope);
+                        out.push_str("::");
+                        out.push_str(&word);
+                    } else {
+                        out.push_str(&word);
+                    }
+                    i = end;
+                }
+                _ => {
+                    out.push(c);
+                    i += 1;
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+fn ident_end(chars: &[char], mut i: usize) -> usize {
+    while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+        i += 1;
+    }
+    i
+}
+
+/// Split an invocation's operand on top-level commas. Parentheses, quotes
+/// and `{ }` groups protect commas; `{ }` is removed later by `unbrace`.
+fn split_args(s: &str) -> Vec<&str> {
+    if s.trim().is_empty() {
+        return Vec::new();
+    }
+    let mut args = Vec::new();
+    let mut depth = 0i32;
+    let mut quote: Option<char> = None;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '(' | '{') => depth += 1,
+            (None, ')' | '}') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                args.push(s[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    args.push(s[start..].trim());
+    args
+}
+
+fn unbrace(arg: &str) -> &str {
+    arg.strip_prefix('{').and_then(|a| a.strip_suffix('}')).unwrap_or(arg)
+}
+
+#[cfg(test)]
+mod expand_tests {
+    use super::*;
+
+    fn subst(line: &str, bound: &[(&str, String)], locals: &[&str]) -> String {
+        let locals = locals.iter().map(|s| s.to_string()).collect();
+        Substitution { bound, count: bound.len(), locals: &locals, scope: "m__0".to_string(), case_sensitive: true }
+            .apply(line)
+            .unwrap()
+    }
+
+    #[test]
+    fn substitutes_whole_identifiers_only() {
+        let bound = [("n", "$10".to_string())];
+        assert_eq!(subst("LDA n+1 ; n", &bound, &[]), "LDA $10+1 ; n");
+        assert_eq!(subst("LDA nn", &bound, &[]), "LDA nn");
+        assert_eq!(subst(".byte n, \"n\"", &bound, &[]), ".byte $10, \"n\"");
+        assert_eq!(subst("LDA \\n,X", &bound, &[]), "LDA $10,X");
+    }
+
+    #[test]
+    fn numbers_and_directives_are_not_parameters() {
+        let bound = [("AB", "1".to_string()), ("byte", "2".to_string())];
+        assert_eq!(subst(".byte $AB, AB", &bound, &[]), ".byte $AB, 1");
+    }
+
+    #[test]
+    fn positional_and_count() {
+        let bound = [("p", "1".to_string()), ("q", "2".to_string())];
+        assert_eq!(subst(".byte \\2, \\1, \\#", &bound, &[]), ".byte 2, 1, 2");
+    }
+
+    #[test]
+    fn locals_are_scoped() {
+        assert_eq!(subst("loop: BNE loop", &[], &["loop"]), "m__0::loop: BNE m__0::loop");
+        assert_eq!(subst("JMP other::loop", &[], &["loop"]), "JMP other::loop");
+    }
+
+    #[test]
+    fn args_split_on_top_level_commas() {
+        assert_eq!(split_args("1, (2,3), {($10),Y}, \"a,b\""), vec!["1", "(2,3)", "{($10),Y}", "\"a,b\""]);
+        assert_eq!(unbrace("{($10),Y}"), "($10),Y");
+        assert!(split_args("  ").is_empty());
+    }
+}
@@ -0,0 +1,367 @@
+//! Writing an assembled program as source for another assembler
+//!
+//! The export works on the final items, after long branches are expanded
+//! and reserved ranges bridged, so the text holds the program exactly as
+//! it was laid out here: the inverted branches, `JMP` bridges and pads
+//! appear as ordinary statements, and labels and constants keep their
+//! names.
+
+use std::ops::Range;
+
+use crate::dialect::Dialect;
+use crate::parser::expression::Expr;
+use crate::parser::lexer::ValueLayout;
+
+/// An assembler [`crate::Assembler6502::export_source`] writes for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ExportTarget {
+    /// ca65 (cc65), placed with `.org`.
+    Ca65,
+    /// ACME.
+    Acme,
+    /// 64tass.
+    Tass64,
+}
+
+impl ExportTarget {
+    /// The [`Dialect`] that reads this target's sources, so an exported
+    /// program can be assembled here again.
+    pub fn dialect(self) -> Dialect {
+        match self {
+            ExportTarget::Ca65 => Dialect::ca65(),
+            ExportTarget::Acme => Dialect::acme(),
+            ExportTarget::Tass64 => Dialect::tass64(),
+        }
+    }
+
+    fn bytes(self) -> &'static str {
+        match self {
+            ExportTarget::Acme => "!byte",
+            _ => ".byte",
+        }
+    }
+
+    fn words(self) -> &'static str {
+        match self {
+            ExportTarget::Acme => "!word",
+            _ => ".word",
+        }
+    }
+}
+
+/// How an instruction's operand is written around its expression.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum OperandForm {
+    /// No operand, or the accumulator.
+    Implied,
+    Immediate,
+    /// A zero-page or absolute address, or a branch target.
+    Direct,
+    /// `addr,X` or `addr,Y`.
+    Indexed(char),
+    IndirectX,
+    IndirectY,
+    /// `JMP (addr)`.
+    Indirect,
+}
+
+impl OperandForm {
+    /// The form of `operand`, already without its `<`/`>` override, and
+    /// the expression inside it.
+    pub(crate) fn split(operand: &str) -> (OperandForm, &str) {
+        if let Some(rest) = operand.strip_prefix('#') {
+            return (OperandForm::Immediate, rest.trim());
+        }
+        if let Some(inside) = operand.strip_prefix('(') {
+            if let Some((inner, idx)) = inside.rsplit_once(')')
+                && idx.trim().strip_prefix(',').is_some_and(|r| r.trim().eq_ignore_ascii_case("Y"))
+            {
+                return (OperandForm::IndirectY, inner.trim());
+            }
+            if let Some(inside) = inside.strip_suffix(')') {
+                if let Some((inner, idx)) = inside.rsplit_once(',')
+                    && idx.trim().eq_ignore_ascii_case("X")
+                {
+                    return (OperandForm::IndirectX, inner.trim());
+                }
+                return (OperandForm::Indirect, inside.trim());
+            }
+        }
+        if let Some((addr, idx)) = operand.split_once(',') {
+            let register = idx.trim().chars().next().unwrap_or('X').to_ascii_uppercase();
+            return (OperandForm::Indexed(register), addr.trim());
+        }
+        (OperandForm::Direct, operand)
+    }
+}
+
+/// An address size the target must be told, because it would pick the
+/// other one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AddressSize {
+    Auto,
+    ZeroPage,
+    Absolute,
+}
+
+/// Statements in column 0 are labels and constants; the rest are
+/// indented.
+const INDENT: &str = "        ";
+
+/// Values per data line.
+const PER_LINE: usize = 16;
+
+/// The exported text, one statement per line.
+pub(crate) struct SourceWriter {
+    target: ExportTarget,
+    out: String,
+}
+
+impl SourceWriter {
+    pub(crate) fn new(target: ExportTarget) -> Self {
+        Self { target, out: String::new() }
+    }
+
+    pub(crate) fn finish(self) -> String {
+        self.out
+    }
+
+    fn line(&mut self, statement: &str) {
+        self.out.push_str(INDENT);
+        self.out.push_str(statement);
+        self.out.push('\n');
+    }
+
+    fn column0(&mut self, statement: &str) {
+        self.out.push_str(statement);
+        self.out.push('\n');
+    }
+
+    pub(crate) fn comment(&mut self, text: &str) {
+        self.line(&format!("; {}", text));
+    }
+
+    pub(crate) fn label(&mut self, name: &str) {
+        match self.target {
+            ExportTarget::Ca65 => self.column0(&format!("{}:", name)),
+            ExportTarget::Acme | ExportTarget::Tass64 => self.column0(name),
+        }
+    }
+
+    /// `name = value`; a variable, assigned more than once, uses the
+    /// target's form for reassignment.
+    pub(crate) fn constant(&mut self, name: &str, value: &Expr, variable: bool) {
+        let value = expr(value);
+        let statement = match (self.target, variable) {
+            (_, false) => format!("{} = {}", name, value),
+            (ExportTarget::Ca65, true) => format!("{} .set {}", name, value),
+            (ExportTarget::Acme, true) => format!("!set {} = {}", name, value),
+            (ExportTarget::Tass64, true) => format!("{} := {}", name, value),
+        };
+        self.column0(&statement);
+    }
+
+    /// Start the output at `address`. In ca65 that is the linker's job;
+    /// `.org` only gives the PC.
+    pub(crate) fn org(&mut self, address: u16) {
+        match self.target {
+            ExportTarget::Ca65 => self.line(&format!(".org {}", number(address as u32))),
+            ExportTarget::Acme | ExportTarget::Tass64 => self.line(&format!("* = {}", number(address as u32))),
+        }
+    }
+
+    /// Continue the output at `address` after output that ends at `pc`.
+    /// ca65's `.org` doesn't move the output, so a gap up to `address` is
+    /// filled with zeros, and code below `pc` starts a segment of its own,
+    /// `ORG_XXXX`, for the linker configuration to place.
+    pub(crate) fn reorg(&mut self, pc: u16, address: u16) {
+        match self.target {
+            ExportTarget::Ca65 if address >= pc => {
+                if address > pc {
+                    self.fill((address - pc) as usize, 0);
+                }
+            }
+            ExportTarget::Ca65 => {
+                self.line(&format!(".segment \"ORG_{:04X}\"", address));
+                self.org(address);
+            }
+            ExportTarget::Acme | ExportTarget::Tass64 => self.org(address),
+        }
+    }
+
+    /// Open a block assembled for `address`.
+    pub(crate) fn logical(&mut self, address: u16) {
+        match self.target {
+            ExportTarget::Ca65 => self.org(address),
+            ExportTarget::Acme => self.line(&format!("!pseudopc {} {{", number(address as u32))),
+            ExportTarget::Tass64 => self.line(&format!(".logical {}", number(address as u32))),
+        }
+    }
+
+    /// Close the innermost block; `physical` is where the code after it
+    /// goes, which ca65 has to be told with another `.org`.
+    pub(crate) fn real_pc(&mut self, physical: u16) {
+        match self.target {
+            ExportTarget::Ca65 => self.org(physical),
+            ExportTarget::Acme => self.line("}"),
+            ExportTarget::Tass64 => self.line(".here"),
+        }
+    }
+
+    pub(crate) fn instruction(&mut self, mnemonic: &str, form: OperandForm, value: Option<&Expr>, size: AddressSize) {
+        let value = value.map(expr).unwrap_or_default();
+        let operand = match form {
+            OperandForm::Implied => String::new(),
+            OperandForm::Immediate => format!("#{}", value),
+            OperandForm::Direct => value,
+            OperandForm::Indexed(register) => format!("{},{}", value, register),
+            OperandForm::IndirectX => format!("({},X)", value),
+            OperandForm::IndirectY => format!("({}),Y", value),
+            OperandForm::Indirect => format!("({})", value),
+        };
+        let (mnemonic, operand) = match (self.target, size) {
+            (_, AddressSize::Auto) => (mnemonic.to_string(), operand),
+            (ExportTarget::Ca65, AddressSize::ZeroPage) => (mnemonic.to_string(), format!("z:{}", operand)),
+            (ExportTarget::Ca65, AddressSize::Absolute) => (mnemonic.to_string(), format!("a:{}", operand)),
+            (ExportTarget::Acme, AddressSize::ZeroPage) => (format!("{}+1", mnemonic), operand),
+            (ExportTarget::Acme, AddressSize::Absolute) => (format!("{}+2", mnemonic), operand),
+            (ExportTarget::Tass64, AddressSize::ZeroPage) => (mnemonic.to_string(), format!("@b {}", operand)),
+            (ExportTarget::Tass64, AddressSize::Absolute) => (mnemonic.to_string(), format!("@w {}", operand)),
+        };
+        if operand.is_empty() {
+            self.line(&mnemonic);
+        } else {
+            self.line(&format!("{} {}", mnemonic, operand));
+        }
+    }
+
+    fn data(&mut self, directive: &str, values: &[String]) {
+        for chunk in values.chunks(PER_LINE) {
+            self.line(&format!("{} {}", directive, chunk.join(", ")));
+        }
+    }
+
+    /// One byte per expression; the caller keeps each one below $100.
+    pub(crate) fn bytes(&mut self, exprs: &[Expr]) {
+        let values: Vec<String> = exprs.iter().map(expr).collect();
+        self.data(self.target.bytes(), &values);
+    }
+
+    pub(crate) fn words(&mut self, exprs: &[Expr]) {
+        let values: Vec<String> = exprs.iter().map(expr).collect();
+        self.data(self.target.words(), &values);
+    }
+
+    /// `.dword`, `.dbyte`, `.rta`, `.lobytes` and `.hibytes`, with the
+    /// target's directive where it has one and bytes or words otherwise.
+    pub(crate) fn values(&mut self, layout: ValueLayout, exprs: &[Expr]) {
+        let each = |f: fn(&str) -> String| -> Vec<String> { exprs.iter().map(|e| f(&operand(e))).collect() };
+        let plain: Vec<String> = exprs.iter().map(expr).collect();
+        match (self.target, layout) {
+            (ExportTarget::Ca65 | ExportTarget::Tass64, ValueLayout::Dword) => self.data(".dword", &plain),
+            (ExportTarget::Acme, ValueLayout::Dword) => self.data("!32", &plain),
+            (ExportTarget::Ca65, ValueLayout::BigWord) => self.data(".dbyt", &plain),
+            (ExportTarget::Acme, ValueLayout::BigWord) => self.data("!be16", &plain),
+            (ExportTarget::Tass64, ValueLayout::BigWord) => {
+                self.data(".byte", &each(|e| format!(">{}, <{}", e, e)))
+            }
+            (ExportTarget::Tass64, ValueLayout::Rta) => self.data(".rta", &plain),
+            (target, ValueLayout::Rta) => self.data(target.words(), &each(|e| format!("{}-1", e))),
+            (ExportTarget::Ca65, ValueLayout::LoBytes) => self.data(".lobytes", &plain),
+            (ExportTarget::Ca65, ValueLayout::HiBytes) => self.data(".hibytes", &plain),
+            (target, ValueLayout::LoBytes) => self.data(target.bytes(), &each(|e| format!("<{}", e))),
+            (target, ValueLayout::HiBytes) => self.data(target.bytes(), &each(|e| format!(">{}", e))),
+        }
+    }
+
+    /// `count` bytes of `byte`.
+    pub(crate) fn fill(&mut self, count: usize, byte: u8) {
+        let (count, byte) = (number(count as u32), number(byte as u32));
+        match self.target {
+            ExportTarget::Ca65 => self.line(&format!(".res {}, {}", count, byte)),
+            ExportTarget::Acme => self.line(&format!("!fill {}, {}", count, byte)),
+            ExportTarget::Tass64 => self.line(&format!(".fill {}, {}", count, byte)),
+        }
+    }
+
+    /// Move the PC `count` bytes on without emitting anything. ca65 has
+    /// no such directive: its `.res` writes zeros outside a BSS segment.
+    pub(crate) fn skip(&mut self, count: usize) {
+        let count = number(count as u32);
+        match self.target {
+            ExportTarget::Ca65 => self.line(&format!(".res {}", count)),
+            ExportTarget::Acme => self.line(&format!("!skip {}", count)),
+            ExportTarget::Tass64 => self.line(&format!("* = * + {}", count)),
+        }
+    }
+
+    /// The `range` of the `size`-byte file at `path`.
+    pub(crate) fn incbin(&mut self, path: &str, range: Range<usize>, size: usize) {
+        let whole = range.start == 0 && range.end == size;
+        let (offset, length) = (number(range.start as u32), number(range.len() as u32));
+        let statement = match self.target {
+            ExportTarget::Ca65 if whole => format!(".incbin \"{}\"", path),
+            ExportTarget::Ca65 => format!(".incbin \"{}\", {}, {}", path, offset, length),
+            // ACME takes the length first
+            ExportTarget::Acme if whole => format!("!binary \"{}\"", path),
+            ExportTarget::Acme => format!("!binary \"{}\", {}, {}", path, length, offset),
+            ExportTarget::Tass64 if whole => format!(".binary \"{}\"", path),
+            ExportTarget::Tass64 => format!(".binary \"{}\", {}, {}", path, offset, length),
+        };
+        self.line(&statement);
+    }
+}
+
+/// A symbol's name in the exported text: scoped names `a::b` are `a__b`.
+pub(crate) fn export_name(name: &str) -> String {
+    name.replace("::", "__")
+}
+
+/// `$12` below $100, `$1234` above, so ACME, which reads `$0012` as an
+/// absolute address, sizes it by value like the others.
+fn number(value: u32) -> String {
+    match value {
+        0..=0xFF => format!("${:02X}", value),
+        0x100..=0xFFFF => format!("${:04X}", value),
+        _ => format!("${:X}", value),
+    }
+}
+
+/// `e` in the syntax the three targets share. Bank numbers, characters,
+/// comparisons and logic have been evaluated to numbers by the caller.
+fn expr(e: &Expr) -> String {
+    match e {
+        Expr::Number(value) => number(*value),
+        Expr::Label(name) => name.clone(),
+        Expr::CurrentAddress => "*".to_string(),
+        Expr::Immediate(inner) => format!("#{}", expr(inner)),
+        Expr::LowByte(inner) => format!("<{}", operand(inner)),
+        Expr::HighByte(inner) => format!(">{}", operand(inner)),
+        Expr::Add(l, r) => format!("{}+{}", term(l, false), term(r, true)),
+        Expr::Sub(l, r) => format!("{}-{}", term(l, false), term(r, true)),
+        Expr::Mul(l, r) => format!("{}*{}", operand(l), operand(r)),
+        Expr::Div(l, r) => format!("{}/{}", operand(l), operand(r)),
+        Expr::Char(_) | Expr::Bank(_)
+        | Expr::Eq(..) | Expr::Ne(..) | Expr::Lt(..) | Expr::Le(..) | Expr::Gt(..) | Expr::Ge(..)
+        | Expr::And(..) | Expr::Or(..) | Expr::Not(_) => unreachable!("evaluated before export"),
+    }
+}
+
+/// `e` as the operand of a prefix or of `*` and `/`: parenthesized
+/// unless it is a single value. The targets bind `<` tighter than `+`,
+/// so `<(ptr+1)` needs them.
+fn operand(e: &Expr) -> String {
+    match e {
+        Expr::Number(_) | Expr::Label(_) | Expr::CurrentAddress => expr(e),
+        _ => format!("({})", expr(e)),
+    }
+}
+
+/// `e` as a side of `+` or `-`; a sum on the right needs parentheses.
+fn term(e: &Expr, right: bool) -> String {
+    match e {
+        Expr::Add(..) | Expr::Sub(..) if right => format!("({})", expr(e)),
+        Expr::LowByte(_) | Expr::HighByte(_) | Expr::Immediate(_) => format!("({})", expr(e)),
+        _ => expr(e),
+    }
+}
@@ -0,0 +1,183 @@
+//! Where `.include` and `.incbin` files come from
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+/// Source of the files a program includes. The default reads the
+/// filesystem; [`MemoryFileProvider`] serves files held by the host instead.
+pub trait FileProvider {
+    /// The whole contents of `path`.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// Whether `path` names a file. Used to search include directories.
+    fn is_file(&self, path: &Path) -> bool;
+
+    /// A name that is the same for every spelling of the same file, used
+    /// by the include guards.
+    fn canonical(&self, path: &Path) -> PathBuf {
+        normalize(path)
+    }
+}
+
+/// Reads files from disk, relative paths from the working directory.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FsFileProvider;
+
+impl FileProvider for FsFileProvider {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn canonical(&self, path: &Path) -> PathBuf {
+        path.canonicalize().unwrap_or_else(|_| normalize(path))
+    }
+}
+
+/// Files held in memory, keyed by path. `a/../b.s` and `./b.s` both find
+/// a file added as `b.s`.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryFileProvider {
+    files: HashMap<PathBuf, Vec<u8>>,
+}
+
+impl MemoryFileProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add or replace a file.
+    pub fn insert(&mut self, path: impl AsRef<Path>, contents: impl Into<Vec<u8>>) {
+        self.files.insert(normalize(path.as_ref()), contents.into());
+    }
+
+    /// Builder form of [`Self::insert`].
+    pub fn with_file(mut self, path: impl AsRef<Path>, contents: impl Into<Vec<u8>>) -> Self {
+        self.insert(path, contents);
+        self
+    }
+}
+
+impl FileProvider for MemoryFileProvider {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.files
+            .get(&normalize(path))
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such file"))
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.files.contains_key(&normalize(path))
+    }
+}
+
+/// Drop `.` components and fold `dir/..` without touching the disk.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if matches!(out.components().next_back(), Some(Component::Normal(_))) => {
+                out.pop();
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+/// The provider plus the contents read during the current assembly. Each
+/// file is read once, so sizing, emitting and the listing all see the
+/// same bytes even if the file changes meanwhile.
+pub struct FileCache {
+    provider: Box<dyn FileProvider>,
+    cache: RefCell<HashMap<PathBuf, Rc<[u8]>>>,
+}
+
+impl FileCache {
+    pub fn new(provider: Box<dyn FileProvider>) -> Self {
+        Self { provider, cache: RefCell::new(HashMap::new()) }
+    }
+
+    pub fn set_provider(&mut self, provider: Box<dyn FileProvider>) {
+        self.provider = provider;
+        self.clear();
+    }
+
+    /// Forget cached contents; called at the start of every assembly.
+    pub fn clear(&self) {
+        self.cache.borrow_mut().clear();
+    }
+
+    pub fn read(&self, path: &Path) -> io::Result<Rc<[u8]>> {
+        let key = self.provider.canonical(path);
+        if let Some(bytes) = self.cache.borrow().get(&key) {
+            return Ok(Rc::clone(bytes));
+        }
+        let bytes: Rc<[u8]> = self.provider.read(path)?.into();
+        self.cache.borrow_mut().insert(key, Rc::clone(&bytes));
+        Ok(bytes)
+    }
+
+    pub fn is_file(&self, path: &Path) -> bool {
+        self.provider.is_file(path)
+    }
+
+    pub fn canonical(&self, path: &Path) -> PathBuf {
+        self.provider.canonical(path)
+    }
+}
+
+impl Default for FileCache {
+    fn default() -> Self {
+        Self::new(Box::new(FsFileProvider))
+    }
+}
+
+#[cfg(test)]
+mod files_tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn memory_paths_are_normalized() {
+        let files = MemoryFileProvider::new().with_file("lib/b.s", "x");
+        assert!(files.is_file(Path::new("./lib/b.s")));
+        assert!(files.is_file(Path::new("lib/../lib/b.s")));
+        assert!(!files.is_file(Path::new("b.s")));
+        assert_eq!(files.read(Path::new("lib/./b.s")).unwrap(), b"x");
+        assert_eq!(files.read(Path::new("nope")).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    /// Counts reads and changes its answer each time.
+    struct Changing(Rc<Cell<u8>>);
+
+    impl FileProvider for Changing {
+        fn read(&self, _path: &Path) -> io::Result<Vec<u8>> {
+            self.0.set(self.0.get() + 1);
+            Ok(vec![self.0.get()])
+        }
+
+        fn is_file(&self, _path: &Path) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn cache_reads_each_file_once() {
+        let reads = Rc::new(Cell::new(0));
+        let cache = FileCache::new(Box::new(Changing(Rc::clone(&reads))));
+        assert_eq!(&*cache.read(Path::new("a.bin")).unwrap(), &[1]);
+        assert_eq!(&*cache.read(Path::new("./a.bin")).unwrap(), &[1]);
+        assert_eq!(reads.get(), 1);
+        cache.clear();
+        assert_eq!(&*cache.read(Path::new("a.bin")).unwrap(), &[2]);
+    }
+}
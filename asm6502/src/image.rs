@@ -0,0 +1,157 @@
+//! The assembled program as placed in memory: runs of bytes at their
+//! load addresses
+
+/// A run of bytes at consecutive addresses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    /// The bank the bytes are in; `None` outside banked memory.
+    pub bank: Option<u32>,
+    pub start: u16,
+    pub bytes: Vec<u8>,
+}
+
+impl Chunk {
+    /// The address just past the chunk; `$10000` for a chunk that ends
+    /// at `$FFFF`.
+    pub fn end(&self) -> u32 {
+        self.start as u32 + self.bytes.len() as u32
+    }
+}
+
+/// Where every output byte goes. `assemble_bytes` returns the bytes in
+/// emission order; the image keeps each `*=` region (or segment area) at
+/// its own address, sorted by address and with touching regions merged.
+///
+/// Banks share CPU addresses, so banked chunks are kept apart, sorted by
+/// bank after the unbanked ones. `start`, `end`, `byte_at` and `to_flat`
+/// only see unbanked memory; `bank_chunks` and `bank_byte_at` look into
+/// one bank.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AssembledImage {
+    chunks: Vec<Chunk>,
+}
+
+impl AssembledImage {
+    /// Build from the output bytes and the address and bank of each.
+    pub(crate) fn from_bytes(bytes: &[u8], addrs: &[u16], banks: &[Option<u32>]) -> Self {
+        let mut chunks: Vec<Chunk> = Vec::new();
+        for ((&byte, &addr), &bank) in bytes.iter().zip(addrs).zip(banks) {
+            match chunks.last_mut() {
+                Some(chunk) if chunk.bank == bank && chunk.end() == addr as u32 => chunk.bytes.push(byte),
+                _ => chunks.push(Chunk { bank, start: addr, bytes: vec![byte] }),
+            }
+        }
+        chunks.sort_by_key(|c| (c.bank, c.start));
+        let mut merged: Vec<Chunk> = Vec::with_capacity(chunks.len());
+        for chunk in chunks {
+            match merged.last_mut() {
+                Some(last) if last.bank == chunk.bank && last.end() == chunk.start as u32 => {
+                    last.bytes.extend(chunk.bytes)
+                }
+                _ => merged.push(chunk),
+            }
+        }
+        Self { chunks: merged }
+    }
+
+    pub fn chunks(&self) -> &[Chunk] {
+        &self.chunks
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// The chunks in `bank`, by address.
+    pub fn bank_chunks(&self, bank: u32) -> impl Iterator<Item = &Chunk> {
+        self.chunks.iter().filter(move |c| c.bank == Some(bank))
+    }
+
+    fn unbanked(&self) -> impl DoubleEndedIterator<Item = &Chunk> {
+        self.chunks.iter().filter(|c| c.bank.is_none())
+    }
+
+    /// The lowest address written.
+    pub fn start(&self) -> Option<u16> {
+        self.unbanked().next().map(|c| c.start)
+    }
+
+    /// The address just past the highest byte written.
+    pub fn end(&self) -> Option<u32> {
+        self.unbanked().next_back().map(Chunk::end)
+    }
+
+    /// The byte written at `addr`, if any.
+    pub fn byte_at(&self, addr: u16) -> Option<u8> {
+        find_byte(self.unbanked(), addr)
+    }
+
+    /// The byte written at `addr` in `bank`, if any.
+    pub fn bank_byte_at(&self, bank: u32, addr: u16) -> Option<u8> {
+        find_byte(self.bank_chunks(bank), addr)
+    }
+
+    /// One block from `start()` to `end()`, with the gaps between chunks
+    /// filled with `fill`.
+    pub fn to_flat(&self, fill: u8) -> Vec<u8> {
+        let (Some(start), Some(end)) = (self.start(), self.end()) else {
+            return Vec::new();
+        };
+        let mut flat = vec![fill; (end - start as u32) as usize];
+        for chunk in self.unbanked() {
+            let offset = (chunk.start - start) as usize;
+            flat[offset..offset + chunk.bytes.len()].copy_from_slice(&chunk.bytes);
+        }
+        flat
+    }
+}
+
+fn find_byte<'a>(mut chunks: impl Iterator<Item = &'a Chunk>, addr: u16) -> Option<u8> {
+    let chunk = chunks.find(|c| c.start <= addr && (addr as u32) < c.end())?;
+    Some(chunk.bytes[(addr - chunk.start) as usize])
+}
+
+#[cfg(test)]
+mod image_tests {
+    use super::*;
+
+    #[test]
+    fn chunks_are_sorted_and_merged() {
+        let image = AssembledImage::from_bytes(&[1, 2, 3, 4, 5], &[0xC000, 0xC001, 0x0800, 0x0801, 0xC002], &[None; 5]);
+        assert_eq!(
+            image.chunks(),
+            &[
+                Chunk { bank: None, start: 0x0800, bytes: vec![3, 4] },
+                Chunk { bank: None, start: 0xC000, bytes: vec![1, 2, 5] },
+            ]
+        );
+        assert_eq!(image.byte_at(0xC002), Some(5));
+        assert_eq!(image.byte_at(0x0802), None);
+        assert_eq!(image.end(), Some(0xC003));
+    }
+
+    #[test]
+    fn flat_render_fills_gaps() {
+        let image = AssembledImage::from_bytes(&[1, 2, 3], &[0x1000, 0x1003, 0xFFFF], &[None; 3]);
+        let flat = image.to_flat(0xEA);
+        assert_eq!(flat.len(), 0x10000 - 0x1000);
+        assert_eq!(&flat[..5], &[1, 0xEA, 0xEA, 2, 0xEA]);
+        assert_eq!(flat.last(), Some(&3));
+        assert!(AssembledImage::default().to_flat(0).is_empty());
+    }
+
+    #[test]
+    fn banks_share_addresses() {
+        let image = AssembledImage::from_bytes(
+            &[1, 2, 3, 4],
+            &[0x8000, 0x8001, 0x8000, 0x0200],
+            &[Some(0), Some(0), Some(1), None],
+        );
+        assert_eq!(image.chunks()[0], Chunk { bank: None, start: 0x0200, bytes: vec![4] });
+        assert_eq!(image.bank_byte_at(0, 0x8001), Some(2));
+        assert_eq!(image.bank_byte_at(1, 0x8000), Some(3));
+        assert_eq!(image.byte_at(0x8000), None);
+        assert_eq!(image.bank_chunks(1).count(), 1);
+        assert_eq!(image.to_flat(0), vec![4]);
+    }
+}
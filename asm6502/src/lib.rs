@@ -1,8 +1,8 @@
 //! 6502 minimal assembler with optional human-readable listing (feature: "listing")
 //! - Strict hex-only syntax ($ for hex numbers)
 //! - Optional address-mode forcing with operand prefixes:
-//!     "<" => force Zero Page (e.g. LDA <$80, LDA <$80,X)
-//!     ">" => force Absolute  (e.g. LDA >$80, LDA >$80,X)
+//!   "<" => force Zero Page (e.g. LDA <$80, LDA <$80,X)
+//!   ">" => force Absolute  (e.g. LDA >$80, LDA >$80,X)
 //! - Adaptive long-branch fixing pass count (bounded by number of branches + 2)
 //! - Reserved memory ranges: skip configured regions with `JMP` + zero-fill
 //!
@@ -19,6 +19,26 @@
 //! - **Reserved ranges**: `add_reserved_range(start, end)` skips a region with
 //!   a `JMP <end+1>`; the range is zero-filled and indivisible data blocks
 //!   are pushed past it.
+//! - **Macros**: `.macro name p1, p2=default` … `.endmacro`, expanded
+//!   before layout with per-expansion local labels.
+//! - **Conditional assembly and loops**: `.if`/`.ifdef`, `.rept` and `.for`.
+//! - **Structs and enums**: `.struct`/`.enum` define field offsets and
+//!   numbered constants as `Name::member`; `.dstruct` emits an instance.
+//! - **Sparse output**: `assemble_image` keeps each `*=` region at its
+//!   address; overlapping writes are errors that name both lines.
+//! - **Segments**: `.segment "NAME"`, placed by a host-supplied
+//!   [`MemoryConfig`] of memory areas; BSS segments reserve without emitting.
+//! - **Banks**: `.bank n, size, cpu_base` for bank-switched ROMs, with
+//!   `bank(label)` in expressions and per-bank reserved ranges.
+//! - **Labels on any line** (`loop: LDA #1`), and colon-less labels in
+//!   column 0 with the [`Dialect`] option `colonless_labels`.
+//! - **Dialects**: [`Dialect::ca65`], [`Dialect::acme`], [`Dialect::tass64`],
+//!   [`Dialect::dasm`], [`Dialect::kick`] and [`Dialect::nesasm`] read
+//!   sources written for those assemblers.
+//! - **Statements and comments**: `LDA #0 : STA $D020` on one line; `;`,
+//!   `/* … */` and `.comment` … `.endcomment` comments.
+//! - **Source export**: [`Assembler6502::export_source`] writes the laid-out
+//!   program as ca65, ACME or 64tass source ([`ExportTarget`]).
 //!
 //! ## Optional Features
 //! - `listing`: enables functions to print and save human-readable assembly listings.
@@ -52,9 +72,27 @@ mod parser;
 mod addressing;
 mod eval;
 mod reserved;
+mod source;
+mod encoding;
+mod segment;
+mod dialect;
+mod export;
+mod bank;
+mod expand;
+mod files;
+mod image;
 mod assembler;
 
 // Public exports
-pub use error::AsmError;
+pub use error::{AsmError, Warning};
+pub use files::{FileProvider, FsFileProvider, MemoryFileProvider};
 pub use assembler::{Assembler6502, Item};
+pub use image::{AssembledImage, Chunk};
 pub use reserved::ReservedRange;
+pub use dialect::{Dialect, DirectiveMap};
+pub use export::ExportTarget;
+pub use segment::{MemoryArea, MemoryConfig, Segment, SegmentKind, DEFAULT_SEGMENT};
+pub use source::{Expansion, SourceLoc};
+pub use encoding::BUILTIN_ENCODINGS;
+pub use expand::{MAX_LOOP_ITERATIONS, MAX_MACRO_DEPTH};
+pub use symbol::{AccessKind, Reference, Symbol, SymbolKind, SymbolOrder};
@@ -109,16 +109,16 @@ impl OpcodeTables {
             ("zeropage", 0x24), ("absolute", 0x2C),
         ]);
         let asl: HashMap<&'static str, u8> = HashMap::from_iter([
-            ("zeropage", 0x06), ("zeropage,X", 0x16), ("absolute", 0x0E), ("absolute,X", 0x1E),
+            ("accumulator", 0x0A), ("zeropage", 0x06), ("zeropage,X", 0x16), ("absolute", 0x0E), ("absolute,X", 0x1E),
         ]);
         let lsr: HashMap<&'static str, u8> = HashMap::from_iter([
-            ("zeropage", 0x46), ("zeropage,X", 0x56), ("absolute", 0x4E), ("absolute,X", 0x5E),
+            ("accumulator", 0x4A), ("zeropage", 0x46), ("zeropage,X", 0x56), ("absolute", 0x4E), ("absolute,X", 0x5E),
         ]);
         let rol: HashMap<&'static str, u8> = HashMap::from_iter([
-            ("zeropage", 0x26), ("zeropage,X", 0x36), ("absolute", 0x2E), ("absolute,X", 0x3E),
+            ("accumulator", 0x2A), ("zeropage", 0x26), ("zeropage,X", 0x36), ("absolute", 0x2E), ("absolute,X", 0x3E),
         ]);
         let ror: HashMap<&'static str, u8> = HashMap::from_iter([
-            ("zeropage", 0x66), ("zeropage,X", 0x76), ("absolute", 0x6E), ("absolute,X", 0x7E),
+            ("accumulator", 0x6A), ("zeropage", 0x66), ("zeropage,X", 0x76), ("absolute", 0x6E), ("absolute,X", 0x7E),
         ]);
         let dec: HashMap<&'static str, u8> = HashMap::from_iter([
             ("zeropage", 0xC6), ("zeropage,X", 0xD6), ("absolute", 0xCE), ("absolute,X", 0xDE),
@@ -1,12 +1,15 @@
 //! Expression parsing for assembly operands
 
 use super::number::NumberParser;
+use super::text::{literal_end, parse_char_literal, TextChar};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
     Number(u32),           // Changed to u32 to support $10000
     Label(String),
     CurrentAddress,        // * symbol
+    Char(char),            // 'A' - mapped through the active text encoding
+    Bank(String),          // bank(label) - the bank a label is placed in
     Immediate(Box<Expr>),  // #value - immediate addressing mode
     LowByte(Box<Expr>),    // <value - extract low byte
     HighByte(Box<Expr>),   // >value - extract high byte
@@ -14,8 +17,110 @@ pub enum Expr {
     Sub(Box<Expr>, Box<Expr>),
     Mul(Box<Expr>, Box<Expr>),
     Div(Box<Expr>, Box<Expr>),
+    Eq(Box<Expr>, Box<Expr>),  // == (or =) - comparisons yield 1 or 0
+    Ne(Box<Expr>, Box<Expr>),
+    Lt(Box<Expr>, Box<Expr>),
+    Le(Box<Expr>, Box<Expr>),
+    Gt(Box<Expr>, Box<Expr>),
+    Ge(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>), // &&
+    Or(Box<Expr>, Box<Expr>),  // ||
+    Not(Box<Expr>),            // !value
 }
 
+impl Expr {
+    /// Call `f` on this expression and every sub-expression.
+    pub fn visit(&self, f: &mut dyn FnMut(&Expr)) {
+        f(self);
+        match self {
+            Expr::Number(_) | Expr::Label(_) | Expr::CurrentAddress | Expr::Char(_) | Expr::Bank(_) => {}
+            Expr::Immediate(inner) | Expr::LowByte(inner) | Expr::HighByte(inner) | Expr::Not(inner) => {
+                inner.visit(f)
+            }
+            Expr::Add(l, r)
+            | Expr::Sub(l, r)
+            | Expr::Mul(l, r)
+            | Expr::Div(l, r)
+            | Expr::Eq(l, r)
+            | Expr::Ne(l, r)
+            | Expr::Lt(l, r)
+            | Expr::Le(l, r)
+            | Expr::Gt(l, r)
+            | Expr::Ge(l, r)
+            | Expr::And(l, r)
+            | Expr::Or(l, r) => {
+                l.visit(f);
+                r.visit(f);
+            }
+        }
+    }
+
+    /// Call `f` on every sub-expression and then on this expression,
+    /// allowing each to be replaced.
+    pub fn visit_mut(&mut self, f: &mut dyn FnMut(&mut Expr)) {
+        match self {
+            Expr::Number(_) | Expr::Label(_) | Expr::CurrentAddress | Expr::Char(_) | Expr::Bank(_) => {}
+            Expr::Immediate(inner) | Expr::LowByte(inner) | Expr::HighByte(inner) | Expr::Not(inner) => {
+                inner.visit_mut(f)
+            }
+            Expr::Add(l, r)
+            | Expr::Sub(l, r)
+            | Expr::Mul(l, r)
+            | Expr::Div(l, r)
+            | Expr::Eq(l, r)
+            | Expr::Ne(l, r)
+            | Expr::Lt(l, r)
+            | Expr::Le(l, r)
+            | Expr::Gt(l, r)
+            | Expr::Ge(l, r)
+            | Expr::And(l, r)
+            | Expr::Or(l, r) => {
+                l.visit_mut(f);
+                r.visit_mut(f);
+            }
+        }
+        f(self);
+    }
+}
+
+/// A character literal: `'A'` becomes `Char`, a `'\xNN'` byte a `Number`.
+fn char_literal(s: &str) -> Option<Expr> {
+    Some(match parse_char_literal(s)? {
+        TextChar::Char(c) => Expr::Char(c),
+        TextChar::Byte(b) => Expr::Number(b as u32),
+    })
+}
+
+/// `s` with the inside of every character literal blanked out, so the
+/// operator scans don't mistake `'+'` for an addition. Byte offsets are
+/// unchanged.
+fn mask_char_literals(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut i = 0;
+    while let Some(offset) = s[i..].find('\'') {
+        let start = i + offset;
+        let end = literal_end(s, start);
+        out.push_str(&s[i..start]);
+        if parse_char_literal(&s[start..end]).is_some() {
+            out.push('\'');
+            out.extend(std::iter::repeat_n('_', end - start - 2));
+            out.push('\'');
+        } else {
+            out.push_str(&s[start..end]);
+        }
+        i = end;
+    }
+    out.push_str(&s[i..]);
+    out
+}
+
+/// Binary operators looser than `+`/`-`, loosest level first.
+const LOGIC_LEVELS: [&[&str]; 3] = [
+    &["||"],
+    &["&&"],
+    &["==", "!=", "<=", ">=", "<", ">", "="],
+];
+
 pub struct ExpressionParser;
 
 impl ExpressionParser {
@@ -29,6 +134,31 @@ impl ExpressionParser {
             return Ok(Expr::Immediate(Box::new(inner)));
         }
 
+        // Logical and comparison operators (loosest binding)
+        for ops in LOGIC_LEVELS {
+            if let Some((pos, op)) = Self::find_logic_op(s, ops) {
+                let left = Box::new(Self::parse(&s[..pos])?);
+                let right = Box::new(Self::parse(&s[pos + op.len()..])?);
+                return Ok(match op {
+                    "||" => Expr::Or(left, right),
+                    "&&" => Expr::And(left, right),
+                    "==" | "=" => Expr::Eq(left, right),
+                    "!=" => Expr::Ne(left, right),
+                    "<=" => Expr::Le(left, right),
+                    ">=" => Expr::Ge(left, right),
+                    "<" => Expr::Lt(left, right),
+                    ">" => Expr::Gt(left, right),
+                    _ => unreachable!(),
+                });
+            }
+        }
+
+        // Logical not
+        if let Some(rest) = s.strip_prefix('!') {
+            let inner = Self::parse(rest.trim())?;
+            return Ok(Expr::Not(Box::new(inner)));
+        }
+
         // Handle low byte operator (<)
         if let Some(rest) = s.strip_prefix('<') {
             let inner = Self::parse(rest.trim())?;
@@ -46,6 +176,10 @@ impl ExpressionParser {
             return Ok(Expr::CurrentAddress);
         }
 
+        if let Some(c) = char_literal(s) {
+            return Ok(c);
+        }
+
         // Check if it contains operators - if so, parse as expression
         if s.contains('+') || s.contains('-') || s.contains('*') || s.contains('/') || s.contains('(') || s.contains(')') {
             // Has operators - parse as expression
@@ -65,6 +199,55 @@ impl ExpressionParser {
         Err(format!("Invalid expression: {}", s))
     }
 
+    /// Find the rightmost of `ops` outside parentheses. A lone `<` or `>`
+    /// only counts as a comparison when it follows an operand; otherwise
+    /// it is the low/high byte prefix.
+    fn find_logic_op(s: &str, ops: &[&'static str]) -> Option<(usize, &'static str)> {
+        let masked = mask_char_literals(s);
+        let s = masked.as_str();
+        let bytes = s.as_bytes();
+        let mut depth = 0i32;
+        let mut found = None;
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'(' => depth += 1,
+                b')' => depth -= 1,
+                _ if depth == 0 => {
+                    // Two-character operators take priority over their prefixes
+                    let op = ["||", "&&", "==", "!=", "<=", ">=", "<", ">", "="]
+                        .into_iter()
+                        .find(|op| s[i..].starts_with(op));
+                    if let Some(op) = op {
+                        let binary = Self::ends_with_operand(&s[..i]);
+                        if binary && ops.contains(&op) {
+                            found = Some((i, op));
+                        }
+                        i += op.len();
+                        continue;
+                    }
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+        found
+    }
+
+    /// Whether `left` ends in something a binary operator can follow. A
+    /// trailing `*` counts when it is the current address, not a multiply.
+    fn ends_with_operand(left: &str) -> bool {
+        const OPERATORS: [char; 12] = ['+', '-', '*', '/', '(', '<', '>', '=', '!', '&', '|', '#'];
+        let left = left.trim_end();
+        match left.strip_suffix('*') {
+            Some(before) => {
+                let before = before.trim_end();
+                before.is_empty() || before.ends_with(OPERATORS)
+            }
+            None => !left.is_empty() && !left.ends_with(OPERATORS),
+        }
+    }
+
     /// Parse addition and subtraction (lowest precedence)
     fn parse_additive(s: &str) -> Result<Expr, String> {
         // Find rightmost + or - that's not inside parentheses
@@ -72,7 +255,7 @@ impl ExpressionParser {
         let mut op_pos = None;
         let mut op_char = '\0';
 
-        for (i, ch) in s.char_indices().rev() {
+        for (i, ch) in mask_char_literals(s).char_indices().rev() {
             match ch {
                 ')' => depth += 1,
                 '(' => depth -= 1,
@@ -107,13 +290,13 @@ impl ExpressionParser {
         let mut op_pos = None;
         let mut op_char = '\0';
 
-        for (i, ch) in s.char_indices().rev() {
+        for (i, ch) in mask_char_literals(s).char_indices().rev() {
             match ch {
                 ')' => depth += 1,
                 '(' => depth -= 1,
                 '*' | '/' if depth == 0 => {
                     // Check if * is current address (at start or after operator)
-                    if ch == '*' && (i == 0 || matches!(s.chars().nth(i.saturating_sub(1)), Some('+' | '-' | '*' | '/' | '(' | ','))) {
+                    if ch == '*' && !Self::ends_with_operand(&s[..i]) {
                         continue; // This is current address, not multiply
                     }
                     op_pos = Some(i);
@@ -142,6 +325,15 @@ impl ExpressionParser {
     fn parse_primary(s: &str) -> Result<Expr, String> {
         let s = s.trim();
 
+        // bank(label)
+        if s.len() > 6
+            && s[..5].eq_ignore_ascii_case("bank(")
+            && let Some(label) = s[5..].strip_suffix(')')
+            && Self::is_valid_label(label.trim())
+        {
+            return Ok(Expr::Bank(label.trim().to_string()));
+        }
+
         // Parenthesized expression
         if s.starts_with('(') && s.ends_with(')') {
             return Self::parse(&s[1..s.len() - 1]);
@@ -152,6 +344,10 @@ impl ExpressionParser {
             return Ok(Expr::CurrentAddress);
         }
 
+        if let Some(c) = char_literal(s) {
+            return Ok(c);
+        }
+
         // Number
         if let Ok(num) = NumberParser::parse(s) {
             return Ok(Expr::Number(num));
@@ -165,8 +361,14 @@ impl ExpressionParser {
         Err(format!("Invalid expression: {}", s))
     }
 
-    /// Check if a string is a valid label name
-    fn is_valid_label(s: &str) -> bool {
+    /// Check if a string is a valid label name: an identifier, or
+    /// identifiers joined by `::` for scoped names (`scope::name`)
+    pub fn is_valid_label(s: &str) -> bool {
+        s.split("::").all(Self::is_identifier)
+    }
+
+    /// Check if a string is a plain identifier
+    pub fn is_identifier(s: &str) -> bool {
         if s.is_empty() {
             return false;
         }
@@ -202,9 +404,66 @@ mod tests {
         assert_eq!(ExpressionParser::parse("my_label").unwrap(), Expr::Label("my_label".to_string()));
     }
 
+    #[test]
+    fn test_bank_function() {
+        assert_eq!(ExpressionParser::parse("bank(far)").unwrap(), Expr::Bank("far".to_string()));
+        assert_eq!(
+            ExpressionParser::parse("#BANK(s::f) + 1").unwrap(),
+            Expr::Immediate(Box::new(Expr::Add(Box::new(Expr::Bank("s::f".to_string())), Box::new(Expr::Number(1)))))
+        );
+        assert!(ExpressionParser::parse("bank($1234)").is_err());
+    }
+
+    #[test]
+    fn test_comparisons_and_logic() {
+        let lbl = |s: &str| Box::new(Expr::Label(s.to_string()));
+        let num = |n| Box::new(Expr::Number(n));
+        assert_eq!(ExpressionParser::parse("PAL == 1").unwrap(), Expr::Eq(lbl("PAL"), num(1)));
+        assert_eq!(ExpressionParser::parse("i < 256").unwrap(), Expr::Lt(lbl("i"), num(256)));
+        assert_eq!(ExpressionParser::parse("i <= <$1234").unwrap(), Expr::Le(lbl("i"), Box::new(Expr::LowByte(num(0x1234)))));
+        assert_eq!(
+            ExpressionParser::parse("!DEBUG || X != 2 && Y").unwrap(),
+            Expr::Or(
+                Box::new(Expr::Not(lbl("DEBUG"))),
+                Box::new(Expr::And(Box::new(Expr::Ne(lbl("X"), num(2))), lbl("Y")))
+            )
+        );
+        assert_eq!(ExpressionParser::parse("* > $0800").unwrap(), Expr::Gt(Box::new(Expr::CurrentAddress), num(0x800)));
+        // Prefix operators are unaffected
+        assert_eq!(ExpressionParser::parse(">$1234").unwrap(), Expr::HighByte(num(0x1234)));
+    }
+
+    #[test]
+    fn test_char_literals() {
+        let chr = |c| Box::new(Expr::Char(c));
+        assert_eq!(ExpressionParser::parse("'A'").unwrap(), Expr::Char('A'));
+        assert_eq!(ExpressionParser::parse("'A' + 1").unwrap(), Expr::Add(chr('A'), Box::new(Expr::Number(1))));
+        // Operator characters inside a literal are not operators
+        assert_eq!(ExpressionParser::parse("1 + '+'").unwrap(), Expr::Add(Box::new(Expr::Number(1)), chr('+')));
+        assert_eq!(ExpressionParser::parse("'<' < '*'").unwrap(), Expr::Lt(chr('<'), chr('*')));
+        assert_eq!(ExpressionParser::parse("'£'").unwrap(), Expr::Char('£'));
+        assert!(ExpressionParser::parse("'AB'").is_err());
+        assert_eq!(ExpressionParser::parse(r"'\''").unwrap(), Expr::Char('\''));
+        assert_eq!(ExpressionParser::parse(r"'\x7F' - '\\'").unwrap(), Expr::Sub(Box::new(Expr::Number(0x7F)), chr('\\')));
+    }
+
+    #[test]
+    fn test_scoped_label() {
+        assert_eq!(ExpressionParser::parse("copy__0::loop").unwrap(), Expr::Label("copy__0::loop".to_string()));
+        assert!(ExpressionParser::parse("a::").is_err());
+    }
+
     #[test]
     fn test_current_address() {
         assert_eq!(ExpressionParser::parse("*").unwrap(), Expr::CurrentAddress);
+        assert_eq!(
+            ExpressionParser::parse("$0900 - *").unwrap(),
+            Expr::Sub(Box::new(Expr::Number(0x900)), Box::new(Expr::CurrentAddress))
+        );
+        assert_eq!(
+            ExpressionParser::parse("* * 2").unwrap(),
+            Expr::Mul(Box::new(Expr::CurrentAddress), Box::new(Expr::Number(2)))
+        );
     }
 
     #[test]
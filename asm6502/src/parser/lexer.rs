@@ -1,6 +1,21 @@
 //! Lexer and parser for assembly source lines
 
 use super::expression::{Expr, ExpressionParser};
+use super::text::{
+    parse_char_literal, parse_message, parse_string_literal, split_operands, split_statements, strip_comments,
+    TextChar,
+};
+use crate::addressing::{is_accumulator, is_mnemonic};
+use crate::dialect::Dialect;
+use crate::source::SourceLoc;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// A macro parameter name and its optional default value.
+pub type MacroParam = (String, Option<String>);
+
+/// The verbatim lines of a `.macro`, `.rept` or `.for` block.
+pub type BlockBody = Vec<(SourceLoc, String)>;
 
 #[derive(Clone, Debug)]
 pub enum Item {
@@ -12,10 +27,189 @@ pub enum Item {
     Constant(String, Expr),
     Data(Vec<Expr>),           // DCB and .byte
     Words(Vec<Expr>),          // .word (16-bit little-endian)
-    String(String),            // .string "text"
-    IncBin(String),            // .incbin "filename"
+    Values {                   // .dword, .dbyte, .rta, .lobytes, .hibytes
+        layout: ValueLayout,
+        exprs: Vec<Expr>,
+    },
+    String {                   // .string/.text/.asciiz/.pstring/.shift (encoded into Text by expansion)
+        directive: &'static str,
+        layout: TextLayout,
+        parts: Vec<TextPart>,
+        text: String,          // the operands as written
+    },
+    Text {                     // a text directive after encoding, one expression per byte
+        directive: &'static str,
+        text: String,
+        bytes: Vec<Expr>,
+    },
+    Encoding(String),          // .encoding "name" (removed by expansion)
+    CharMap(char, Expr),       // .charmap 'c', value (removed by expansion)
+    IncBin {                   // .incbin "filename"[, offset[, length]]
+        path: String,
+        offset: Option<Expr>,
+        length: Option<Expr>,
+    },
+    Align {                    // .align boundary[, fill]
+        boundary: Expr,
+        fill: Option<Expr>,
+    },
+    Fill {                     // .fill / .res / .ds count[, value]
+        count: Expr,
+        value: Option<Expr>,
+    },
+    Skip(Expr),                // .skip count: advance the PC without emitting bytes
+    Assert {                   // .assert cond[, "message"], checked after final layout
+        cond: Expr,
+        text: String,          // the condition as written
+        message: Option<String>,
+    },
+    Error(String),             // .error "message"
+    Warning(String),           // .warning "message"
     Org(Expr),
+    Segment(String),           // .segment "NAME"
+    Bank {                     // .bank n[, size, cpu_base]
+        number: Expr,
+        /// Size and CPU address of the bank window; given once per bank.
+        window: Option<(Expr, Expr)>,
+    },
+    Logical(Expr),             // .pseudopc / .logical / .relocate addr: assemble for addr
+    RealPc,                    // .realpc / .here: back to the physical address
     Pad(usize),                // emit N zero bytes (internal: reserved-range filler)
+    Line(SourceLoc),           // marks the source line of the items that follow (emits nothing)
+    Macro {                    // .macro name params ... .endmacro (removed by expansion)
+        name: String,
+        params: Vec<MacroParam>,
+        body: BlockBody,
+    },
+    Rept {                     // .rept count ... .endrept (removed by expansion)
+        count: Expr,
+        body: BlockBody,
+    },
+    For {                      // .for var = start, cond, next ... .endfor (removed by expansion)
+        var: String,
+        start: Expr,
+        cond: Expr,
+        step: Expr,
+        body: BlockBody,
+    },
+    Include {                  // .include / .include_once "file" (removed by expansion)
+        path: String,
+        once: bool,
+    },
+    Struct {                   // .struct Name ... .endstruct (removed by expansion)
+        name: String,
+        body: BlockBody,
+    },
+    Enum {                     // .enum [Name] ... .endenum (removed by expansion)
+        name: Option<String>,
+        body: BlockBody,
+    },
+    StructData {               // .dstruct Name[, field = value ...] (removed by expansion)
+        name: String,
+        inits: Vec<(String, Expr)>,
+    },
+    If(Condition),             // .if / .ifdef / .ifndef (removed by expansion)
+    ElseIf(Condition),         // .elseif
+    Else,                      // .else
+    EndIf,                     // .endif
+}
+
+/// How the data directives beyond `.byte` and `.word` store each value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueLayout {
+    /// `.dword`, `.long`: 32 bits, little-endian.
+    Dword,
+    /// `.dbyte`, `.bigword`: 16 bits, big-endian.
+    BigWord,
+    /// `.rta`: the address minus one, little-endian, for RTS dispatch.
+    Rta,
+    /// `.lobytes`: the low byte of each value.
+    LoBytes,
+    /// `.hibytes`: the high byte of each value.
+    HiBytes,
+}
+
+impl ValueLayout {
+    /// Bytes per value.
+    pub fn size(self) -> usize {
+        match self {
+            ValueLayout::Dword => 4,
+            ValueLayout::BigWord | ValueLayout::Rta => 2,
+            ValueLayout::LoBytes | ValueLayout::HiBytes => 1,
+        }
+    }
+
+    /// The name the listing shows.
+    pub fn directive(self) -> &'static str {
+        match self {
+            ValueLayout::Dword => ".dword",
+            ValueLayout::BigWord => ".dbyte",
+            ValueLayout::Rta => ".rta",
+            ValueLayout::LoBytes => ".lobytes",
+            ValueLayout::HiBytes => ".hibytes",
+        }
+    }
+
+    /// The bytes stored for `value`.
+    pub fn bytes(self, value: u32) -> Vec<u8> {
+        match self {
+            ValueLayout::Dword => value.to_le_bytes().to_vec(),
+            ValueLayout::BigWord => (value as u16).to_be_bytes().to_vec(),
+            ValueLayout::Rta => (value as u16).wrapping_sub(1).to_le_bytes().to_vec(),
+            ValueLayout::LoBytes => vec![value as u8],
+            ValueLayout::HiBytes => vec![(value >> 8) as u8],
+        }
+    }
+}
+
+const VALUE_DIRECTIVES: [(&str, ValueLayout); 7] = [
+    (".dword", ValueLayout::Dword),
+    (".long", ValueLayout::Dword),
+    (".dbyte", ValueLayout::BigWord),
+    (".bigword", ValueLayout::BigWord),
+    (".rta", ValueLayout::Rta),
+    (".lobytes", ValueLayout::LoBytes),
+    (".hibytes", ValueLayout::HiBytes),
+];
+
+/// What a text directive adds around its operands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextLayout {
+    /// `.string`, `.text`: just the operands.
+    Plain,
+    /// `.asciiz`, `.cstring`: followed by a `$00` byte.
+    NulTerminated,
+    /// `.pstring`: preceded by a byte holding the length.
+    LengthPrefixed,
+    /// `.shift`: bit 7 set on the last byte.
+    LastBitSet,
+}
+
+/// One operand of a text directive.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TextPart {
+    Str(Vec<TextChar>),
+    Expr(Expr),
+}
+
+const TEXT_DIRECTIVES: [(&str, TextLayout); 6] = [
+    (".string", TextLayout::Plain),
+    (".text", TextLayout::Plain),
+    (".asciiz", TextLayout::NulTerminated),
+    (".cstring", TextLayout::NulTerminated),
+    (".pstring", TextLayout::LengthPrefixed),
+    (".shift", TextLayout::LastBitSet),
+];
+
+/// The test of a conditional-assembly directive.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Condition {
+    /// `.if expr` / `.elseif expr`: true when the value is non-zero.
+    Expr(Expr),
+    /// `.ifdef name`
+    Defined(String),
+    /// `.ifndef name`
+    NotDefined(String),
 }
 
 #[derive(Clone, Debug)]
@@ -24,61 +218,402 @@ pub enum Either<T> {
     Many(Vec<T>),
 }
 
-/// Parse entire source into a list of Items
-pub fn parse_source(source: &str) -> Result<Vec<Item>, String> {
-    let mut instructions = Vec::new();
-    for (line_num, raw) in source.lines().enumerate() {
-        let line = raw.split(';').next().unwrap_or("").trim().to_string();
-        if line.is_empty() {
+/// Parse the contents of `file` (`None` for a source string handed to the
+/// assembler directly); every item's location names the file. `is_macro`
+/// tells which (uppercase) names are macros defined before it.
+pub fn parse_file(
+    source: &str,
+    file: Option<Arc<str>>,
+    dialect: &Dialect,
+    is_macro: &dyn Fn(&str) -> bool,
+) -> Result<Vec<Item>, String> {
+    parse_lines(
+        source
+            .lines()
+            .enumerate()
+            .map(|(line_num, raw)| (SourceLoc::new(file.clone(), line_num + 1), raw)),
+        dialect,
+        is_macro,
+    )
+}
+
+/// Directives that open a verbatim block, and the names that close it.
+pub const BLOCKS: [(&str, &[&str]); 5] = [
+    (".macro", &[".endmacro", ".endm"]),
+    (".rept", &[".endrept", ".endr"]),
+    (".for", &[".endfor"]),
+    (".struct", &[".endstruct", ".ends"]),
+    (".enum", &[".endenum", ".ende"]),
+];
+
+/// The block directive `line` opens (`.macro`, `.rept`, `.for`,
+/// `.struct`, `.enum`) with the rest of the line.
+pub fn block_open(line: &str) -> Option<(&'static str, &str)> {
+    BLOCKS
+        .iter()
+        .find_map(|(open, _)| strip_directive(line, open).map(|rest| (*open, rest)))
+}
+
+/// The block directive `line` closes, if it is an end directive.
+pub fn block_close(line: &str) -> Option<&'static str> {
+    BLOCKS.iter().find_map(|(open, ends)| {
+        ends.iter()
+            .any(|end| strip_directive(line, end).is_some_and(|rest| rest.trim().is_empty()))
+            .then_some(*open)
+    })
+}
+
+/// A block being collected by `parse_lines`: its opening directive, the
+/// Item it will become (with an empty body) and the lines so far.
+struct BlockCapture {
+    loc: SourceLoc,
+    open: &'static str,
+    item: Item,
+    body: BlockBody,
+    nesting: usize,
+}
+
+/// Parse located lines into Items. `.macro`, `.rept` and `.for` bodies are
+/// not parsed here: they are kept in the block's Item, one statement per
+/// line, and parsed per invocation or iteration once names have been
+/// substituted. A label may start any line, including a block's opening or
+/// closing line, and `:` separates statements on a line. `is_macro` tells
+/// which (uppercase) names are macros defined before these lines.
+pub fn parse_lines<S: AsRef<str>>(
+    lines: impl IntoIterator<Item = (SourceLoc, S)>,
+    dialect: &Dialect,
+    is_macro: &dyn Fn(&str) -> bool,
+) -> Result<Vec<Item>, String> {
+    let mut parser = LineParser { dialect, items: Vec::new(), capture: None, scope: None, macros: HashSet::new() };
+    // Where an unclosed `/*` or `.comment` started
+    let mut block_comment: Option<SourceLoc> = None;
+    let mut comment_block: Option<SourceLoc> = None;
+    for (loc, raw) in lines {
+        let mut in_comment = block_comment.is_some();
+        let text = strip_comments(raw.as_ref(), dialect.line_comment, &mut in_comment);
+        if !in_comment {
+            block_comment = None;
+        } else if block_comment.is_none() {
+            block_comment = Some(loc.clone());
+        }
+        let text = text.trim_end();
+
+        if comment_block.is_some() {
+            if strip_directive(text.trim(), ".endcomment").is_some_and(|rest| rest.trim().is_empty()) {
+                comment_block = None;
+            }
             continue;
         }
-        match parse_line(&line) {
+        let (mut label, rest) = split_line_label(text, dialect, &|name| is_macro(name) || parser.macros.contains(name));
+        let rest = dialect.address_sizes(rest);
+        let text = text.trim();
+        let mut statements: Vec<&str> = split_statements(&rest).into_iter().map(str::trim).filter(|s| !s.is_empty()).collect();
+        if statements.is_empty() && label.is_some() {
+            statements.push("");
+        }
+        for statement in statements {
+            if strip_directive(statement, ".comment").is_some_and(|rest| rest.trim().is_empty()) {
+                // The rest of the line is part of the comment too
+                comment_block = Some(loc.clone());
+                break;
+            }
+            if strip_directive(statement, ".endcomment").is_some() {
+                return Err(format!("{}: {} - .endcomment without .comment", loc, text));
+            }
+            parser.statement(&loc, label.take(), statement, text)?;
+        }
+    }
+    if let Some(loc) = comment_block {
+        return Err(format!("{}: .comment has no matching .endcomment", loc));
+    }
+    if let Some(loc) = block_comment {
+        return Err(format!("{}: /* has no matching */", loc));
+    }
+    if let Some(cap) = parser.capture {
+        let end = BLOCKS.iter().find(|(open, _)| *open == cap.open).map_or("", |(_, ends)| ends[0]);
+        return Err(format!("{}: {} has no matching {}", cap.loc, cap.open, end));
+    }
+    Ok(parser.items)
+}
+
+/// The Items of `parse_lines` so far, and the block being collected.
+struct LineParser<'d> {
+    dialect: &'d Dialect,
+    items: Vec<Item>,
+    capture: Option<BlockCapture>,
+    /// The last label that isn't local: the scope of the local labels
+    /// after it.
+    scope: Option<String>,
+    /// Macros defined so far, by uppercase name.
+    macros: HashSet<String>,
+}
+
+impl LineParser<'_> {
+    /// One statement and the label before it, if it is the first on its
+    /// line; `text` is the whole line, for error messages.
+    fn statement(&mut self, loc: &SourceLoc, label: Option<&str>, statement: &str, text: &str) -> Result<(), String> {
+        let context = |e: String| format!("{}: {} - {}", loc, text, e);
+        if let Some(label) = label {
+            check_symbol_name(label).map_err(context)?;
+        }
+        let statement = self.dialect.translate(statement).map_err(context)?;
+        let statement = statement.as_ref();
+        if statement.is_empty() && label.is_none() {
+            return Ok(());
+        }
+        if let Some(cap) = self.capture.as_mut() {
+            if block_open(statement).is_some() {
+                cap.nesting += 1;
+            } else if let Some(open) = block_close(statement) {
+                if cap.nesting == 0 {
+                    if open != cap.open {
+                        return Err(format!("{}: {} - does not close {} at {}", loc, text, cap.open, cap.loc));
+                    }
+                    let cap = self.capture.take().unwrap();
+                    if let Item::Macro { name, .. } = &cap.item {
+                        self.macros.insert(name.to_ascii_uppercase());
+                    }
+                    self.items.push(Item::Line(cap.loc));
+                    self.items.push(cap.item.with_body(cap.body));
+                    // A label on the closing line comes after the whole block
+                    if let Some(label) = label {
+                        self.label(loc, label).map_err(context)?;
+                    }
+                    return Ok(());
+                }
+                cap.nesting -= 1;
+            }
+            // Indented, so a colon-less label dialect doesn't read it as a label
+            let line = match label {
+                Some(label) => format!("{}: {}", label, statement),
+                None => format!("    {}", statement),
+            };
+            cap.body.push((loc.clone(), line));
+            return Ok(());
+        }
+
+        if let Some(label) = label {
+            self.label(loc, label).map_err(context)?;
+        }
+        if statement.is_empty() {
+            return Ok(());
+        }
+
+        if let Some((open, rest)) = block_open(statement) {
+            let item = parse_block_header(open, rest)
+                .map_err(|e| format!("{}: {} - {}", loc, text, e))?;
+            self.capture = Some(BlockCapture {
+                loc: loc.clone(),
+                open,
+                item,
+                body: Vec::new(),
+                nesting: 0,
+            });
+            return Ok(());
+        }
+        if let Some(open) = block_close(statement) {
+            return Err(format!("{}: {} without {}", loc, text, open));
+        }
+        // Local labels in block bodies and headers are left for the body's own parse
+        match parse_statement(&self.dialect.localize(statement, self.scope.as_deref())) {
             Ok(Some(parsed)) => {
+                self.items.push(Item::Line(loc.clone()));
                 match parsed {
-                    Either::Many(list) => instructions.extend(list),
-                    Either::One(item) => instructions.push(item),
+                    Either::Many(list) => self.items.extend(list),
+                    Either::One(item) => self.items.push(item),
                 }
+                Ok(())
             }
-            Ok(None) => {
-                // Empty line or comment only - skip
+            Ok(None) => Ok(()),
+            Err(e) => Err(format!("{}: {} - {}", loc, text, e)),
+        }
+    }
+
+    /// Define a label outside any block. A local label is qualified by
+    /// the last label that isn't, which becomes the scope for those after.
+    fn label(&mut self, loc: &SourceLoc, label: &str) -> Result<(), String> {
+        let name = if self.dialect.local_name(label).is_some() {
+            self.dialect.localize(label, self.scope.as_deref()).into_owned()
+        } else {
+            if !label.contains("::") {
+                self.scope = Some(label.to_string());
             }
-            Err(e) => {
-                return Err(format!("Line {}: {} - {}", line_num + 1, line, e));
+            label.to_string()
+        };
+        check_symbol_name(&name)?;
+        self.items.push(Item::Line(loc.clone()));
+        self.items.push(Item::Label(name));
+        Ok(())
+    }
+}
+
+impl Item {
+    /// A captured block item with its body filled in.
+    fn with_body(mut self, lines: BlockBody) -> Item {
+        if let Item::Macro { body, .. } | Item::Rept { body, .. } | Item::For { body, .. }
+        | Item::Struct { body, .. } | Item::Enum { body, .. } = &mut self
+        {
+            *body = lines;
+        }
+        self
+    }
+}
+
+/// The Item for a block's opening line, with an empty body.
+fn parse_block_header(open: &str, rest: &str) -> Result<Item, String> {
+    match open {
+        ".macro" => {
+            let (name, params) = parse_macro_header(rest)?;
+            Ok(Item::Macro { name, params, body: Vec::new() })
+        }
+        ".rept" => {
+            if rest.trim().is_empty() {
+                return Err(".rept expects a count".to_string());
+            }
+            Ok(Item::Rept { count: ExpressionParser::parse(rest)?, body: Vec::new() })
+        }
+        ".struct" => {
+            let name = rest.trim();
+            if !ExpressionParser::is_identifier(name) {
+                return Err(format!("Invalid struct name: '{}'", name));
             }
+            Ok(Item::Struct { name: name.to_string(), body: Vec::new() })
         }
+        ".enum" => {
+            let name = rest.trim();
+            if !name.is_empty() && !ExpressionParser::is_identifier(name) {
+                return Err(format!("Invalid enum name: '{}'", name));
+            }
+            Ok(Item::Enum { name: (!name.is_empty()).then(|| name.to_string()), body: Vec::new() })
+        }
+        _ => parse_for_header(rest),
     }
-    Ok(instructions)
 }
 
-/// Parse a single line into an Item
-pub fn parse_line(line: &str) -> Result<Option<Either<Item>>, String> {
-    let l = line.split(';').next().unwrap_or("").trim();
-    if l.is_empty() {
-        return Ok(None);
+/// `var = start, condition, next` after `.for`. The last part is the next
+/// value of the variable, written either `i+1` or `i = i+1`.
+fn parse_for_header(rest: &str) -> Result<Item, String> {
+    let parts: Vec<&str> = rest.split(',').map(str::trim).collect();
+    let [init, cond, step] = parts[..] else {
+        return Err(".for expects 'var = start, condition, next'".to_string());
+    };
+    let Some((var, start)) = init.split_once('=') else {
+        return Err(".for expects 'var = start' before the first comma".to_string());
+    };
+    let var = var.trim();
+    if !ExpressionParser::is_identifier(var) {
+        return Err(format!("Invalid .for variable: '{}'", var));
     }
+    check_substituted_name(var, ".for variable")?;
+    let step = match step.split_once('=') {
+        Some((lhs, next)) if lhs.trim() == var && !next.starts_with('=') => next,
+        _ => step,
+    };
+    Ok(Item::For {
+        var: var.to_string(),
+        start: ExpressionParser::parse(start)?,
+        cond: ExpressionParser::parse(cond)?,
+        step: ExpressionParser::parse(step)?,
+        body: Vec::new(),
+    })
+}
 
-    // Label with DCB on same line: "label: DCB $01 $02"
-    if l.contains(':') && l.contains("DCB") {
-        let mut parts = l.split(':');
-        let label = parts.next().unwrap().trim().to_string();
-        let rest = parts.next().unwrap_or("").trim();
-        if rest.starts_with("DCB") {
-            let data_exprs: Vec<Expr> = rest[3..]
-                .split_whitespace()
-                .map(|s| ExpressionParser::parse(s))
-                .collect::<Result<_, _>>()?;
-            return Ok(Some(Either::Many(vec![
-                Item::Label(label),
-                Item::Data(data_exprs),
-            ])));
+/// `name p1, p2=default` after `.macro`.
+fn parse_macro_header(rest: &str) -> Result<(String, Vec<MacroParam>), String> {
+    let rest = rest.trim();
+    let (name, params) = rest
+        .split_once(char::is_whitespace)
+        .unwrap_or((rest, ""));
+    if !ExpressionParser::is_identifier(name) {
+        return Err(format!("Invalid macro name: '{}'", name));
+    }
+    let mut list = Vec::new();
+    for param in params.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (pname, default) = match param.split_once('=') {
+            Some((n, d)) => (n.trim(), Some(d.trim().to_string())),
+            None => (param, None),
+        };
+        if !ExpressionParser::is_identifier(pname) {
+            return Err(format!("Invalid macro parameter: '{}'", pname));
+        }
+        check_substituted_name(pname, "macro parameter")?;
+        if list.iter().any(|(p, _): &MacroParam| p == pname) {
+            return Err(format!("Duplicate macro parameter: '{}'", pname));
         }
+        list.push((pname.to_string(), default));
     }
+    Ok((name.to_string(), list))
+}
+
+/// Parse a single line into Items: its label, if it has one, and its
+/// statements. Blocks and comments spanning lines need `parse_lines`.
+pub fn parse_line(line: &str, dialect: &Dialect) -> Result<Option<Either<Item>>, String> {
+    let text = strip_comments(line, dialect.line_comment, &mut false);
+    let (label, rest) = split_line_label(text.trim_end(), dialect, &|_| false);
+    let mut items = Vec::new();
+    if let Some(label) = label {
+        let label = dialect.localize(label, None);
+        check_symbol_name(&label)?;
+        items.push(Item::Label(label.into_owned()));
+    }
+    for statement in split_statements(&dialect.address_sizes(rest)) {
+        let statement = dialect.translate(statement.trim())?;
+        match parse_statement(&dialect.localize(&statement, None))? {
+            Some(Either::One(item)) => items.push(item),
+            Some(Either::Many(list)) => items.extend(list),
+            None => {}
+        }
+    }
+    Ok(match items.len() {
+        0 => None,
+        1 => items.pop().map(Either::One),
+        _ => Some(Either::Many(items)),
+    })
+}
+
+/// Split the label off the start of a line (`loop: LDA #1`, or with
+/// colon-less labels `loop LDA #1` in column 0) from the statement after
+/// it. `line` has no comment but keeps its indentation. A column-0 word
+/// that is an instruction, a directive or a macro (`is_macro`, given the
+/// uppercase name) starts a statement instead.
+pub fn split_line_label<'a>(
+    line: &'a str,
+    dialect: &Dialect,
+    is_macro: &dyn Fn(&str) -> bool,
+) -> (Option<&'a str>, &'a str) {
+    let trimmed = line.trim();
+    let is_label = |name: &str| ExpressionParser::is_valid_label(name) || dialect.local_name(name).is_some();
+    // "NOP : INX" is two statements and "X:=1" a constant, not labels
+    if let Some((label, rest)) = split_label(trimmed)
+        && is_label(label)
+        && !rest.starts_with('=')
+    {
+        return (Some(label), rest.trim());
+    }
+    if dialect.colonless_labels
+        && line.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || Some(c) == dialect.local_label_prefix)
+    {
+        let (word, rest) = trimmed.split_once(char::is_whitespace).unwrap_or((trimmed, ""));
+        let rest = rest.trim();
+        let upper = word.to_ascii_uppercase();
+        let statement = is_mnemonic(&upper) || upper == "DCB" || is_macro(&upper) || dialect.is_directive(trimmed);
+        // "NAME = value" and "NAME EQU value" are constants, not labels
+        if is_label(word) && !statement && !rest.starts_with('=') && !dialect.is_equate(rest) {
+            return (Some(word), rest);
+        }
+    }
+    (None, trimmed)
+}
 
-    // Simple label: "label:"
-    if l.ends_with(':') {
-        return Ok(Some(Either::One(Item::Label(
-            l[..l.len() - 1].to_string(),
-        ))));
+/// Parse a statement, the part of a line after its label.
+fn parse_statement(l: &str) -> Result<Option<Either<Item>>, String> {
+    if l.is_empty() {
+        return Ok(None);
+    }
+
+    // Conditional assembly: ".if expr", ".ifdef name", ".else", ...
+    if let Some(item) = parse_conditional(l)? {
+        return Ok(Some(Either::One(item)));
     }
 
     // Constant assignment: "LABEL = value" or "LABEL = *+1"
@@ -88,63 +623,235 @@ pub fn parse_line(line: &str) -> Result<Option<Either<Item>>, String> {
             let name = parts[0].trim();
             let value_str = parts[1].trim();
 
-            // Validate label name
-            if !name.is_empty() && name.chars().next().unwrap().is_ascii_alphabetic() {
+            // Validate label name; anything else (e.g. a macro call with
+            // named arguments) falls through to the instruction path
+            if name.starts_with(|c: char| c.is_ascii_alphabetic()) && ExpressionParser::is_valid_label(name) {
+                check_symbol_name(name)?;
                 let expr = ExpressionParser::parse(value_str)?;
                 return Ok(Some(Either::One(Item::Constant(name.to_string(), expr))));
             }
         }
     }
 
-    // Origin directive: "*=$0800"
-    if let Some(rest) = l.strip_prefix("*=") {
+    // Origin directive: "*=$0800" or "* = $0800"
+    if let Some(rest) = l.strip_prefix('*').and_then(|rest| rest.trim_start().strip_prefix('=')) {
         let expr = ExpressionParser::parse(rest.trim())?;
         return Ok(Some(Either::One(Item::Org(expr))));
     }
 
     // .byte directive: ".byte $01,$02,$03"
-    if let Some(rest) = l.strip_prefix(".byte") {
-        let data: Vec<Expr> = rest
-            .split(',')
+    if let Some(rest) = strip_directive(l, ".byte") {
+        let data: Vec<Expr> = split_operands(rest)
+            .into_iter()
             .map(|s| ExpressionParser::parse(s.trim()))
             .collect::<Result<_, _>>()?;
         return Ok(Some(Either::One(Item::Data(data))));
     }
 
     // .word directive: ".word $1000,$2000"
-    if let Some(rest) = l.strip_prefix(".word") {
-        let words: Vec<Expr> = rest
-            .split(',')
+    if let Some(rest) = strip_directive(l, ".word") {
+        let words: Vec<Expr> = split_operands(rest)
+            .into_iter()
             .map(|s| ExpressionParser::parse(s.trim()))
             .collect::<Result<_, _>>()?;
         return Ok(Some(Either::One(Item::Words(words))));
     }
 
-    // .string directive: ".string "hello""
-    if let Some(rest) = l.strip_prefix(".string") {
-        let rest = rest.trim();
-        if rest.starts_with('"') && rest.ends_with('"') {
-            let string_content = &rest[1..rest.len() - 1];
-            return Ok(Some(Either::One(Item::String(string_content.to_string()))));
+    // Wider and split data: ".dword $12345678", ".rta handler", ".lobytes a, b"
+    for (directive, layout) in VALUE_DIRECTIVES {
+        if let Some(rest) = strip_directive(l, directive) {
+            if rest.trim().is_empty() {
+                return Err(format!("{} expects at least one value", directive));
+            }
+            let exprs: Vec<Expr> = split_operands(rest)
+                .into_iter()
+                .map(|s| ExpressionParser::parse(s.trim()))
+                .collect::<Result<_, _>>()?;
+            return Ok(Some(Either::One(Item::Values { layout, exprs })));
         }
-        return Err("Invalid .string format, expected quotes".to_string());
     }
 
-    // .incbin directive: ".incbin "filename.bin""
-    if let Some(rest) = l.strip_prefix(".incbin") {
+    // Text: ".string "hello"", ".text "HI", 13, 0", ".asciiz "path\n""
+    for (directive, layout) in TEXT_DIRECTIVES {
+        if let Some(rest) = strip_directive(l, directive) {
+            let text = rest.trim();
+            if text.is_empty() {
+                return Err(format!("{} expects a string", directive));
+            }
+            let parts = split_operands(text)
+                .into_iter()
+                .map(|op| {
+                    let op = op.trim();
+                    if op.starts_with('"') {
+                        parse_string_literal(op).map(TextPart::Str)
+                    } else {
+                        ExpressionParser::parse(op).map(TextPart::Expr)
+                    }
+                })
+                .collect::<Result<_, _>>()?;
+            return Ok(Some(Either::One(Item::String { directive, layout, parts, text: text.to_string() })));
+        }
+    }
+
+    // Diagnostics: ".assert * <= $9FFF, "too big"", ".error "msg"", ".warning "msg"
+    if let Some(rest) = strip_directive(l, ".assert") {
+        let operands = split_operands(rest);
+        let (cond, message) = match operands[..] {
+            [cond] => (cond.trim(), None),
+            [cond, message] => (cond.trim(), Some(parse_message(message.trim())?)),
+            _ => return Err(".assert takes a condition and an optional message".to_string()),
+        };
+        if cond.is_empty() {
+            return Err(".assert expects a condition".to_string());
+        }
+        let expr = ExpressionParser::parse(cond)?;
+        return Ok(Some(Either::One(Item::Assert { cond: expr, text: cond.to_string(), message })));
+    }
+    if let Some(rest) = strip_directive(l, ".error") {
+        return Ok(Some(Either::One(Item::Error(parse_message(rest.trim())?))));
+    }
+    if let Some(rest) = strip_directive(l, ".warning") {
+        return Ok(Some(Either::One(Item::Warning(parse_message(rest.trim())?))));
+    }
+
+    // Text encoding: ".encoding "petscii"", ".charmap 'A', $01"
+    if let Some(rest) = strip_directive(l, ".encoding") {
         let rest = rest.trim();
-        if rest.starts_with('"') && rest.ends_with('"') {
-            let filename = &rest[1..rest.len() - 1];
-            return Ok(Some(Either::One(Item::IncBin(filename.to_string()))));
+        if rest.len() >= 2 && rest.starts_with('"') && rest.ends_with('"') {
+            return Ok(Some(Either::One(Item::Encoding(rest[1..rest.len() - 1].to_string()))));
+        }
+        return Err("Invalid .encoding format, expected a quoted encoding name".to_string());
+    }
+    if let Some(rest) = strip_directive(l, ".charmap") {
+        if let [literal, value] = split_operands(rest)[..]
+            && let Some(TextChar::Char(c)) = parse_char_literal(literal.trim())
+        {
+            return Ok(Some(Either::One(Item::CharMap(c, ExpressionParser::parse(value)?))));
+        }
+        return Err("Invalid .charmap format, expected 'c', value".to_string());
+    }
+
+    // Struct instance: ".dstruct Actor, x = 10, pos = start"
+    if let Some(rest) = strip_directive(l, ".dstruct") {
+        let operands = split_operands(rest);
+        let name = operands[0].trim();
+        if !ExpressionParser::is_identifier(name) {
+            return Err(format!(".dstruct expects a struct name, got '{}'", name));
+        }
+        let inits = operands[1..]
+            .iter()
+            .map(|init| match init.split_once('=') {
+                Some((field, value)) if ExpressionParser::is_identifier(field.trim()) => {
+                    Ok((field.trim().to_string(), ExpressionParser::parse(value.trim())?))
+                }
+                _ => Err(format!(".dstruct initialiser '{}' should be field = value", init.trim())),
+            })
+            .collect::<Result<_, _>>()?;
+        return Ok(Some(Either::One(Item::StructData { name: name.to_string(), inits })));
+    }
+
+    // Logical blocks: ".pseudopc $1000" ... ".realpc"
+    for directive in [".pseudopc", ".logical", ".relocate"] {
+        if let Some(rest) = strip_directive(l, directive) {
+            if rest.trim().is_empty() {
+                return Err(format!("{} expects an address", directive));
+            }
+            return Ok(Some(Either::One(Item::Logical(ExpressionParser::parse(rest.trim())?))));
+        }
+    }
+    for directive in [".realpc", ".here"] {
+        if let Some(rest) = strip_directive(l, directive) {
+            if !rest.trim().is_empty() {
+                return Err(format!("{} takes no operand", directive));
+            }
+            return Ok(Some(Either::One(Item::RealPc)));
+        }
+    }
+
+    // Segment switch: ".segment "RODATA""
+    if let Some(rest) = strip_directive(l, ".segment") {
+        let name = parse_message(rest.trim()).map_err(|_| "Invalid .segment format, expected a quoted segment name".to_string())?;
+        if name.is_empty() {
+            return Err(".segment expects a segment name".to_string());
+        }
+        return Ok(Some(Either::One(Item::Segment(name))));
+    }
+
+    // Bank switch: ".bank 1, $4000, $8000" defines a bank, ".bank 1" resumes it
+    if let Some(rest) = strip_directive(l, ".bank") {
+        let operands = split_operands(rest);
+        let mut exprs = operands.iter().map(|op| ExpressionParser::parse(op.trim()));
+        return match (exprs.next(), exprs.next(), exprs.next(), exprs.next()) {
+            (Some(number), None, None, None) => Ok(Some(Either::One(Item::Bank { number: number?, window: None }))),
+            (Some(number), Some(size), Some(base), None) => {
+                Ok(Some(Either::One(Item::Bank { number: number?, window: Some((size?, base?)) })))
+            }
+            _ => Err(".bank expects a bank number, optionally followed by its size and CPU address".to_string()),
+        };
+    }
+
+    // .incbin directive: ".incbin "filename.bin"[, offset[, length]]"
+    if let Some(rest) = strip_directive(l, ".incbin") {
+        let Some((path, args)) = rest.trim().strip_prefix('"').and_then(|r| r.split_once('"')) else {
+            return Err("Invalid .incbin format, expected quotes".to_string());
+        };
+        let args = args.trim();
+        let mut exprs = Vec::new();
+        if !args.is_empty() {
+            let Some(args) = args.strip_prefix(',') else {
+                return Err("Invalid .incbin format, expected ', offset[, length]' after the file name".to_string());
+            };
+            for arg in args.split(',') {
+                exprs.push(ExpressionParser::parse(arg.trim())?);
+            }
+        }
+        if exprs.len() > 2 {
+            return Err(".incbin takes at most an offset and a length".to_string());
+        }
+        let mut exprs = exprs.into_iter();
+        return Ok(Some(Either::One(Item::IncBin {
+            path: path.to_string(),
+            offset: exprs.next(),
+            length: exprs.next(),
+        })));
+    }
+
+    // Storage: ".align 256[, $EA]", ".fill 16[, $FF]", ".res 4", ".skip 2"
+    if let Some(rest) = strip_directive(l, ".align") {
+        let (boundary, fill) = parse_amount(rest, ".align")?;
+        return Ok(Some(Either::One(Item::Align { boundary, fill })));
+    }
+    for directive in [".fill", ".res", ".ds"] {
+        if let Some(rest) = strip_directive(l, directive) {
+            let (count, value) = parse_amount(rest, directive)?;
+            return Ok(Some(Either::One(Item::Fill { count, value })));
+        }
+    }
+    if let Some(rest) = strip_directive(l, ".skip") {
+        let (count, fill) = parse_amount(rest, ".skip")?;
+        if fill.is_some() {
+            return Err(".skip emits no bytes, so it takes no fill value".to_string());
+        }
+        return Ok(Some(Either::One(Item::Skip(count))));
+    }
+
+    // Source inclusion: ".include "file.s"", ".include_once "file.s""
+    for (directive, once) in [(".include", false), (".include_once", true)] {
+        if let Some(rest) = strip_directive(l, directive) {
+            let rest = rest.trim();
+            if rest.len() >= 2 && rest.starts_with('"') && rest.ends_with('"') {
+                let path = rest[1..rest.len() - 1].to_string();
+                return Ok(Some(Either::One(Item::Include { path, once })));
+            }
+            return Err(format!("Invalid {} format, expected quotes", directive));
         }
-        return Err("Invalid .incbin format, expected quotes".to_string());
     }
 
     // Data directive: "DCB $01 $02 $03"
-    if l.starts_with("DCB") {
-        let data: Vec<Expr> = l[3..]
+    if let Some(rest) = strip_directive(l, "DCB") {
+        let data: Vec<Expr> = rest
             .split_whitespace()
-            .map(|s| ExpressionParser::parse(s))
+            .map(ExpressionParser::parse)
             .collect::<Result<_, _>>()?;
         return Ok(Some(Either::One(Item::Data(data))));
     }
@@ -154,16 +861,125 @@ pub fn parse_line(line: &str) -> Result<Option<Either<Item>>, String> {
     let parts: Vec<&str> = l.splitn(2, char::is_whitespace).collect();
     match parts.len() {
         1 => Ok(Some(Either::One(Item::Instruction {
-            mnemonic: parts[0].to_string(),
+            mnemonic: parts[0].to_ascii_uppercase(),
             operand: None,
         }))),
         2 => {
             // Keep operand as string with all spaces intact
             Ok(Some(Either::One(Item::Instruction {
-                mnemonic: parts[0].to_string(),
+                mnemonic: parts[0].to_ascii_uppercase(),
                 operand: Some(parts[1].trim().to_string()),
             })))
         }
         _ => Err(format!("Invalid line: {}", l)),
     }
 }
+
+/// The `amount[, fill]` operands of the storage directives.
+fn parse_amount(rest: &str, directive: &str) -> Result<(Expr, Option<Expr>), String> {
+    if rest.trim().is_empty() {
+        return Err(format!("{} expects a byte count", directive));
+    }
+    let mut args = rest.split(',');
+    let amount = ExpressionParser::parse(args.next().unwrap_or("").trim())?;
+    let fill = args.next().map(|arg| ExpressionParser::parse(arg.trim())).transpose()?;
+    if args.next().is_some() {
+        return Err(format!("{} takes a byte count and an optional fill value", directive));
+    }
+    Ok((amount, fill))
+}
+
+/// `.if`, `.ifdef`, `.ifndef`, `.elseif`, `.else` and `.endif`.
+fn parse_conditional(l: &str) -> Result<Option<Item>, String> {
+    let symbol = |rest: &str, directive: &str| {
+        let name = rest.trim();
+        if ExpressionParser::is_valid_label(name) {
+            Ok(name.to_string())
+        } else {
+            Err(format!("{} expects a symbol name, got '{}'", directive, name))
+        }
+    };
+    let expr = |rest: &str, directive: &str| {
+        if rest.trim().is_empty() {
+            return Err(format!("{} expects a condition", directive));
+        }
+        ExpressionParser::parse(rest)
+    };
+    let item = if let Some(rest) = strip_directive(l, ".if") {
+        Item::If(Condition::Expr(expr(rest, ".if")?))
+    } else if let Some(rest) = strip_directive(l, ".ifdef") {
+        Item::If(Condition::Defined(symbol(rest, ".ifdef")?))
+    } else if let Some(rest) = strip_directive(l, ".ifndef") {
+        Item::If(Condition::NotDefined(symbol(rest, ".ifndef")?))
+    } else if let Some(rest) = strip_directive(l, ".elseif") {
+        Item::ElseIf(Condition::Expr(expr(rest, ".elseif")?))
+    } else if strip_directive(l, ".else").is_some_and(|rest| rest.trim().is_empty()) {
+        Item::Else
+    } else if strip_directive(l, ".endif").is_some_and(|rest| rest.trim().is_empty()) {
+        Item::EndIf
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(item))
+}
+
+/// Split `label: rest` at the colon ending the label. The `::` of a
+/// scoped name (`scope::name`) is part of the label, not its end.
+pub fn split_label(l: &str) -> Option<(&str, &str)> {
+    let bytes = l.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b':' {
+            if bytes.get(i + 1) == Some(&b':') {
+                i += 2;
+                continue;
+            }
+            return Some((&l[..i], &l[i + 1..]));
+        }
+        i += 1;
+    }
+    None
+}
+
+/// `A` is the accumulator operand (`ASL A`) and mnemonics start
+/// statements (`INX:INY` isn't a label), so neither can name a symbol.
+fn check_symbol_name(name: &str) -> Result<(), String> {
+    if is_accumulator(name) {
+        return Err(format!("'{}' is reserved for the accumulator and can't be used as a symbol name", name));
+    }
+    if is_mnemonic(&name.to_ascii_uppercase()) {
+        return Err(format!("'{}' is an instruction and can't be used as a symbol name", name));
+    }
+    Ok(())
+}
+
+/// Macro parameters and `.for` variables are substituted for every
+/// matching word in their body, so a register or mnemonic name would
+/// rewrite `ASL A`, `LDA src,X` or the instruction itself.
+fn check_substituted_name(name: &str, what: &str) -> Result<(), String> {
+    if is_accumulator(name) {
+        return Err(format!("'{}' is reserved for the accumulator and can't be used as a {}", name, what));
+    }
+    if name.eq_ignore_ascii_case("X") || name.eq_ignore_ascii_case("Y") {
+        return Err(format!("'{}' is an index register and can't be used as a {}", name, what));
+    }
+    if is_mnemonic(&name.to_ascii_uppercase()) {
+        return Err(format!("'{}' is an instruction and can't be used as a {}", name, what));
+    }
+    Ok(())
+}
+
+/// Match a directive name case-insensitively at the start of `l`. Returns
+/// the rest of the line unless the name runs on into more identifier
+/// characters, so `.byte` does not also match `.bytes`.
+pub fn strip_directive<'a>(l: &'a str, name: &str) -> Option<&'a str> {
+    let head = l.get(..name.len())?;
+    if !head.eq_ignore_ascii_case(name) {
+        return None;
+    }
+    let rest = &l[name.len()..];
+    match rest.chars().next() {
+        Some(c) if c.is_ascii_alphanumeric() || c == '_' => None,
+        _ => Some(rest),
+    }
+}
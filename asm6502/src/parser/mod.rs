@@ -3,6 +3,8 @@
 pub mod lexer;
 pub mod number;
 pub mod expression;
+pub mod text;
+pub mod structs;
 
-pub use lexer::{parse_source, parse_line, Either};
+pub use lexer::{parse_file, parse_line, parse_lines, Condition, Either};
 pub use expression::ExpressionParser;
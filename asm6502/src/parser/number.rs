@@ -21,7 +21,7 @@ impl NumberParser {
             return Self::parse_hex(hex);
         }
         if let Some(hex) = trimmed.strip_prefix("0x").or_else(|| trimmed.strip_prefix("0X")) {
-            let hex = hex.strip_suffix('h').or(Some(hex)).unwrap();
+            let hex = hex.strip_suffix('h').unwrap_or(hex);
             return Self::parse_hex(hex);
         }
 
@@ -0,0 +1,116 @@
+//! The lines inside `.struct` and `.enum` blocks
+
+use super::expression::{Expr, ExpressionParser};
+use super::lexer::strip_directive;
+use super::text::split_operands;
+
+/// The storage a struct field declares.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldKind {
+    /// `.byte [count]`
+    Byte,
+    /// `.word [count]`, `.addr [count]`
+    Word,
+    /// `.dword [count]`, `.long [count]`
+    Dword,
+    /// `.res n`, `.ds n`: n bytes
+    Res,
+    /// `.tag Name`: an embedded copy of another struct
+    Tag(String),
+}
+
+/// One line of a `.struct` body: `x .byte`, `name .res 8`, or an unnamed
+/// `.res 2` for padding.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldDecl {
+    pub name: Option<String>,
+    pub kind: FieldKind,
+    /// Elements for `.byte`/`.word`/`.dword`, bytes for `.res`.
+    pub count: Option<Expr>,
+}
+
+const FIELD_DIRECTIVES: [(&str, FieldKind); 7] = [
+    (".byte", FieldKind::Byte),
+    (".word", FieldKind::Word),
+    (".addr", FieldKind::Word),
+    (".dword", FieldKind::Dword),
+    (".long", FieldKind::Dword),
+    (".res", FieldKind::Res),
+    (".ds", FieldKind::Res),
+];
+
+pub fn parse_field(line: &str) -> Result<FieldDecl, String> {
+    let (name, rest) = if line.starts_with('.') {
+        (None, line)
+    } else {
+        let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let name = name.strip_suffix(':').unwrap_or(name);
+        if !ExpressionParser::is_identifier(name) {
+            return Err(format!("Invalid field name: '{}'", name));
+        }
+        (Some(name.to_string()), rest.trim())
+    };
+    if let Some(tag) = strip_directive(rest, ".tag") {
+        let tag = tag.trim();
+        if !ExpressionParser::is_identifier(tag) {
+            return Err(format!(".tag expects a struct name, got '{}'", tag));
+        }
+        return Ok(FieldDecl { name, kind: FieldKind::Tag(tag.to_string()), count: None });
+    }
+    let (kind, operand) = FIELD_DIRECTIVES
+        .iter()
+        .find_map(|(directive, kind)| strip_directive(rest, directive).map(|op| (kind.clone(), op.trim())))
+        .ok_or_else(|| format!("expected a field: name .byte/.word/.dword/.res n/.tag Struct, got '{}'", line))?;
+    let count = match operand {
+        "" if kind == FieldKind::Res => return Err(".res field expects a size".to_string()),
+        "" => None,
+        op => Some(ExpressionParser::parse(op)?),
+    };
+    Ok(FieldDecl { name, kind, count })
+}
+
+/// One line of an `.enum` body: `RED`, `GREEN = 4`, or several of them
+/// separated by commas.
+pub fn parse_enum_members(line: &str) -> Result<Vec<(String, Option<Expr>)>, String> {
+    split_operands(line)
+        .into_iter()
+        .map(|member| {
+            let (name, value) = match member.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(ExpressionParser::parse(value.trim())?)),
+                None => (member.trim(), None),
+            };
+            if !ExpressionParser::is_identifier(name) {
+                return Err(format!("Invalid enum member: '{}'", name));
+            }
+            Ok((name.to_string(), value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod structs_tests {
+    use super::*;
+
+    #[test]
+    fn field_lines() {
+        let field = parse_field("pos .word").unwrap();
+        assert_eq!((field.name.as_deref(), field.kind, field.count), (Some("pos"), FieldKind::Word, None));
+        let field = parse_field("name: .BYTE 8").unwrap();
+        assert_eq!((field.kind, field.count), (FieldKind::Byte, Some(Expr::Number(8))));
+        let field = parse_field(".ds 2").unwrap();
+        assert_eq!((field.name, field.kind), (None, FieldKind::Res));
+        assert_eq!(parse_field("home .tag Point").unwrap().kind, FieldKind::Tag("Point".to_string()));
+        assert!(parse_field("x LDA").is_err());
+        assert!(parse_field("buf .res").is_err());
+        assert!(parse_field("1x .byte").is_err());
+    }
+
+    #[test]
+    fn enum_lines() {
+        let members = parse_enum_members("RED, GREEN = 4, BLUE").unwrap();
+        let names: Vec<&str> = members.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, ["RED", "GREEN", "BLUE"]);
+        assert_eq!(members[1].1, Some(Expr::Number(4)));
+        assert!(parse_enum_members("not valid").is_err());
+    }
+}
@@ -0,0 +1,267 @@
+//! String and character literals: escape sequences, and where literals
+//! end so that commas, `:` and comment markers inside them are left alone
+
+use std::borrow::Cow;
+
+/// One character of a string or character literal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextChar {
+    /// A character, mapped through the active encoding.
+    Char(char),
+    /// A `\xNN` or `\0` escape: this byte, whatever the encoding.
+    Byte(u8),
+}
+
+/// The byte index just past the literal whose opening quote is at
+/// `start`, or the end of `s` if it is never closed. A backslash escapes
+/// the character after it.
+pub fn literal_end(s: &str, start: usize) -> usize {
+    let quote = s.as_bytes()[start];
+    let mut chars = s[start + 1..].char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            _ if c as u32 == quote as u32 => return start + 1 + i + 1,
+            _ => {}
+        }
+    }
+    s.len()
+}
+
+/// `line` without its `;` comment. A `;` inside a string or character
+/// literal is part of the text.
+pub fn strip_comment(line: &str) -> &str {
+    let mut i = 0;
+    while let Some(offset) = line[i..].find([';', '"', '\'']) {
+        let at = i + offset;
+        if line.as_bytes()[at] == b';' {
+            return &line[..at];
+        }
+        i = literal_end(line, at);
+    }
+    line
+}
+
+/// `line` without its comments: `;` (or the dialect's `marker`) to the
+/// end of the line, and `/* ... */`, which may span lines. `in_comment`
+/// says whether a `/*` is still open, on entry from the line before and on
+/// return for the next. A comment in the middle of a line leaves a space
+/// in its place.
+pub fn strip_comments<'a>(line: &'a str, marker: Option<&str>, in_comment: &mut bool) -> Cow<'a, str> {
+    let mut out = String::new();
+    let mut i = 0;
+    let marker = marker.filter(|m| !m.is_empty());
+    if !*in_comment && marker.is_none() {
+        // The common case: no block comment on this line
+        let end = strip_comment(line).len();
+        if !line[..end].contains("/*") {
+            return Cow::Borrowed(&line[..end]);
+        }
+    }
+    loop {
+        if *in_comment {
+            let Some(offset) = line[i..].find("*/") else {
+                return Cow::Owned(out);
+            };
+            i += offset + 2;
+            *in_comment = false;
+            out.push(' ');
+        }
+        let Some(offset) = line[i..].find(|c| matches!(c, ';' | '"' | '\'' | '/') || marker.is_some_and(|m| m.starts_with(c)))
+        else {
+            out.push_str(&line[i..]);
+            return Cow::Owned(out);
+        };
+        let at = i + offset;
+        match line.as_bytes()[at] {
+            _ if marker.is_some_and(|m| line[at..].starts_with(m)) => {
+                out.push_str(&line[i..at]);
+                return Cow::Owned(out);
+            }
+            b';' => {
+                out.push_str(&line[i..at]);
+                return Cow::Owned(out);
+            }
+            b'/' if line[at..].starts_with("/*") => {
+                out.push_str(&line[i..at]);
+                i = at + 2;
+                *in_comment = true;
+            }
+            b'"' | b'\'' => {
+                let end = literal_end(line, at);
+                out.push_str(&line[i..end]);
+                i = end;
+            }
+            _ => {
+                let next = at + line[at..].chars().next().map_or(1, char::len_utf8);
+                out.push_str(&line[i..next]);
+                i = next;
+            }
+        }
+    }
+}
+
+/// Split a line into its statements at the `:` separators outside
+/// literals (`LDA #0 : STA $D020`). The `::` of a scoped name and `:=`
+/// don't separate.
+pub fn split_statements(s: &str) -> Vec<&str> {
+    let bytes = s.as_bytes();
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' | b'\'' => {
+                i = literal_end(s, i);
+                continue;
+            }
+            b':' if bytes.get(i + 1) == Some(&b':') => i += 1,
+            b':' if bytes.get(i + 1) == Some(&b'=') => {}
+            b':' => {
+                parts.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+/// Split directive operands at the commas outside literals and
+/// parentheses, so `.byte ','` and `.text "a, b", 0` split correctly.
+pub fn split_operands(s: &str) -> Vec<&str> {
+    let bytes = s.as_bytes();
+    let mut parts = Vec::new();
+    let mut depth = 0i32;
+    let mut start = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' | b'\'' => {
+                i = literal_end(s, i);
+                continue;
+            }
+            b'(' => depth += 1,
+            b')' => depth -= 1,
+            b',' if depth == 0 => {
+                parts.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+/// Decode the escape after a backslash.
+fn unescape(chars: &mut std::str::Chars) -> Result<TextChar, String> {
+    let c = match chars.next() {
+        Some('n') => '\n',
+        Some('r') => '\r',
+        Some('t') => '\t',
+        Some('0') => return Ok(TextChar::Byte(0)),
+        Some(c @ ('"' | '\'' | '\\')) => c,
+        Some('x') => {
+            let digits: String = chars.by_ref().take(2).collect();
+            return match u8::from_str_radix(&digits, 16) {
+                Ok(byte) if digits.len() == 2 => Ok(TextChar::Byte(byte)),
+                _ => Err(format!("'\\x{}' needs two hex digits", digits)),
+            };
+        }
+        Some(other) => return Err(format!("unknown escape '\\{}'", other)),
+        None => return Err("a backslash ends the literal".to_string()),
+    };
+    Ok(TextChar::Char(c))
+}
+
+/// The characters of `s`, which must be exactly one `"..."` literal.
+pub fn parse_string_literal(s: &str) -> Result<Vec<TextChar>, String> {
+    if !s.starts_with('"') || literal_end(s, 0) != s.len() || s.len() < 2 || !s.ends_with('"') {
+        return Err(format!("Invalid string {}, expected text in double quotes", s));
+    }
+    let mut chars = s[1..s.len() - 1].chars();
+    let mut out = Vec::new();
+    while let Some(c) = chars.next() {
+        out.push(match c {
+            '\\' => unescape(&mut chars)?,
+            c => TextChar::Char(c),
+        });
+    }
+    Ok(out)
+}
+
+/// A quoted message for `.assert`, `.error` or `.warning`, unescaped.
+pub fn parse_message(s: &str) -> Result<String, String> {
+    Ok(parse_string_literal(s)?
+        .into_iter()
+        .map(|c| match c {
+            TextChar::Char(c) => c,
+            TextChar::Byte(b) => char::from(b),
+        })
+        .collect())
+}
+
+/// `'c'` or `'\e'`: a single character between single quotes.
+pub fn parse_char_literal(s: &str) -> Option<TextChar> {
+    let mut chars = s.strip_prefix('\'')?.strip_suffix('\'')?.chars();
+    let c = match chars.next()? {
+        '\\' => unescape(&mut chars).ok()?,
+        c => TextChar::Char(c),
+    };
+    chars.next().is_none().then_some(c)
+}
+
+#[cfg(test)]
+mod text_tests {
+    use super::*;
+
+    #[test]
+    fn escapes() {
+        use TextChar::{Byte, Char};
+        assert_eq!(
+            parse_string_literal(r#""a\"b\\\n\x7F\0""#).unwrap(),
+            vec![Char('a'), Char('"'), Char('b'), Char('\\'), Char('\n'), Byte(0x7F), Byte(0)]
+        );
+        assert!(parse_string_literal(r#""\q""#).unwrap_err().contains("unknown escape"));
+        assert!(parse_string_literal(r#""\x4""#).unwrap_err().contains("two hex digits"));
+        assert!(parse_string_literal(r#""open"#).is_err());
+        assert!(parse_string_literal(r#""a" "b""#).is_err());
+        assert_eq!(parse_char_literal(r"'\''"), Some(Char('\'')));
+        assert_eq!(parse_char_literal(r"'\x41'"), Some(Byte(0x41)));
+        assert_eq!(parse_char_literal("'ab'"), None);
+    }
+
+    #[test]
+    fn comments_and_operands_skip_literals() {
+        assert_eq!(strip_comment(r#".text "a;b\";c" ; note"#), r#".text "a;b\";c" "#);
+        assert_eq!(strip_comment("LDA #';' ; semicolon"), "LDA #';' ");
+        assert_eq!(split_operands(r#""a, b", ',', (1,2)"#), vec![r#""a, b""#, " ','", " (1,2)"]);
+    }
+
+    #[test]
+    fn block_comments_span_lines() {
+        let mut open = false;
+        assert_eq!(strip_comments("LDA /* x; */ #1 ; y", None, &mut open), "LDA   #1 ");
+        assert!(!open);
+        assert_eq!(strip_comments(r#".text "/*" /* start"#, None, &mut open), r#".text "/*" "#);
+        assert!(open);
+        assert_eq!(strip_comments("still ; \"comment", None, &mut open), "");
+        assert_eq!(strip_comments("end */ NOP", None, &mut open), "  NOP");
+        assert!(!open);
+        assert_eq!(strip_comments("X = 10/2", None, &mut open), "X = 10/2");
+        assert_eq!(strip_comments("X = 10/2 // half", Some("//"), &mut open), "X = 10/2 ");
+        assert_eq!(strip_comments(".text \"a//b\" ; c", Some("//"), &mut open), ".text \"a//b\" ");
+    }
+
+    #[test]
+    fn statements_split_outside_literals() {
+        assert_eq!(split_statements("LDA #':' : STA a::b"), vec!["LDA #':' ", " STA a::b"]);
+        assert_eq!(split_statements(r#".text "a:b""#), vec![r#".text "a:b""#]);
+    }
+}
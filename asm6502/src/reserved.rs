@@ -9,11 +9,19 @@
 pub struct ReservedRange {
     pub start: u16,
     pub end: u16,
+    /// The only bank the range applies to; `None` for every bank and
+    /// unbanked memory.
+    pub bank: Option<u32>,
 }
 
 impl ReservedRange {
     pub fn new(start: u16, end: u16) -> Self {
-        Self { start, end }
+        Self { start, end, bank: None }
+    }
+
+    /// Whether the range applies to code placed in `bank`.
+    pub fn applies_to(&self, bank: Option<u32>) -> bool {
+        self.bank.is_none() || self.bank == bank
     }
 
     pub fn size(&self) -> u32 {
@@ -0,0 +1,204 @@
+//! Segments and the memory configuration that places them.
+//!
+//! `.segment "NAME"` switches the stream that following code and data go
+//! into. A [`MemoryConfig`] lists the memory areas of the target and
+//! which segments each holds; before layout the streams are laid out
+//! area by area, each segment following the previous one in its area.
+//!
+//! A banked area is one bank of a bank-switched ROM: several banks share
+//! a CPU address window, each with its own place in the output.
+
+use crate::error::AsmError;
+use crate::parser::expression::Expr;
+use crate::parser::lexer::Item;
+use crate::source::SourceLoc;
+
+/// The segment code goes into until the first `.segment`.
+pub const DEFAULT_SEGMENT: &str = "CODE";
+
+/// A range of the target's memory that segments are placed in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryArea {
+    pub name: String,
+    pub start: u16,
+    pub size: u32,
+    /// Pad the area to its full size in the output with this byte.
+    /// Without it the output stops after the last byte written.
+    pub fill: Option<u8>,
+    /// The bank the area is, for areas that share their addresses with
+    /// other banks.
+    pub bank: Option<u32>,
+}
+
+impl MemoryArea {
+    /// The last address in the area.
+    pub fn end(&self) -> u16 {
+        (self.start as u32 + self.size - 1) as u16
+    }
+}
+
+/// Whether a segment's contents are written to the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentKind {
+    /// Code and initialised data.
+    Code,
+    /// Uninitialised storage: labels and `.res`/`.fill`/`.skip`/`.align`
+    /// reserve addresses, but no bytes are emitted.
+    Bss,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub name: String,
+    pub area: String,
+    pub kind: SegmentKind,
+}
+
+/// The memory areas of the target and the segments placed in each, in
+/// order. Names are case-sensitive.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryConfig {
+    areas: Vec<MemoryArea>,
+    segments: Vec<Segment>,
+}
+
+impl MemoryConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a memory area of `size` bytes from `start`. Areas are written
+    /// to the output in the order they are added.
+    pub fn add_area(&mut self, name: &str, start: u16, size: u32, fill: Option<u8>) -> Result<(), AsmError> {
+        self.push_area(MemoryArea { name: name.to_string(), start, size, fill, bank: None })
+    }
+
+    /// Add bank `bank` of a bank-switched ROM: `size` bytes seen by the
+    /// CPU at `start`. Banks may share addresses with each other, and
+    /// `*=` may move forward within one (e.g. to the vectors at `$FFFA`).
+    pub fn add_banked_area(&mut self, name: &str, bank: u32, start: u16, size: u32, fill: Option<u8>) -> Result<(), AsmError> {
+        if let Some(other) = self.areas.iter().find(|a| a.bank == Some(bank)) {
+            return Err(AsmError::Asm(format!("Bank {} is already memory area \"{}\"", bank, other.name)));
+        }
+        self.push_area(MemoryArea { name: name.to_string(), start, size, fill, bank: Some(bank) })
+    }
+
+    fn push_area(&mut self, area: MemoryArea) -> Result<(), AsmError> {
+        let (name, start, size) = (&area.name, area.start, area.size);
+        if self.area(name).is_some() {
+            return Err(AsmError::Asm(format!("Memory area \"{}\" is already defined", name)));
+        }
+        if size == 0 || start as u32 + size > 0x10000 {
+            return Err(AsmError::Asm(format!(
+                "Memory area \"{}\" at ${:04X} with size ${:X} doesn't fit the address space",
+                name, start, size
+            )));
+        }
+        self.areas.push(area);
+        Ok(())
+    }
+
+    /// Place segment `name` in `area`, after the segments already there.
+    pub fn add_segment(&mut self, name: &str, area: &str, kind: SegmentKind) -> Result<(), AsmError> {
+        if self.segment(name).is_some() {
+            return Err(AsmError::Asm(format!("Segment \"{}\" is already defined", name)));
+        }
+        if self.area(area).is_none() {
+            return Err(AsmError::Asm(format!("Segment \"{}\": no memory area \"{}\"", name, area)));
+        }
+        self.segments.push(Segment { name: name.to_string(), area: area.to_string(), kind });
+        Ok(())
+    }
+
+    pub fn areas(&self) -> &[MemoryArea] {
+        &self.areas
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn area(&self, name: &str) -> Option<&MemoryArea> {
+        self.areas.iter().find(|a| a.name == name)
+    }
+
+    pub fn segment(&self, name: &str) -> Option<&Segment> {
+        self.segments.iter().find(|s| s.name == name)
+    }
+
+    /// The bank of the area segment `name` is placed in.
+    pub fn segment_bank(&self, name: &str) -> Option<u32> {
+        self.area(&self.segment(name)?.area)?.bank
+    }
+
+    /// Gather the items of each segment and lay them out area by area:
+    /// an `Org` to the area's start, then each of its segments behind an
+    /// `Item::Segment` marker. Constants and diagnostics before the first
+    /// segment is chosen go in front when there is no `CODE` segment.
+    /// `*=` is only kept inside banked areas.
+    pub(crate) fn link(&self, items: Vec<Item>) -> Result<Vec<Item>, String> {
+        let mut prelude = Vec::new();
+        let mut streams: Vec<Vec<Item>> = vec![Vec::new(); self.segments.len()];
+        let mut stream_lines: Vec<Option<SourceLoc>> = vec![None; self.segments.len()];
+        let mut current = self.segments.iter().position(|s| s.name == DEFAULT_SEGMENT);
+        let mut loc = SourceLoc::default();
+        for item in items {
+            match item {
+                Item::Line(l) => loc = l,
+                Item::Segment(name) => {
+                    let index = self.segments.iter().position(|s| s.name == name).ok_or_else(|| {
+                        format!("{}: segment \"{}\" is not in the memory configuration", loc, name)
+                    })?;
+                    current = Some(index);
+                }
+                Item::Bank { .. } => {
+                    return Err(format!(
+                        "{}: .bank can't be used with a memory configuration; place segments in banked memory areas (add_banked_area)",
+                        loc
+                    ));
+                }
+                Item::Org(_) if current.is_none_or(|i| self.segment_bank(&self.segments[i].name).is_none()) => {
+                    return Err(format!(
+                        "{}: *= can't be used with a memory configuration; switch segments with .segment",
+                        loc
+                    ));
+                }
+                item => {
+                    let Some(index) = current else {
+                        if matches!(item, Item::Constant(..) | Item::Assert { .. } | Item::Error(_) | Item::Warning(_)) {
+                            prelude.push(Item::Line(loc.clone()));
+                            prelude.push(item);
+                            continue;
+                        }
+                        return Err(format!(
+                            "{}: no segment selected, and the memory configuration has no \"{}\" segment",
+                            loc, DEFAULT_SEGMENT
+                        ));
+                    };
+                    if stream_lines[index].as_ref() != Some(&loc) {
+                        streams[index].push(Item::Line(loc.clone()));
+                        stream_lines[index] = Some(loc.clone());
+                    }
+                    streams[index].push(item);
+                }
+            }
+        }
+
+        let mut linked = prelude;
+        for area in &self.areas {
+            let mut opened = false;
+            for (index, segment) in self.segments.iter().enumerate() {
+                if segment.area != area.name {
+                    continue;
+                }
+                if !opened {
+                    linked.push(Item::Org(Expr::Number(area.start as u32)));
+                    opened = true;
+                }
+                linked.push(Item::Segment(segment.name.clone()));
+                linked.append(&mut streams[index]);
+            }
+        }
+        Ok(linked)
+    }
+}
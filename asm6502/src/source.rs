@@ -0,0 +1,62 @@
+//! Source locations attached to parsed items
+
+use std::fmt;
+use std::sync::Arc;
+
+/// Where an item came from: the file it was read from (`None` for the
+/// source string handed to the assembler directly) and its 1-based line.
+/// Lines produced by a macro expansion also remember the invocation.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SourceLoc {
+    pub file: Option<Arc<str>>,
+    pub line: usize,
+    pub expanded_from: Option<Arc<Expansion>>,
+}
+
+/// The invocation that produced an expanded line: the macro name and the
+/// location of the call.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Expansion {
+    pub name: String,
+    pub loc: SourceLoc,
+}
+
+impl SourceLoc {
+    pub fn new(file: Option<Arc<str>>, line: usize) -> Self {
+        Self { file, line, expanded_from: None }
+    }
+
+    /// The same line, marked as coming from an expansion of `name`
+    /// invoked at `call`.
+    pub fn expanded(&self, name: &str, call: &SourceLoc) -> Self {
+        Self {
+            file: self.file.clone(),
+            line: self.line,
+            expanded_from: Some(Arc::new(Expansion {
+                name: name.to_string(),
+                loc: call.clone(),
+            })),
+        }
+    }
+
+    /// The line in the source itself that the expansions started from.
+    pub fn outermost(&self) -> &SourceLoc {
+        match &self.expanded_from {
+            Some(exp) => exp.loc.outermost(),
+            None => self,
+        }
+    }
+}
+
+impl fmt::Display for SourceLoc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}", file, self.line)?,
+            None => write!(f, "Line {}", self.line)?,
+        }
+        if let Some(exp) = &self.expanded_from {
+            write!(f, " (expanded from {} at {})", exp.name, exp.loc)?;
+        }
+        Ok(())
+    }
+}
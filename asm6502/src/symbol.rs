@@ -1,10 +1,82 @@
 //! Symbol table for labels and constants
 
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
 
+use crate::source::SourceLoc;
+
+/// What kind of definition produced a symbol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SymbolKind {
+    /// `name:` — an address in the program.
+    Label,
+    /// `NAME = expr` assigned exactly once.
+    Constant,
+    /// `NAME = expr` assigned more than once; the value is the last one.
+    Variable,
+    /// Generated by the assembler itself (e.g. `__skip_N` from long-branch
+    /// expansion). Hidden from the sorted symbol views unless asked for.
+    Internal,
+    /// Supplied by the host with `define_symbol` before assembly.
+    Predefined,
+}
+
+/// Sort order for [`crate::Assembler6502::sorted_symbols`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolOrder {
+    Address,
+    Name,
+}
+
+/// A symbol as defined by the final assembly pass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub value: u16,
+    /// Location of the (last) definition.
+    pub loc: SourceLoc,
+    /// Enclosing namespace for qualified `Scope::name` symbols; `None`
+    /// for global ones.
+    pub scope: Option<String>,
+}
+
+/// How a reference uses the symbol it names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AccessKind {
+    /// Operand of a load, compare, logic or arithmetic instruction.
+    Read,
+    /// Operand of a store or read-modify-write instruction.
+    Write,
+    /// Target of `JMP` or `JSR`.
+    Jump,
+    /// Target of a conditional branch.
+    Branch,
+    /// Used as a value: data directives and immediate operands.
+    Data,
+}
+
+/// One use of a symbol, recorded during the final emit pass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    pub symbol: String,
+    pub loc: SourceLoc,
+    /// Address of the instruction or data block holding the reference.
+    pub address: u16,
+    /// The referencing statement, e.g. `STA SCREEN` or `.word`.
+    pub statement: String,
+    pub access: AccessKind,
+}
+
 pub struct SymbolTable {
     labels: HashMap<String, u16>,
     zp_labels: HashSet<String>,
+    records: HashMap<String, Symbol>,
+    predefined: HashMap<String, u16>,
+    case_sensitive: bool,
+    /// The bank of each label placed in a banked memory area. Set once
+    /// per assembly, after linking, and kept by `clear`.
+    banks: HashMap<String, u32>,
 }
 
 impl SymbolTable {
@@ -12,20 +84,132 @@ impl SymbolTable {
         Self {
             labels: HashMap::new(),
             zp_labels: HashSet::new(),
+            records: HashMap::new(),
+            predefined: HashMap::new(),
+            case_sensitive: true,
+            banks: HashMap::new(),
         }
     }
 
+    /// Case-insensitive tables fold every name to uppercase on the way in
+    /// and on lookup. The setting survives `clear`.
+    pub fn set_case_sensitive(&mut self, enabled: bool) {
+        self.case_sensitive = enabled;
+    }
+
+    pub fn is_case_sensitive(&self) -> bool {
+        self.case_sensitive
+    }
+
+    /// The name a symbol is stored under.
+    pub fn key<'n>(&self, name: &'n str) -> Cow<'n, str> {
+        if self.case_sensitive {
+            Cow::Borrowed(name)
+        } else {
+            Cow::Owned(name.to_ascii_uppercase())
+        }
+    }
+
+    /// Forget everything the source defined. Predefined symbols stay.
     pub fn clear(&mut self) {
         self.labels.clear();
         self.zp_labels.clear();
+        self.records.clear();
+        for (name, &value) in &self.predefined {
+            let name = self.fold(name.clone());
+            self.labels.insert(name.clone(), value);
+            self.records.insert(name.clone(), Symbol {
+                name,
+                kind: SymbolKind::Predefined,
+                value,
+                loc: SourceLoc::default(),
+                scope: None,
+            });
+        }
+    }
+
+    /// Define a symbol that survives `clear`, visible to every pass.
+    pub fn predefine(&mut self, name: String, value: u16) {
+        self.predefined.insert(name, value);
+        self.clear();
+    }
+
+    pub fn clear_predefined(&mut self) {
+        self.predefined.clear();
+        self.clear();
+    }
+
+    /// A fresh table holding only the predefined symbols, with the same
+    /// case setting.
+    pub fn predefined_only(&self) -> SymbolTable {
+        let mut table = SymbolTable::new();
+        table.case_sensitive = self.case_sensitive;
+        table.predefined = self.predefined.clone();
+        table.clear();
+        table
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        let key = self.key(name).into_owned();
+        self.labels.remove(&key);
+        self.records.remove(&key);
+    }
+
+    fn fold(&self, name: String) -> String {
+        if self.case_sensitive { name } else { name.to_ascii_uppercase() }
     }
 
     pub fn insert(&mut self, name: String, addr: u16) {
+        let name = self.fold(name);
         self.labels.insert(name, addr);
     }
 
+    /// Insert a value together with its metadata. Only the final layout
+    /// pass calls this; the fix-up passes just need the bare value.
+    /// Re-assigning a constant turns it into a variable.
+    pub fn define(&mut self, name: String, value: u16, mut kind: SymbolKind, loc: SourceLoc) {
+        let name = self.fold(name);
+        if kind == SymbolKind::Constant
+            && let Some(prev) = self.records.get(&name)
+            && matches!(prev.kind, SymbolKind::Constant | SymbolKind::Variable)
+        {
+            kind = SymbolKind::Variable;
+        }
+        let scope = name.rsplit_once("::").map(|(scope, _)| scope.to_string());
+        self.labels.insert(name.clone(), value);
+        self.records.insert(name.clone(), Symbol { name, kind, value, loc, scope });
+    }
+
     pub fn get(&self, name: &str) -> Option<u16> {
-        self.labels.get(name).copied()
+        self.labels.get(self.key(name).as_ref()).copied()
+    }
+
+    /// Replace the label banks; names are stored under their key.
+    pub fn set_banks(&mut self, banks: HashMap<String, u32>) {
+        self.banks = banks;
+    }
+
+    pub fn bank(&self, name: &str) -> Option<u32> {
+        self.banks.get(self.key(name).as_ref()).copied()
+    }
+
+    pub fn info(&self, name: &str) -> Option<&Symbol> {
+        self.records.get(self.key(name).as_ref())
+    }
+
+    /// Every recorded symbol in the requested order. Ties in address
+    /// order fall back to the name so the output is deterministic.
+    pub fn sorted(&self, order: SymbolOrder, include_internal: bool) -> Vec<&Symbol> {
+        let mut list: Vec<&Symbol> = self
+            .records
+            .values()
+            .filter(|s| include_internal || s.kind != SymbolKind::Internal)
+            .collect();
+        match order {
+            SymbolOrder::Address => list.sort_by(|a, b| a.value.cmp(&b.value).then_with(|| a.name.cmp(&b.name))),
+            SymbolOrder::Name => list.sort_by(|a, b| a.name.cmp(&b.name)),
+        }
+        list
     }
 
     pub fn labels(&self) -> &HashMap<String, u16> {